@@ -0,0 +1,177 @@
+// Trains a vocabulary from a folder of images and tries it out, away from the browser:
+//
+//     cargo run --release --example vocabulary -- train <image folder> <vocabulary file> [branching] [depth]
+//     cargo run --release --example vocabulary -- query <vocabulary file> <image folder> <image>
+//
+// Only binary PGM and PPM images are read, as there are no image decoders here. Other formats can
+// be converted first, e.g. with `mogrify -format ppm *.jpg`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use slamburger::common::{Descriptor, Image};
+use slamburger::frame::Frame;
+use slamburger::vocabulary::{Database, Vocabulary};
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["train", folder, output, rest @ ..] if rest.len() <= 2 => {
+            let branching = rest.first().map_or(10, |b| parse_number(b));
+            let depth = rest.get(1).map_or(4, |d| parse_number(d));
+            train(Path::new(folder), Path::new(output), branching, depth);
+        }
+        ["query", vocabulary, folder, image] => {
+            query(Path::new(vocabulary), Path::new(folder), Path::new(image));
+        }
+        _ => {
+            eprintln!(
+                "usage: vocabulary train <image folder> <vocabulary file> [branching] [depth]"
+            );
+            eprintln!("       vocabulary query <vocabulary file> <image folder> <image>");
+            exit(1);
+        }
+    }
+}
+
+fn train(folder: &Path, output: &Path, branching: usize, depth: usize) {
+    let images: Vec<Vec<Descriptor>> = image_paths(folder)
+        .iter()
+        .map(|path| descriptors(path))
+        .collect();
+    let num_descriptors: usize = images.iter().map(Vec::len).sum();
+    println!(
+        "training on {} descriptors from {} images",
+        num_descriptors,
+        images.len()
+    );
+
+    let vocabulary = Vocabulary::train(&images, branching, depth);
+    fs::write(output, vocabulary.to_bytes()).unwrap_or_else(|error| {
+        eprintln!("can't write {}: {}", output.display(), error);
+        exit(1);
+    });
+    println!(
+        "wrote {} words to {}",
+        vocabulary.num_words(),
+        output.display()
+    );
+}
+
+fn query(vocabulary: &Path, folder: &Path, image: &Path) {
+    let bytes = fs::read(vocabulary).unwrap_or_else(|error| {
+        eprintln!("can't read {}: {}", vocabulary.display(), error);
+        exit(1);
+    });
+    let vocabulary = Vocabulary::from_bytes(&bytes).unwrap_or_else(|| {
+        eprintln!("not a vocabulary file");
+        exit(1);
+    });
+
+    let paths = image_paths(folder);
+    let mut database = Database::new();
+    for (id, path) in paths.iter().enumerate() {
+        database.add(id, vocabulary.transform(&descriptors(path)));
+    }
+
+    let results = database.query(&vocabulary.transform(&descriptors(image)), 5);
+    for (id, score) in results {
+        println!("{:.3}  {}", score, paths[id].display());
+    }
+}
+
+// The PGM and PPM files in the folder, sorted by name.
+fn image_paths(folder: &Path) -> Vec<PathBuf> {
+    let entries = fs::read_dir(folder).unwrap_or_else(|error| {
+        eprintln!("can't read {}: {}", folder.display(), error);
+        exit(1);
+    });
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            matches!(
+                path.extension().and_then(|e| e.to_str()),
+                Some("pgm" | "ppm")
+            )
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn descriptors(path: &Path) -> Vec<Descriptor> {
+    let (data, width, height) = fs::read(path)
+        .ok()
+        .and_then(|bytes| read_netpbm(&bytes))
+        .unwrap_or_else(|| {
+            eprintln!("can't read {} as a binary PGM or PPM", path.display());
+            exit(1);
+        });
+    let image = Image {
+        data: &data,
+        width,
+        height,
+    };
+    Frame::new(image).descriptors
+}
+
+// Decodes a binary PGM (P5) or PPM (P6) image with 8 bit samples into RGBA, the way the browser
+// hands images over.
+fn read_netpbm(bytes: &[u8]) -> Option<(Vec<u8>, usize, usize)> {
+    // the header is 4 fields separated by whitespace, with comments from # to the end of a line
+    let mut fields = vec![];
+    let mut position = 0;
+    while fields.len() < 4 {
+        match *bytes.get(position)? {
+            b'#' => {
+                while *bytes.get(position)? != b'\n' {
+                    position += 1;
+                }
+            }
+            byte if byte.is_ascii_whitespace() => position += 1,
+            _ => {
+                let start = position;
+                while !bytes.get(position)?.is_ascii_whitespace() {
+                    position += 1;
+                }
+                fields.push(std::str::from_utf8(&bytes[start..position]).ok()?);
+            }
+        }
+    }
+    // a single whitespace byte separates the header from the pixels
+    let pixels = bytes.get(position + 1..)?;
+
+    let channels = match fields[0] {
+        "P5" => 1,
+        "P6" => 3,
+        _ => return None,
+    };
+    let width: usize = fields[1].parse().ok()?;
+    let height: usize = fields[2].parse().ok()?;
+    if fields[3] != "255" || pixels.len() < width * height * channels {
+        return None;
+    }
+
+    let rgba = pixels
+        .chunks_exact(channels)
+        .take(width * height)
+        .flat_map(|pixel| match channels {
+            1 => [pixel[0], pixel[0], pixel[0], 255],
+            _ => [pixel[0], pixel[1], pixel[2], 255],
+        })
+        .collect();
+    Some((rgba, width, height))
+}
+
+fn parse_number(text: &str) -> usize {
+    text.parse().unwrap_or_else(|_| {
+        eprintln!("{} isn't a number", text);
+        exit(1);
+    })
+}
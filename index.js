@@ -121,10 +121,12 @@ function update_fps() {
       }
 
       if (frame1_ptr !== undefined && frame2_ptr !== undefined) {
-        const result = wasmInstance.instance.exports.calculate(
+        // the frame we just wrote is in the slot we aren't about to write to
+        const result = wasmInstance.instance.exports.process_frame(
           frame_1.width,
           frame_1.height,
-          slot
+          1 - slot,
+          performance.now() / 1000
         );
 
         console.log(result);
@@ -132,19 +134,22 @@ function update_fps() {
         let greyPtr = wasmInstance.instance.exports.get_grayscale();
         let greyLen = wasmInstance.instance.exports.get_grayscale_len();
 
-        let greyData = new Uint8Array(
-          wasmInstance.instance.exports.memory.buffer,
-          greyPtr,
-          greyLen
-        );
+        // nothing has been published yet if the frame couldn't be processed
+        if (greyLen > 0) {
+          let greyData = new Uint8Array(
+            wasmInstance.instance.exports.memory.buffer,
+            greyPtr,
+            greyLen
+          );
 
-        let greyImageData = new ImageData(
-          new Uint8ClampedArray(greyData),
-          frame_1.width,
-          frame_1.height
-        );
+          let greyImageData = new ImageData(
+            new Uint8ClampedArray(greyData),
+            frame_1.width,
+            frame_1.height
+          );
 
-        ctx.putImageData(greyImageData, 0, 0);
+          ctx.putImageData(greyImageData, 0, 0);
+        }
 
         let keypointPtr0 = wasmInstance.instance.exports.get_keypoints_slot_0();
         let keypointsLen0 =
@@ -220,9 +225,17 @@ function update_fps() {
         keypointMatchesLen * 3
       );
 
-      //for each pair, draw a line
+      // one entry per pair, 1 if the match agrees with the estimated pose
+      let inliersPtr = wasmInstance.instance.exports.get_inliers();
+      let inliersLen = wasmInstance.instance.exports.get_inliers_len();
+      let inliers = new Uint8Array(
+        wasmInstance.instance.exports.memory.buffer,
+        inliersPtr,
+        inliersLen
+      );
+
+      //for each pair, draw a line, green for inliers and red for outliers
       ctx.globalAlpha = 1;
-      ctx.strokeStyle = "red";
       for (let i = 0; i < keypointMatchesLen; i += 2) {
         let x0 = keypointData[i * 3];
         let y0 = keypointData[i * 3 + 1];
@@ -230,6 +243,7 @@ function update_fps() {
         let x1 = keypointData[i * 3 + 3] + width;
         let y1 = keypointData[i * 3 + 4];
 
+        ctx.strokeStyle = inliers[i / 2] === 1 ? "lime" : "red";
         ctx.beginPath();
         ctx.moveTo(x0, y0);
         ctx.lineTo(x1, y1);
@@ -241,6 +255,30 @@ function update_fps() {
       ctx.fillStyle = "white";
       ctx.font = "20px Arial";
       ctx.fillText("FPS: " + average_fps.toFixed(0), 10, 30);
+      ctx.fillText(
+        "Inliers: " +
+          wasmInstance.instance.exports.get_num_inliers() +
+          " / " +
+          keypointMatchesLen / 2,
+        10,
+        60
+      );
+
+      // the camera's position in the world is -Rᵀ·t
+      const pose = new Float64Array(
+        wasmInstance.instance.exports.memory.buffer,
+        wasmInstance.instance.exports.get_pose(),
+        12
+      );
+      const position = [0, 1, 2].map(
+        (col) =>
+          -(pose[col] * pose[9] + pose[3 + col] * pose[10] + pose[6 + col] * pose[11])
+      );
+      ctx.fillText(
+        "Camera: " + position.map((v) => v.toFixed(2)).join(", "),
+        10,
+        90
+      );
 
       update_fps();
       requestAnimationFrame(run);
@@ -0,0 +1,565 @@
+// Bundle adjustment moves the cameras and the points together until every point projects as close
+// as it can to every keypoint it was seen at. The name comes from the bundles of rays between each
+// camera and its points, which get adjusted until they meet.
+//
+// It is Levenberg–Marquardt again, like `phase_7`, but with far more unknowns: 6 per camera and 3
+// per point. The trick that makes it fast is that a point only ever appears next to the cameras
+// that see it, so in the normal equations
+//
+//     | U   W | |δc|     |g_c|
+//     | Wᵀ  V | |δp| = - |g_p|
+//
+// V is block diagonal with one 3x3 block per point and is trivial to invert. The points are
+// eliminated first (the Schur complement), leaving a small system in the cameras only:
+//
+//     (U - W·V⁻¹·Wᵀ)·δc = -g_c + W·V⁻¹·g_p
+//
+// and each point's step then follows from the cameras' on its own.
+//
+// Without something held still the whole scene could slide, turn and grow with no change in the
+// reprojection errors, so some cameras are fixed to pin it down (the gauge).
+
+use nalgebra::{DMatrix, DVector, Matrix3, Matrix6, SMatrix, Vector2, Vector3, Vector6};
+
+use crate::common::*;
+use crate::geometry::SE3;
+use crate::map::{KeyFrameId, Map, MapPointId};
+use crate::phase_7::Loss;
+
+type Matrix6x3 = SMatrix<f64, 6, 3>;
+
+// Keypoint `pixel` of camera `camera` is point `point`.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Observation {
+    pub camera: usize,
+    pub point: usize,
+    pub pixel: (f64, f64),
+}
+
+pub struct BundleProblem {
+    // map world points into each camera
+    pub cameras: Vec<SE3>,
+    // fixed cameras don't move, they hold the scene in place
+    pub fixed: Vec<bool>,
+    pub points: Vec<Vector3<f64>>,
+    pub observations: Vec<Observation>,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct BundleAdjustmentSummary {
+    pub initial_cost: f64,
+    pub final_cost: f64,
+    pub iterations: usize,
+}
+
+// The pixel error of an observation, or None when the point is behind the camera.
+fn residual(
+    intrinsics: &CameraIntrinsics,
+    camera: &SE3,
+    point: &Vector3<f64>,
+    pixel: (f64, f64),
+) -> Option<Vector2<f64>> {
+    let (u, v) = intrinsics.project(&camera.act(point))?;
+    Some(Vector2::new(u - pixel.0, v - pixel.1))
+}
+
+// How the pixel moves with the point in camera coordinates.
+fn projection_jacobian(intrinsics: &CameraIntrinsics, p: &Vector3<f64>) -> SMatrix<f64, 2, 3> {
+    let z_inverse = 1.0 / p.z;
+    let x = p.x * z_inverse;
+    let y = p.y * z_inverse;
+    SMatrix::<f64, 2, 3>::new(
+        intrinsics.fx * z_inverse,
+        intrinsics.skew * z_inverse,
+        -(intrinsics.fx * x + intrinsics.skew * y) * z_inverse,
+        0.0,
+        intrinsics.fy * z_inverse,
+        -intrinsics.fy * y * z_inverse,
+    )
+}
+
+impl BundleProblem {
+    // The robust cost of all the observations, and how many of them are behind their camera.
+    // Those can't be projected and are left out of the cost.
+    pub fn cost(&self, intrinsics: &CameraIntrinsics, loss: Loss) -> (f64, usize) {
+        let mut cost = 0.0;
+        let mut behind = 0;
+        for observation in &self.observations {
+            let camera = &self.cameras[observation.camera];
+            match residual(
+                intrinsics,
+                camera,
+                &self.points[observation.point],
+                observation.pixel,
+            ) {
+                Some(r) => cost += loss.cost(r.norm()),
+                None => behind += 1,
+            }
+        }
+        (cost, behind)
+    }
+}
+
+// The blocks of the normal equations of the reweighted problem.
+struct NormalEquations {
+    u: Vec<Matrix6<f64>>,
+    v: Vec<Matrix3<f64>>,
+    // one block per observation, as a point is seen at most once by each camera
+    w: Vec<Matrix6x3>,
+    g_c: Vec<Vector6<f64>>,
+    g_p: Vec<Vector3<f64>>,
+}
+
+fn normal_equations(
+    problem: &BundleProblem,
+    intrinsics: &CameraIntrinsics,
+    loss: Loss,
+) -> NormalEquations {
+    let mut equations = NormalEquations {
+        u: vec![Matrix6::zeros(); problem.cameras.len()],
+        v: vec![Matrix3::zeros(); problem.points.len()],
+        w: vec![Matrix6x3::zeros(); problem.observations.len()],
+        g_c: vec![Vector6::zeros(); problem.cameras.len()],
+        g_p: vec![Vector3::zeros(); problem.points.len()],
+    };
+
+    for (o, observation) in problem.observations.iter().enumerate() {
+        let camera = &problem.cameras[observation.camera];
+        let point = &problem.points[observation.point];
+        let r = match residual(intrinsics, camera, point, observation.pixel) {
+            Some(r) => r,
+            None => continue,
+        };
+        let weight = loss.weight(r.norm());
+
+        // the camera point moves with the pose as in `SE3::act_jacobian` and with the world
+        // point through the rotation
+        let projection = projection_jacobian(intrinsics, &camera.act(point));
+        let j_c = projection * camera.act_jacobian(point);
+        let j_p = projection * camera.rotation.matrix();
+
+        let (c, p) = (observation.camera, observation.point);
+        equations.v[p] += j_p.transpose() * j_p * weight;
+        equations.g_p[p] += j_p.transpose() * r * weight;
+        if !problem.fixed[c] {
+            equations.u[c] += j_c.transpose() * j_c * weight;
+            equations.g_c[c] += j_c.transpose() * r * weight;
+            equations.w[o] = j_c.transpose() * j_p * weight;
+        }
+    }
+    equations
+}
+
+// How far each camera and point moves in one iteration.
+struct Step {
+    cameras: Vec<Vector6<f64>>,
+    points: Vec<Vector3<f64>>,
+}
+
+// Solves the damped normal equations for a step of every camera and point, eliminating the points
+// first. Fixed cameras get a zero step.
+fn solve_step(problem: &BundleProblem, equations: &NormalEquations, lambda: f64) -> Option<Step> {
+    // the free cameras are numbered in the reduced system
+    let mut slots = vec![None; problem.cameras.len()];
+    let mut num_free = 0;
+    for (camera, fixed) in problem.fixed.iter().enumerate() {
+        if !fixed {
+            slots[camera] = Some(num_free);
+            num_free += 1;
+        }
+    }
+
+    let damp3 = |m: &Matrix3<f64>| {
+        let mut damped = *m;
+        for i in 0..3 {
+            damped[(i, i)] += lambda * m[(i, i)].max(1e-9);
+        }
+        damped
+    };
+    // a point seen from nowhere useful has a singular block and simply doesn't move
+    let v_inverse: Vec<Matrix3<f64>> = equations
+        .v
+        .iter()
+        .map(|v| damp3(v).try_inverse().unwrap_or_else(Matrix3::zeros))
+        .collect();
+
+    let mut s = DMatrix::<f64>::zeros(6 * num_free, 6 * num_free);
+    let mut b = DVector::<f64>::zeros(6 * num_free);
+    for (camera, slot) in slots.iter().enumerate() {
+        if let Some(slot) = slot {
+            let mut u = equations.u[camera];
+            for i in 0..6 {
+                u[(i, i)] += lambda * u[(i, i)].max(1e-9);
+            }
+            s.fixed_view_mut::<6, 6>(6 * slot, 6 * slot).copy_from(&u);
+            b.fixed_rows_mut::<6>(6 * slot)
+                .copy_from(&-equations.g_c[camera]);
+        }
+    }
+
+    // the observations of each point, since W·V⁻¹·Wᵀ pairs up every two cameras seeing a point
+    let mut point_observations = vec![vec![]; problem.points.len()];
+    for (o, observation) in problem.observations.iter().enumerate() {
+        if slots[observation.camera].is_some() {
+            point_observations[observation.point].push(o);
+        }
+    }
+    for (point, observations) in point_observations.iter().enumerate() {
+        for &o1 in observations {
+            let slot_1 = slots[problem.observations[o1].camera].unwrap();
+            let w_v = equations.w[o1] * v_inverse[point];
+            let mut rows = b.fixed_rows_mut::<6>(6 * slot_1);
+            rows += w_v * equations.g_p[point];
+            for &o2 in observations {
+                let slot_2 = slots[problem.observations[o2].camera].unwrap();
+                let block = w_v * equations.w[o2].transpose();
+                let mut view = s.fixed_view_mut::<6, 6>(6 * slot_1, 6 * slot_2);
+                view -= block;
+            }
+        }
+    }
+
+    let camera_step = match s.clone().cholesky() {
+        Some(cholesky) => cholesky.solve(&b),
+        None => s.lu().solve(&b)?,
+    };
+
+    let camera_steps: Vec<Vector6<f64>> = slots
+        .iter()
+        .map(|slot| match slot {
+            Some(slot) => camera_step.fixed_rows::<6>(6 * slot).into_owned(),
+            None => Vector6::zeros(),
+        })
+        .collect();
+
+    // back-substitute each point: δp = V⁻¹·(-g_p - Wᵀ·δc)
+    let mut point_rhs: Vec<Vector3<f64>> = equations.g_p.iter().map(|g| -g).collect();
+    for (o, observation) in problem.observations.iter().enumerate() {
+        point_rhs[observation.point] -=
+            equations.w[o].transpose() * camera_steps[observation.camera];
+    }
+    let point_steps = point_rhs
+        .iter()
+        .zip(&v_inverse)
+        .map(|(rhs, v_inverse)| v_inverse * rhs)
+        .collect();
+
+    Some(Step {
+        cameras: camera_steps,
+        points: point_steps,
+    })
+}
+
+// Levenberg–Marquardt over the free cameras and all the points. The loss is in pixels.
+pub fn bundle_adjust(
+    problem: &mut BundleProblem,
+    intrinsics: &CameraIntrinsics,
+    loss: Loss,
+    max_iterations: usize,
+) -> BundleAdjustmentSummary {
+    let (initial_cost, mut behind) = problem.cost(intrinsics, loss);
+    let mut cost = initial_cost;
+    let mut lambda = 1e-4;
+    let mut iterations = 0;
+
+    while iterations < max_iterations {
+        iterations += 1;
+        let equations = normal_equations(problem, intrinsics, loss);
+
+        // try steps until one lowers the cost, leaning more towards gradient descent each time
+        let mut improved = false;
+        while lambda < 1e10 {
+            let step = match solve_step(problem, &equations, lambda) {
+                Some(step) => step,
+                None => break,
+            };
+            let candidate = BundleProblem {
+                cameras: problem
+                    .cameras
+                    .iter()
+                    .zip(&step.cameras)
+                    .map(|(camera, step)| SE3::exp(step) * *camera)
+                    .collect(),
+                fixed: problem.fixed.clone(),
+                points: problem
+                    .points
+                    .iter()
+                    .zip(&step.points)
+                    .map(|(point, step)| point + step)
+                    .collect(),
+                observations: problem.observations.clone(),
+            };
+
+            // a step that pushes points behind the cameras only looks cheaper
+            let (candidate_cost, candidate_behind) = candidate.cost(intrinsics, loss);
+            if candidate_behind <= behind && candidate_cost < cost {
+                let converged = cost - candidate_cost < 1e-10 * cost;
+                *problem = candidate;
+                cost = candidate_cost;
+                behind = candidate_behind;
+                lambda = (lambda / 10.0).max(1e-12);
+                improved = !converged;
+                break;
+            }
+            lambda *= 10.0;
+        }
+
+        if !improved {
+            break;
+        }
+    }
+
+    BundleAdjustmentSummary {
+        initial_cost,
+        final_cost: cost,
+        iterations,
+    }
+}
+
+// Bundle adjustment of a window of keyframes and the points they see. Keyframes outside the window
+// that also see those points take part to keep the window attached to the rest of the map, but stay
+// fixed, as does the map's first keyframe. The map is updated with the result.
+pub fn local_bundle_adjustment(
+    map: &mut Map,
+    window: &[KeyFrameId],
+    intrinsics: &CameraIntrinsics,
+    loss: Loss,
+    max_iterations: usize,
+) -> BundleAdjustmentSummary {
+    let first_keyframe = map.keyframes().next().map(|keyframe| keyframe.id);
+
+    // the points the window sees, in the order they'll be in the problem
+    let mut point_ids: Vec<MapPointId> = window
+        .iter()
+        .filter_map(|&id| map.keyframe(id))
+        .flat_map(|keyframe| keyframe.observed_map_points())
+        .collect();
+    point_ids.sort();
+    point_ids.dedup();
+
+    let mut camera_ids: Vec<KeyFrameId> = point_ids
+        .iter()
+        .flat_map(|&id| map.map_point(id).unwrap().observations.iter())
+        .map(|&(keyframe, _)| keyframe)
+        .collect();
+    camera_ids.sort();
+    camera_ids.dedup();
+
+    let mut problem = BundleProblem {
+        cameras: camera_ids
+            .iter()
+            .map(|&id| SE3::from_pose(&map.keyframe(id).unwrap().pose))
+            .collect(),
+        fixed: camera_ids
+            .iter()
+            .map(|id| !window.contains(id) || Some(*id) == first_keyframe)
+            .collect(),
+        points: point_ids
+            .iter()
+            .map(|&id| map.map_point(id).unwrap().position)
+            .collect(),
+        observations: vec![],
+    };
+    for (point, &point_id) in point_ids.iter().enumerate() {
+        for &(keyframe, index) in &map.map_point(point_id).unwrap().observations {
+            let camera = camera_ids.binary_search(&keyframe).unwrap();
+            let keypoint = map.keyframe(keyframe).unwrap().keypoints[index];
+            problem.observations.push(Observation {
+                camera,
+                point,
+                pixel: (keypoint.x as f64, keypoint.y as f64),
+            });
+        }
+    }
+
+    let summary = bundle_adjust(&mut problem, intrinsics, loss, max_iterations);
+
+    for ((id, camera), fixed) in camera_ids.iter().zip(&problem.cameras).zip(&problem.fixed) {
+        if !fixed {
+            map.set_keyframe_pose(*id, camera.to_pose());
+        }
+    }
+    for (id, position) in point_ids.iter().zip(&problem.points) {
+        map.set_map_point_position(*id, *position);
+    }
+    summary
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Frame;
+    use crate::rand::Rand;
+    use crate::synthetic::*;
+    use nalgebra::Rotation3;
+
+    // Cameras side by side looking at the points, each turned slightly.
+    fn cameras() -> Vec<SE3> {
+        (0..5)
+            .map(|i| {
+                let rotation = Rotation3::from_euler_angles(0.01 * i as f64, -0.02 * i as f64, 0.0);
+                SE3::from_pose(&(
+                    rotation.into_inner(),
+                    Vector3::new(-0.3 * i as f64, 0.0, 0.0),
+                ))
+            })
+            .collect()
+    }
+
+    fn exact_problem(rng: &mut Rand) -> BundleProblem {
+        let cameras = cameras();
+        let points = random_points(rng, 100);
+        let mut observations = vec![];
+        for (c, camera) in cameras.iter().enumerate() {
+            for (p, point) in points.iter().enumerate() {
+                let pixel = intrinsics().project(&camera.act(point)).unwrap();
+                observations.push(Observation {
+                    camera: c,
+                    point: p,
+                    pixel,
+                });
+            }
+        }
+        BundleProblem {
+            fixed: vec![true, true, false, false, false],
+            cameras,
+            points,
+            observations,
+        }
+    }
+
+    fn perturb(problem: &mut BundleProblem, rng: &mut Rand) {
+        for (camera, fixed) in problem.cameras.iter_mut().zip(&problem.fixed) {
+            if !fixed {
+                let step = Vector6::from_fn(|_, _| rng.gen_range(-0.02..=0.02) as f64);
+                *camera = SE3::exp(&step) * *camera;
+            }
+        }
+        for point in problem.points.iter_mut() {
+            *point += Vector3::from_fn(|_, _| rng.gen_range(-0.05..=0.05) as f64);
+        }
+    }
+
+    fn camera_error(a: &SE3, b: &SE3) -> f64 {
+        (a.inverse() * *b).log().norm()
+    }
+
+    #[test]
+    fn test_bundle_adjust() {
+        let mut rng = Rand::new_with_seed(19);
+        let truth = exact_problem(&mut rng);
+        let mut problem = exact_problem(&mut Rand::new_with_seed(19));
+        perturb(&mut problem, &mut rng);
+
+        let summary = bundle_adjust(&mut problem, &intrinsics(), Loss::Squared, 20);
+        assert!(summary.initial_cost > 1000.0);
+        assert!(summary.final_cost < 1e-8, "{:?}", summary);
+
+        // two fixed cameras pin down the scale too, so everything goes back where it was
+        for (camera, expected) in problem.cameras.iter().zip(&truth.cameras) {
+            assert!(camera_error(camera, expected) < 1e-6);
+        }
+        for (point, expected) in problem.points.iter().zip(&truth.points) {
+            assert!((point - expected).norm() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_bundle_adjust_with_outliers() {
+        let mut rng = Rand::new_with_seed(20);
+        let truth = exact_problem(&mut rng);
+        let mut problem = exact_problem(&mut Rand::new_with_seed(20));
+        // a few keypoints matched to the wrong point
+        for observation in problem.observations.iter_mut().step_by(25) {
+            observation.pixel.0 += 40.0;
+        }
+        perturb(&mut problem, &mut rng);
+
+        let solve = |loss| {
+            let mut problem = BundleProblem {
+                cameras: problem.cameras.clone(),
+                fixed: problem.fixed.clone(),
+                points: problem.points.clone(),
+                observations: problem.observations.clone(),
+            };
+            bundle_adjust(&mut problem, &intrinsics(), loss, 30);
+            problem
+                .cameras
+                .iter()
+                .zip(&truth.cameras)
+                .map(|(camera, expected)| camera_error(camera, expected))
+                .fold(0.0, f64::max)
+        };
+
+        let squared = solve(Loss::Squared);
+        let huber = solve(Loss::Huber(1.0));
+        let cauchy = solve(Loss::Cauchy(1.0));
+        assert!(huber < squared);
+        assert!(cauchy < squared);
+        assert!(cauchy < 1e-3);
+    }
+
+    #[test]
+    fn test_local_bundle_adjustment() {
+        let mut rng = Rand::new_with_seed(21);
+        let points = random_points(&mut rng, 60);
+        let cameras = cameras();
+
+        // a map where every keyframe sees every point, with the last keyframes and the points
+        // knocked out of place
+        let mut map = Map::new();
+        let point_ids: Vec<MapPointId> = points
+            .iter()
+            .map(|point| map.add_map_point(point + Vector3::new(0.03, -0.02, 0.04)))
+            .collect();
+        for (c, camera) in cameras.iter().enumerate() {
+            let keypoints = points
+                .iter()
+                .map(|point| {
+                    let (x, y) = intrinsics().project(&camera.act(point)).unwrap();
+                    KeyPoint {
+                        x: x as f32,
+                        y: y as f32,
+                        orientation: 0.0,
+                    }
+                })
+                .collect();
+            let frame = Frame {
+                width: 640,
+                height: 480,
+                blurred_image: vec![],
+                descriptors: vec![Descriptor(vec![0]); points.len()],
+                keypoints,
+            };
+            let pose = if c >= 3 {
+                let nudge = Vector6::new(0.05, 0.0, -0.03, 0.01, 0.0, 0.01);
+                (SE3::exp(&nudge) * *camera).to_pose()
+            } else {
+                camera.to_pose()
+            };
+            let keyframe = map.add_keyframe(pose, &frame);
+            for (index, &point) in point_ids.iter().enumerate() {
+                map.add_observation(point, keyframe, index);
+            }
+        }
+
+        // only the last three keyframes are in the window, the first two hold the rest in place
+        let window: Vec<KeyFrameId> = map.keyframes().skip(2).map(|k| k.id).collect();
+        let summary =
+            local_bundle_adjustment(&mut map, &window, &intrinsics(), Loss::Huber(1.0), 30);
+        assert!(summary.final_cost < 1e-6, "{:?}", summary);
+
+        for (keyframe, camera) in map.keyframes().zip(&cameras) {
+            assert!(camera_error(&SE3::from_pose(&keyframe.pose), camera) < 1e-5);
+        }
+        for (&id, expected) in point_ids.iter().zip(&points) {
+            assert!((map.map_point(id).unwrap().position - expected).norm() < 1e-5);
+        }
+        // the fixed keyframes didn't move at all
+        assert_eq!(map.keyframe(0).unwrap().pose, cameras[0].to_pose());
+    }
+}
@@ -1,3 +1,5 @@
+use nalgebra::{Matrix3, Vector3};
+
 #[repr(C)]
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub struct KeyPoint {
@@ -15,3 +17,193 @@ pub struct Image<'a> {
     pub height: usize,
     pub data: &'a [u8],
 }
+
+// A rotation and translation describing how the camera moved between two images.
+pub type Pose = (Matrix3<f64>, Vector3<f64>);
+
+// The pinhole camera model that maps a direction in front of the camera onto a pixel.
+//
+//     | fx  skew  cx |
+// K = |  0   fy   cy |
+//     |  0    0    1 |
+//
+// fx and fy are the focal lengths in pixels, (cx, cy) is the principal point where the optical
+// axis hits the image, and skew is almost always 0 for real cameras.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct CameraIntrinsics {
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+    pub skew: f64,
+}
+
+impl CameraIntrinsics {
+    // None unless both focal lengths are positive, as pixels couldn't be converted otherwise.
+    pub fn new(fx: f64, fy: f64, cx: f64, cy: f64, skew: f64) -> Option<Self> {
+        let valid = |focal: f64| focal > 0.0 && focal.is_finite();
+        (valid(fx) && valid(fy)).then_some(CameraIntrinsics {
+            fx,
+            fy,
+            cx,
+            cy,
+            skew,
+        })
+    }
+
+    // Most devices tell us their horizontal field of view rather than their focal length. With
+    // square pixels and the principal point in the center of the image we can work it out. The
+    // field of view must be between 0 and 180°, in radians.
+    pub fn from_field_of_view(horizontal_fov: f64, width: usize, height: usize) -> Option<Self> {
+        if !(horizontal_fov > 0.0 && horizontal_fov < std::f64::consts::PI) {
+            return None;
+        }
+        let focal = (width as f64 / 2.0) / (horizontal_fov / 2.0).tan();
+        CameraIntrinsics::new(focal, focal, width as f64 / 2.0, height as f64 / 2.0, 0.0)
+    }
+
+    // Without anything better to go on, assume a typical webcam's 60° field of view.
+    pub fn typical_webcam(width: usize, height: usize) -> Self {
+        let focal = (width.max(1) as f64 / 2.0) / 30.0_f64.to_radians().tan();
+        CameraIntrinsics {
+            fx: focal,
+            fy: focal,
+            cx: width as f64 / 2.0,
+            cy: height as f64 / 2.0,
+            skew: 0.0,
+        }
+    }
+
+    pub fn matrix(&self) -> Matrix3<f64> {
+        Matrix3::new(
+            self.fx, self.skew, self.cx, //
+            0.0, self.fy, self.cy, //
+            0.0, 0.0, 1.0,
+        )
+    }
+
+    // Converts a pixel into normalized image coordinates (K⁻¹ · pixel), which is where the point
+    // would land on an ideal camera with a focal length of 1 and the principal point at (0, 0).
+    pub fn normalize(&self, keypoint: &KeyPoint) -> KeyPoint {
+        let y = (keypoint.y as f64 - self.cy) / self.fy;
+        let x = (keypoint.x as f64 - self.cx - self.skew * y) / self.fx;
+        KeyPoint {
+            x: x as f32,
+            y: y as f32,
+            orientation: keypoint.orientation,
+        }
+    }
+
+    // The pixel a point in camera coordinates shows up at, or None if it is behind the camera.
+    pub fn project(&self, point: &Vector3<f64>) -> Option<(f64, f64)> {
+        if point.z <= 0.0 {
+            return None;
+        }
+        let x = point.x / point.z;
+        let y = point.y / point.z;
+        Some((self.fx * x + self.skew * y + self.cx, self.fy * y + self.cy))
+    }
+
+    // A distance in pixels expressed in normalized image coordinates.
+    pub fn pixels_to_normalized(&self, pixels: f64) -> f64 {
+        pixels * 2.0 / (self.fx + self.fy)
+    }
+
+    // The essential matrix relates normalized coordinates, the fundamental matrix relates pixels:
+    // F = K⁻ᵀ · E · K⁻¹, or None if K can't be inverted.
+    pub fn essential_to_fundamental(&self, essential: &Matrix3<f64>) -> Option<Matrix3<f64>> {
+        let k_inv = self.matrix().try_inverse()?;
+        Some(k_inv.transpose() * essential * k_inv)
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let intrinsics = CameraIntrinsics::new(500.0, 400.0, 320.0, 240.0, 2.0).unwrap();
+        let keypoint = KeyPoint {
+            x: 420.0,
+            y: 140.0,
+            orientation: 1.0,
+        };
+
+        let normalized = intrinsics.normalize(&keypoint);
+        assert!((normalized.y - -0.25).abs() < 0.0001);
+        assert!((normalized.x - 0.201).abs() < 0.0001);
+        assert_eq!(normalized.orientation, 1.0);
+
+        // normalizing should be the same as multiplying by the inverse of K
+        let expected = intrinsics.matrix().try_inverse().unwrap() * Vector3::new(420.0, 140.0, 1.0);
+        assert!((normalized.x as f64 - expected[0]).abs() < 0.0001);
+        assert!((normalized.y as f64 - expected[1]).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_project() {
+        let intrinsics = CameraIntrinsics::new(500.0, 400.0, 320.0, 240.0, 2.0).unwrap();
+        let (x, y) = intrinsics.project(&Vector3::new(0.4, -0.5, 2.0)).unwrap();
+
+        // projecting undoes normalizing
+        let normalized = intrinsics.normalize(&KeyPoint {
+            x: x as f32,
+            y: y as f32,
+            orientation: 0.0,
+        });
+        assert!((normalized.x - 0.2).abs() < 0.0001);
+        assert!((normalized.y - -0.25).abs() < 0.0001);
+
+        assert_eq!(intrinsics.project(&Vector3::new(0.4, -0.5, -2.0)), None);
+    }
+
+    #[test]
+    fn test_from_field_of_view() {
+        // a 90 degree field of view means the edge of the image is as far from the center as
+        // the focal length
+        let intrinsics =
+            CameraIntrinsics::from_field_of_view(std::f64::consts::FRAC_PI_2, 640, 480).unwrap();
+        assert!((intrinsics.fx - 320.0).abs() < 0.0001);
+        assert!((intrinsics.fy - 320.0).abs() < 0.0001);
+        assert_eq!(intrinsics.cx, 320.0);
+        assert_eq!(intrinsics.cy, 240.0);
+        assert_eq!(intrinsics.skew, 0.0);
+
+        // no lens sees nothing or everything around it
+        assert!(CameraIntrinsics::from_field_of_view(0.0, 640, 480).is_none());
+        assert!(CameraIntrinsics::from_field_of_view(std::f64::consts::PI, 640, 480).is_none());
+
+        assert_eq!(
+            CameraIntrinsics::typical_webcam(640, 480),
+            CameraIntrinsics::from_field_of_view(60.0_f64.to_radians(), 640, 480).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_invalid_focal_length() {
+        assert!(CameraIntrinsics::new(0.0, 500.0, 320.0, 240.0, 0.0).is_none());
+        assert!(CameraIntrinsics::new(500.0, -500.0, 320.0, 240.0, 0.0).is_none());
+        assert!(CameraIntrinsics::new(f64::NAN, 500.0, 320.0, 240.0, 0.0).is_none());
+        assert!(CameraIntrinsics::new(500.0, 500.0, 320.0, 240.0, 0.0).is_some());
+    }
+
+    #[test]
+    fn test_essential_to_fundamental() {
+        let intrinsics = CameraIntrinsics::new(500.0, 500.0, 320.0, 240.0, 0.0).unwrap();
+        // pure horizontal motion
+        let essential = Matrix3::new(0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 1.0, 0.0);
+        let fundamental = intrinsics.essential_to_fundamental(&essential).unwrap();
+
+        // two pixels on the same row satisfy the epipolar constraint
+        let p1 = Vector3::new(100.0, 200.0, 1.0);
+        let p2 = Vector3::new(300.0, 200.0, 1.0);
+        assert!(p2.dot(&(fundamental * p1)).abs() < 1e-9);
+        let p2 = Vector3::new(300.0, 210.0, 1.0);
+        assert!(p2.dot(&(fundamental * p1)).abs() > 1e-9);
+    }
+}
@@ -0,0 +1,355 @@
+// The five-point algorithm finds the essential matrices consistent with just five matches in
+// normalized image coordinates. An essential matrix has 5 degrees of freedom, so this is the
+// smallest sample that can pin it down, which means RANSAC needs far fewer iterations to find an
+// outlier free sample than with eight points. Unlike the eight-point algorithm it also keeps
+// working when all the points lie on a plane.
+//
+// This follows Nistér's formulation as solved by Stewénius et al.:
+//
+// 1. Each match gives one linear equation p2ᵀ·E·p1 = 0, so five matches leave a 4 dimensional
+//    space of solutions E = x·X + y·Y + z·Z + W.
+// 2. A real essential matrix must also satisfy det(E) = 0 and 2·E·Eᵀ·E - trace(E·Eᵀ)·E = 0, which
+//    gives 10 cubic equations in x, y and z.
+// 3. Eliminating the 10 cubic monomials leaves us able to express "multiply by x" as a 10x10
+//    matrix acting on the remaining monomials. Its eigenvalues are the values of x at each of the
+//    (up to 10) solutions, and its eigenvectors hold the matching y and z.
+
+use nalgebra::{Complex, DMatrix, Matrix3, SMatrix};
+
+use crate::common::KeyPoint;
+
+// Every monomial in x, y and z up to degree three. The 10 cubic ones come first so they are the
+// ones eliminated, the rest form the basis the action matrix works in.
+const MONOMIALS: [(u8, u8, u8); 20] = [
+    (3, 0, 0),
+    (2, 1, 0),
+    (2, 0, 1),
+    (1, 2, 0),
+    (1, 1, 1),
+    (1, 0, 2),
+    (0, 3, 0),
+    (0, 2, 1),
+    (0, 1, 2),
+    (0, 0, 3),
+    (2, 0, 0),
+    (1, 1, 0),
+    (1, 0, 1),
+    (0, 2, 0),
+    (0, 1, 1),
+    (0, 0, 2),
+    (1, 0, 0),
+    (0, 1, 0),
+    (0, 0, 1),
+    (0, 0, 0),
+];
+
+const NUM_CUBIC: usize = 10;
+const X: usize = 16;
+const Y: usize = 17;
+const Z: usize = 18;
+const ONE: usize = 19;
+
+// A polynomial in x, y and z of at most degree three, as coefficients of `MONOMIALS`.
+type Polynomial = [f64; 20];
+
+fn monomial_index(exponents: (u8, u8, u8)) -> usize {
+    MONOMIALS
+        .iter()
+        .position(|&m| m == exponents)
+        .expect("polynomial degree should stay at or below three")
+}
+
+fn add(a: &Polynomial, b: &Polynomial) -> Polynomial {
+    let mut result = *a;
+    for (r, b) in result.iter_mut().zip(b) {
+        *r += b;
+    }
+    result
+}
+
+fn scale(a: &Polynomial, s: f64) -> Polynomial {
+    let mut result = *a;
+    for r in result.iter_mut() {
+        *r *= s;
+    }
+    result
+}
+
+fn multiply(a: &Polynomial, b: &Polynomial) -> Polynomial {
+    let mut result = [0.0; 20];
+    for (i, &coefficient_a) in a.iter().enumerate() {
+        if coefficient_a == 0.0 {
+            continue;
+        }
+        for (j, &coefficient_b) in b.iter().enumerate() {
+            if coefficient_b == 0.0 {
+                continue;
+            }
+            let (ax, ay, az) = MONOMIALS[i];
+            let (bx, by, bz) = MONOMIALS[j];
+            result[monomial_index((ax + bx, ay + by, az + bz))] += coefficient_a * coefficient_b;
+        }
+    }
+    result
+}
+
+type PolynomialMatrix = [[Polynomial; 3]; 3];
+
+fn multiply_matrices(a: &PolynomialMatrix, b: &PolynomialMatrix) -> PolynomialMatrix {
+    let mut result = [[[0.0; 20]; 3]; 3];
+    for (r, row) in result.iter_mut().enumerate() {
+        for (c, entry) in row.iter_mut().enumerate() {
+            for k in 0..3 {
+                *entry = add(entry, &multiply(&a[r][k], &b[k][c]));
+            }
+        }
+    }
+    result
+}
+
+fn transpose(a: &PolynomialMatrix) -> PolynomialMatrix {
+    let mut result = *a;
+    for (r, row) in result.iter_mut().enumerate() {
+        for (c, entry) in row.iter_mut().enumerate() {
+            *entry = a[c][r];
+        }
+    }
+    result
+}
+
+// The 4 dimensional space of matrices satisfying p2ᵀ·E·p1 = 0 for all five matches.
+fn null_space_basis(keypoints: &[(KeyPoint, KeyPoint)]) -> [Matrix3<f64>; 4] {
+    // padded with zero rows so the SVD gives us all 9 right singular vectors
+    let mut a = DMatrix::<f64>::zeros(9, 9);
+    for (i, (p1, p2)) in keypoints.iter().enumerate() {
+        let (x1, y1) = (p1.x as f64, p1.y as f64);
+        let (x2, y2) = (p2.x as f64, p2.y as f64);
+        a[(i, 0)] = x1 * x2;
+        a[(i, 1)] = y1 * x2;
+        a[(i, 2)] = x2;
+        a[(i, 3)] = x1 * y2;
+        a[(i, 4)] = y1 * y2;
+        a[(i, 5)] = y2;
+        a[(i, 6)] = x1;
+        a[(i, 7)] = y1;
+        a[(i, 8)] = 1.0;
+    }
+
+    // singular values are sorted in descending order, so the null space is the last four
+    let v_t = a.svd(false, true).v_t.unwrap();
+    let basis = |row: usize| Matrix3::from_row_slice(v_t.row(row).transpose().as_slice());
+    [basis(5), basis(6), basis(7), basis(8)]
+}
+
+// The 10 cubic constraints every essential matrix E = x·X + y·Y + z·Z + W must satisfy.
+fn constraints(basis: &[Matrix3<f64>; 4]) -> DMatrix<f64> {
+    let mut e: PolynomialMatrix = [[[0.0; 20]; 3]; 3];
+    for (r, row) in e.iter_mut().enumerate() {
+        for (c, entry) in row.iter_mut().enumerate() {
+            entry[X] = basis[0][(r, c)];
+            entry[Y] = basis[1][(r, c)];
+            entry[Z] = basis[2][(r, c)];
+            entry[ONE] = basis[3][(r, c)];
+        }
+    }
+
+    let mut rows = Vec::with_capacity(10);
+
+    // det(E) = 0
+    let determinant = add(
+        &add(
+            &multiply(
+                &e[0][0],
+                &add(
+                    &multiply(&e[1][1], &e[2][2]),
+                    &scale(&multiply(&e[1][2], &e[2][1]), -1.0),
+                ),
+            ),
+            &scale(
+                &multiply(
+                    &e[0][1],
+                    &add(
+                        &multiply(&e[1][0], &e[2][2]),
+                        &scale(&multiply(&e[1][2], &e[2][0]), -1.0),
+                    ),
+                ),
+                -1.0,
+            ),
+        ),
+        &multiply(
+            &e[0][2],
+            &add(
+                &multiply(&e[1][0], &e[2][1]),
+                &scale(&multiply(&e[1][1], &e[2][0]), -1.0),
+            ),
+        ),
+    );
+    rows.push(determinant);
+
+    // 2·E·Eᵀ·E - trace(E·Eᵀ)·E = 0
+    let e_et = multiply_matrices(&e, &transpose(&e));
+    let trace = add(&add(&e_et[0][0], &e_et[1][1]), &e_et[2][2]);
+    let e_et_e = multiply_matrices(&e_et, &e);
+    for r in 0..3 {
+        for c in 0..3 {
+            rows.push(add(
+                &scale(&e_et_e[r][c], 2.0),
+                &scale(&multiply(&trace, &e[r][c]), -1.0),
+            ));
+        }
+    }
+
+    DMatrix::from_fn(10, 20, |r, c| rows[r][c])
+}
+
+// Returns every essential matrix (up to 10) consistent with five matches in normalized image
+// coordinates.
+pub fn five_point(keypoints: &[(KeyPoint, KeyPoint)]) -> Vec<Matrix3<f64>> {
+    assert_eq!(
+        keypoints.len(),
+        5,
+        "the five-point algorithm needs 5 matches"
+    );
+
+    let basis = null_space_basis(keypoints);
+    let a = constraints(&basis);
+
+    // Gauss-Jordan elimination of the cubic monomials: cubic = -B · (remaining monomials)
+    let cubic = a.columns(0, NUM_CUBIC).into_owned();
+    let rest = a.columns(NUM_CUBIC, 20 - NUM_CUBIC).into_owned();
+    let b = match cubic.lu().solve(&rest) {
+        Some(b) => b,
+        None => return vec![],
+    };
+
+    // The action matrix of "multiply by x" on the basis [x², xy, xz, y², yz, z², x, y, z, 1]
+    let mut action = SMatrix::<f64, 10, 10>::zeros();
+    for k in 0..10 {
+        let (mx, my, mz) = MONOMIALS[NUM_CUBIC + k];
+        let product = monomial_index((mx + 1, my, mz));
+        if product < NUM_CUBIC {
+            for j in 0..10 {
+                action[(k, j)] = -b[(product, j)];
+            }
+        } else {
+            action[(k, product - NUM_CUBIC)] = 1.0;
+        }
+    }
+
+    let mut solutions = Vec::new();
+    for eigenvalue in action.complex_eigenvalues().iter() {
+        if !is_real(eigenvalue) {
+            continue;
+        }
+
+        // the eigenvector holds the basis monomials evaluated at this solution
+        let shifted = action - SMatrix::<f64, 10, 10>::identity() * eigenvalue.re;
+        let svd = shifted.svd(false, true);
+        let v_t = svd.v_t.unwrap();
+        let eigenvector = v_t.row(svd.singular_values.imin());
+
+        let one = eigenvector[ONE - NUM_CUBIC];
+        if one.abs() < 1e-12 {
+            continue;
+        }
+        let x = eigenvector[X - NUM_CUBIC] / one;
+        let y = eigenvector[Y - NUM_CUBIC] / one;
+        let z = eigenvector[Z - NUM_CUBIC] / one;
+
+        let essential = basis[0] * x + basis[1] * y + basis[2] * z + basis[3];
+        solutions.push(essential / essential.norm());
+    }
+
+    solutions
+}
+
+fn is_real(value: &Complex<f64>) -> bool {
+    value.im.abs() <= 1e-8 * value.re.abs().max(1.0)
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand::Rand;
+    use crate::synthetic::*;
+    use nalgebra::Vector3;
+
+    fn contains_essential(solutions: &[Matrix3<f64>], expected: &Matrix3<f64>) -> bool {
+        let expected = expected / expected.norm();
+        solutions
+            .iter()
+            .any(|e| (e - expected).norm().min((e + expected).norm()) < 1e-4)
+    }
+
+    #[test]
+    fn test_polynomial_multiply() {
+        // (x + 1) * (y - 2) = xy - 2x + y - 2
+        let mut a = [0.0; 20];
+        a[X] = 1.0;
+        a[ONE] = 1.0;
+        let mut b = [0.0; 20];
+        b[Y] = 1.0;
+        b[ONE] = -2.0;
+
+        let product = multiply(&a, &b);
+        assert_eq!(product[monomial_index((1, 1, 0))], 1.0);
+        assert_eq!(product[X], -2.0);
+        assert_eq!(product[Y], 1.0);
+        assert_eq!(product[ONE], -2.0);
+        assert_eq!(product.iter().filter(|&&c| c != 0.0).count(), 4);
+    }
+
+    #[test]
+    fn test_five_point() {
+        let mut rng = Rand::new_with_seed(5);
+        for _ in 0..10 {
+            let points = random_points(&mut rng, 5);
+            let rotation = small_rotation();
+            let translation = Vector3::new(
+                rng.gen_range(-1.0..=1.0) as f64,
+                rng.gen_range(-1.0..=1.0) as f64,
+                rng.gen_range(-1.0..=1.0) as f64,
+            );
+            let matches = two_view_matches(&points, &rotation, &translation);
+
+            let solutions = five_point(&matches);
+            assert!(!solutions.is_empty() && solutions.len() <= 10);
+            assert!(contains_essential(
+                &solutions,
+                &essential_from_pose(&rotation, &translation)
+            ));
+
+            // every solution has to be consistent with the five matches
+            for essential in solutions.iter() {
+                for (p1, p2) in matches.iter() {
+                    let p1 = Vector3::new(p1.x as f64, p1.y as f64, 1.0);
+                    let p2 = Vector3::new(p2.x as f64, p2.y as f64, 1.0);
+                    assert!(p2.dot(&(essential * p1)).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_five_point_planar_scene() {
+        // all points on the plane z = 5, where the eight-point algorithm is degenerate
+        let mut rng = Rand::new_with_seed(11);
+        let points: Vec<Vector3<f64>> = random_points(&mut rng, 5)
+            .into_iter()
+            .map(|p| Vector3::new(p.x, p.y, 5.0))
+            .collect();
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.5, 0.1, -0.2);
+        let matches = two_view_matches(&points, &rotation, &translation);
+
+        let solutions = five_point(&matches);
+        assert!(contains_essential(
+            &solutions,
+            &essential_from_pose(&rotation, &translation)
+        ));
+    }
+}
@@ -0,0 +1,120 @@
+// A frame is an image after the work that only depends on the image itself: greyscale, blur,
+// keypoints with their orientations and their BRIEF descriptors (phases 1 to 3). In a stream of
+// images every frame gets compared twice, once with the frame before it and once with the frame
+// after it, so doing this work once and keeping the result saves half of it.
+
+use crate::common::*;
+use crate::phase_1;
+use crate::phase_2;
+use crate::phase_3;
+use crate::rand::Rand;
+
+const BLUR_RADIUS: f32 = 3.0;
+const FAST_THRESHOLD: u8 = 30;
+const PATCH_SIZE: usize = 100;
+const NUM_PAIRS: usize = 500;
+// Descriptors can only be compared when they were computed with the same sampling pattern, so
+// every frame generates it from the same seed.
+const SAMPLING_PATTERN_SEED: u64 = 2523523;
+
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub blurred_image: Vec<u8>,
+    pub keypoints: Vec<KeyPoint>,
+    // one per keypoint
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl Frame {
+    pub fn new(image: Image) -> Frame {
+        let width = image.width;
+        let height = image.height;
+
+        // PHASE 1  -  Convert RGB image to greyscale and blur it with a Gaussian filter
+        let greyscale = phase_1::rgb_to_grayscale(image.data, width, height);
+        let blurred_image =
+            phase_1::greyscale_gaussian_blur(&greyscale, width, height, BLUR_RADIUS);
+
+        // PHASE 2  -  Detect FAST keypoints and compute their orientations
+        let keypoints = phase_2::fast_keypoints(&blurred_image, width, height, FAST_THRESHOLD);
+        let keypoints = phase_2::compute_orientations(&blurred_image, width, &keypoints);
+
+        // PHASE 3  -  Compute BRIEF descriptors for each keypoint so we can visually match them
+        let mut random = Rand::new_with_seed(SAMPLING_PATTERN_SEED);
+        let sampling_pattern =
+            phase_3::generate_sampling_pattern(&mut random, PATCH_SIZE, NUM_PAIRS);
+        let descriptors = phase_3::compute_brief_descriptors(
+            &blurred_image,
+            width as u32,
+            height as u32,
+            &keypoints,
+            &sampling_pattern,
+        );
+
+        Frame {
+            width,
+            height,
+            blurred_image,
+            keypoints,
+            descriptors,
+        }
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A white square on a black background, its corners are good FAST keypoints.
+    fn square_image(width: usize, height: usize, offset: usize) -> Vec<u8> {
+        let mut data = vec![0; width * height * 4];
+        for y in 0..height {
+            for x in 0..width {
+                let inside = (20 + offset..40 + offset).contains(&x) && (20..40).contains(&y);
+                let value = if inside { 255 } else { 0 };
+                let i = (y * width + x) * 4;
+                data[i..i + 3].copy_from_slice(&[value; 3]);
+                data[i + 3] = 255;
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_frame() {
+        let data = square_image(64, 64, 0);
+        let frame = Frame::new(Image {
+            data: &data,
+            width: 64,
+            height: 64,
+        });
+
+        assert_eq!(frame.blurred_image.len(), 64 * 64);
+        assert!(!frame.keypoints.is_empty());
+        assert_eq!(frame.keypoints.len(), frame.descriptors.len());
+        assert!(frame
+            .descriptors
+            .iter()
+            .all(|d| d.0.len() == NUM_PAIRS / 8 + 1));
+    }
+
+    #[test]
+    fn test_frames_share_a_sampling_pattern() {
+        // the same image always gets the same descriptors, so they can be compared across frames
+        let data = square_image(64, 64, 5);
+        let image = Image {
+            data: &data,
+            width: 64,
+            height: 64,
+        };
+        let frame_a = Frame::new(image);
+        let frame_b = Frame::new(image);
+        assert_eq!(frame_a.keypoints, frame_b.keypoints);
+        assert_eq!(frame_a.descriptors, frame_b.descriptors);
+    }
+}
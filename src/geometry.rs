@@ -0,0 +1,692 @@
+// Rotations and rigid motions don't form a vector space: adding two rotation matrices doesn't give
+// a rotation. They form Lie groups instead, and optimizing over them works by moving in the
+// tangent space (the Lie algebra, an ordinary vector space) and mapping back onto the group with
+// the exponential map. The log map goes the other way.
+//
+// - SO(3): rotations, with tangent vectors ω (axis times angle).
+// - SE(3): rotations plus translations, with tangent vectors ξ = (ρ, ω).
+// - Sim(3): SE(3) plus a scale, with tangent vectors ζ = (ρ, ω, σ), needed when the scale of a
+//   monocular map drifts.
+//
+// Perturbations are applied on the left everywhere, T ← exp(ξ)·T, matching `phase_7` and `pnp`.
+
+use std::ops::Mul;
+
+use nalgebra::{
+    Matrix3, Matrix4, Matrix6, Rotation3, SMatrix, SVector, UnitQuaternion, Vector3, Vector6,
+};
+
+use crate::common::Pose;
+
+pub type Vector7 = SVector<f64, 7>;
+pub type Matrix7 = SMatrix<f64, 7, 7>;
+
+// Below this angle the closed forms divide by (nearly) zero, so their Taylor expansions are used.
+const SMALL_ANGLE: f64 = 1e-6;
+
+// The skew-symmetric matrix [v]ₓ with [v]ₓ·u = v × u.
+pub fn hat(v: &Vector3<f64>) -> Matrix3<f64> {
+    v.cross_matrix()
+}
+
+// The vector of a skew-symmetric matrix, the inverse of `hat`.
+pub fn vee(m: &Matrix3<f64>) -> Vector3<f64> {
+    Vector3::new(m[(2, 1)], m[(0, 2)], m[(1, 0)])
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct SO3 {
+    matrix: Matrix3<f64>,
+}
+
+impl SO3 {
+    pub fn identity() -> Self {
+        SO3 {
+            matrix: Matrix3::identity(),
+        }
+    }
+
+    // Snaps a nearly orthonormal matrix (e.g. after many compositions) back onto the group.
+    pub fn from_matrix(matrix: &Matrix3<f64>) -> Self {
+        SO3 {
+            matrix: *Rotation3::from_matrix(matrix).matrix(),
+        }
+    }
+
+    pub fn matrix(&self) -> Matrix3<f64> {
+        self.matrix
+    }
+
+    pub fn exp(omega: &Vector3<f64>) -> Self {
+        SO3 {
+            matrix: *Rotation3::new(*omega).matrix(),
+        }
+    }
+
+    pub fn log(&self) -> Vector3<f64> {
+        // R - Rᵀ = 2·sin θ·[axis]ₓ and trace(R) = 1 + 2·cos θ
+        let skew = vee(&(self.matrix - self.matrix.transpose())) / 2.0;
+        let sin_theta = skew.norm();
+        let cos_theta = (self.matrix.trace() - 1.0) / 2.0;
+        let theta = sin_theta.atan2(cos_theta);
+
+        if theta < SMALL_ANGLE {
+            // sin θ ≈ θ
+            skew
+        } else if std::f64::consts::PI - theta < SMALL_ANGLE {
+            // sin θ ≈ 0 leaves the axis undetermined by the skew part
+            Rotation3::from_matrix_unchecked(self.matrix).scaled_axis()
+        } else {
+            skew * (theta / sin_theta)
+        }
+    }
+
+    pub fn inverse(&self) -> Self {
+        SO3 {
+            matrix: self.matrix.transpose(),
+        }
+    }
+
+    pub fn act(&self, point: &Vector3<f64>) -> Vector3<f64> {
+        self.matrix * point
+    }
+
+    // R·exp(ω)·Rᵀ = exp(R·ω), so the adjoint of a rotation is the rotation itself.
+    pub fn adjoint(&self) -> Matrix3<f64> {
+        self.matrix
+    }
+
+    pub fn from_quaternion(quaternion: &UnitQuaternion<f64>) -> Self {
+        SO3 {
+            matrix: *quaternion.to_rotation_matrix().matrix(),
+        }
+    }
+
+    pub fn to_quaternion(&self) -> UnitQuaternion<f64> {
+        UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(self.matrix))
+    }
+
+    pub fn from_axis_angle(axis: &Vector3<f64>, angle: f64) -> Self {
+        SO3::exp(&(axis.normalize() * angle))
+    }
+
+    // The axis is arbitrary (x) for the identity.
+    pub fn to_axis_angle(&self) -> (Vector3<f64>, f64) {
+        let omega = self.log();
+        let angle = omega.norm();
+        if angle < SMALL_ANGLE {
+            (Vector3::x(), 0.0)
+        } else {
+            (omega / angle, angle)
+        }
+    }
+
+    // Goes along the shortest rotation from self (t = 0) to other (t = 1).
+    pub fn interpolate(&self, other: &SO3, t: f64) -> SO3 {
+        *self * SO3::exp(&((self.inverse() * *other).log() * t))
+    }
+
+    // How a point moves as the rotation is perturbed, d(exp(ω)·R·p)/dω at ω = 0.
+    pub fn act_jacobian(&self, point: &Vector3<f64>) -> Matrix3<f64> {
+        -hat(&self.act(point))
+    }
+
+    // The left Jacobian J(ω), with exp(ω + δ) ≈ exp(J(ω)·δ)·exp(ω) for small δ. It is also what
+    // turns the translation part of an SE(3) tangent vector into a translation.
+    pub fn left_jacobian(omega: &Vector3<f64>) -> Matrix3<f64> {
+        let theta = omega.norm();
+        let w = hat(omega);
+        let (a, b) = if theta < SMALL_ANGLE {
+            (0.5, 1.0 / 6.0)
+        } else {
+            (
+                (1.0 - theta.cos()) / (theta * theta),
+                (theta - theta.sin()) / (theta * theta * theta),
+            )
+        };
+        Matrix3::identity() + w * a + w * w * b
+    }
+
+    pub fn left_jacobian_inverse(omega: &Vector3<f64>) -> Matrix3<f64> {
+        let theta = omega.norm();
+        let w = hat(omega);
+        let b = if theta < SMALL_ANGLE {
+            1.0 / 12.0
+        } else {
+            (1.0 - theta * theta.sin() / (2.0 * (1.0 - theta.cos()))) / (theta * theta)
+        };
+        Matrix3::identity() - w * 0.5 + w * w * b
+    }
+
+    // The right Jacobian, with exp(ω + δ) ≈ exp(ω)·exp(J(ω)·δ), is the left one of -ω.
+    pub fn right_jacobian(omega: &Vector3<f64>) -> Matrix3<f64> {
+        SO3::left_jacobian(&-omega)
+    }
+}
+
+impl Mul for SO3 {
+    type Output = SO3;
+
+    fn mul(self, other: SO3) -> SO3 {
+        SO3 {
+            matrix: self.matrix * other.matrix,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct SE3 {
+    pub rotation: SO3,
+    pub translation: Vector3<f64>,
+}
+
+impl SE3 {
+    pub fn identity() -> Self {
+        SE3 {
+            rotation: SO3::identity(),
+            translation: Vector3::zeros(),
+        }
+    }
+
+    pub fn new(rotation: SO3, translation: Vector3<f64>) -> Self {
+        SE3 {
+            rotation,
+            translation,
+        }
+    }
+
+    pub fn from_pose((rotation, translation): &Pose) -> Self {
+        SE3::new(SO3::from_matrix(rotation), *translation)
+    }
+
+    pub fn to_pose(&self) -> Pose {
+        (self.rotation.matrix(), self.translation)
+    }
+
+    pub fn matrix(&self) -> Matrix4<f64> {
+        let mut matrix = Matrix4::identity();
+        matrix
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&self.rotation.matrix());
+        matrix
+            .fixed_view_mut::<3, 1>(0, 3)
+            .copy_from(&self.translation);
+        matrix
+    }
+
+    // ξ = (ρ, ω): the rotation is exp(ω) and the translation J(ω)·ρ.
+    pub fn exp(xi: &Vector6<f64>) -> Self {
+        let rho = xi.fixed_rows::<3>(0).into_owned();
+        let omega = xi.fixed_rows::<3>(3).into_owned();
+        SE3::new(SO3::exp(&omega), SO3::left_jacobian(&omega) * rho)
+    }
+
+    pub fn log(&self) -> Vector6<f64> {
+        let omega = self.rotation.log();
+        let rho = SO3::left_jacobian_inverse(&omega) * self.translation;
+        Vector6::new(rho.x, rho.y, rho.z, omega.x, omega.y, omega.z)
+    }
+
+    pub fn inverse(&self) -> Self {
+        let rotation = self.rotation.inverse();
+        SE3::new(rotation, -rotation.act(&self.translation))
+    }
+
+    pub fn act(&self, point: &Vector3<f64>) -> Vector3<f64> {
+        self.rotation.act(point) + self.translation
+    }
+
+    // T·exp(ξ)·T⁻¹ = exp(Ad(T)·ξ), which moves a perturbation from one side of T to the other.
+    pub fn adjoint(&self) -> Matrix6<f64> {
+        let r = self.rotation.matrix();
+        let mut adjoint = Matrix6::zeros();
+        adjoint.fixed_view_mut::<3, 3>(0, 0).copy_from(&r);
+        adjoint
+            .fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(hat(&self.translation) * r));
+        adjoint.fixed_view_mut::<3, 3>(3, 3).copy_from(&r);
+        adjoint
+    }
+
+    pub fn interpolate(&self, other: &SE3, t: f64) -> SE3 {
+        *self * SE3::exp(&((self.inverse() * *other).log() * t))
+    }
+
+    // How a point moves as the pose is perturbed, d(exp(ξ)·T·p)/dξ at ξ = 0.
+    pub fn act_jacobian(&self, point: &Vector3<f64>) -> SMatrix<f64, 3, 6> {
+        let mut jacobian = SMatrix::<f64, 3, 6>::zeros();
+        jacobian
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&Matrix3::identity());
+        jacobian
+            .fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&-hat(&self.act(point)));
+        jacobian
+    }
+}
+
+impl Mul for SE3 {
+    type Output = SE3;
+
+    fn mul(self, other: SE3) -> SE3 {
+        SE3::new(self.rotation * other.rotation, self.act(&other.translation))
+    }
+}
+
+// A similarity transform, p ↦ s·R·p + t.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Sim3 {
+    pub rotation: SO3,
+    pub translation: Vector3<f64>,
+    pub scale: f64,
+}
+
+impl Sim3 {
+    pub fn identity() -> Self {
+        Sim3::new(SO3::identity(), Vector3::zeros(), 1.0)
+    }
+
+    pub fn new(rotation: SO3, translation: Vector3<f64>, scale: f64) -> Self {
+        Sim3 {
+            rotation,
+            translation,
+            scale,
+        }
+    }
+
+    pub fn from_se3(se3: &SE3) -> Self {
+        Sim3::new(se3.rotation, se3.translation, 1.0)
+    }
+
+    pub fn matrix(&self) -> Matrix4<f64> {
+        let mut matrix = Matrix4::identity();
+        matrix
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(self.rotation.matrix() * self.scale));
+        matrix
+            .fixed_view_mut::<3, 1>(0, 3)
+            .copy_from(&self.translation);
+        matrix
+    }
+
+    // The translation of exp(ζ) is W·ρ, where W = Σ Ωᵏ / (k + 1)! for Ω = [ω]ₓ + σ·I. Rather than
+    // its closed form (which needs care whenever ω or σ is small) we read it off the matrix
+    // exponential of [[Ω, I], [0, 0]], whose top right block is exactly that series.
+    fn w_matrix(omega: &Vector3<f64>, sigma: f64) -> Matrix3<f64> {
+        let mut generator = Matrix6::zeros();
+        generator
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(hat(omega) + Matrix3::identity() * sigma));
+        generator
+            .fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&Matrix3::identity());
+        generator.exp().fixed_view::<3, 3>(0, 3).into_owned()
+    }
+
+    // ζ = (ρ, ω, σ): the rotation is exp(ω), the scale e^σ and the translation W·ρ.
+    pub fn exp(zeta: &Vector7) -> Self {
+        let rho = zeta.fixed_rows::<3>(0).into_owned();
+        let omega = zeta.fixed_rows::<3>(3).into_owned();
+        let sigma = zeta[6];
+        Sim3::new(
+            SO3::exp(&omega),
+            Sim3::w_matrix(&omega, sigma) * rho,
+            sigma.exp(),
+        )
+    }
+
+    pub fn log(&self) -> Vector7 {
+        let omega = self.rotation.log();
+        let sigma = self.scale.ln();
+        let rho = Sim3::w_matrix(&omega, sigma)
+            .try_inverse()
+            .unwrap_or_else(Matrix3::identity)
+            * self.translation;
+        Vector7::from_column_slice(&[rho.x, rho.y, rho.z, omega.x, omega.y, omega.z, sigma])
+    }
+
+    pub fn inverse(&self) -> Self {
+        let rotation = self.rotation.inverse();
+        let scale = 1.0 / self.scale;
+        Sim3::new(rotation, -rotation.act(&self.translation) * scale, scale)
+    }
+
+    pub fn act(&self, point: &Vector3<f64>) -> Vector3<f64> {
+        self.rotation.act(point) * self.scale + self.translation
+    }
+
+    // S·exp(ζ)·S⁻¹ = exp(Ad(S)·ζ).
+    pub fn adjoint(&self) -> Matrix7 {
+        let r = self.rotation.matrix();
+        let mut adjoint = Matrix7::zeros();
+        adjoint
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(r * self.scale));
+        adjoint
+            .fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(hat(&self.translation) * r));
+        adjoint
+            .fixed_view_mut::<3, 1>(0, 6)
+            .copy_from(&-self.translation);
+        adjoint.fixed_view_mut::<3, 3>(3, 3).copy_from(&r);
+        adjoint[(6, 6)] = 1.0;
+        adjoint
+    }
+
+    pub fn interpolate(&self, other: &Sim3, t: f64) -> Sim3 {
+        *self * Sim3::exp(&((self.inverse() * *other).log() * t))
+    }
+
+    // How a point moves as the transform is perturbed, d(exp(ζ)·S·p)/dζ at ζ = 0.
+    pub fn act_jacobian(&self, point: &Vector3<f64>) -> SMatrix<f64, 3, 7> {
+        let moved = self.act(point);
+        let mut jacobian = SMatrix::<f64, 3, 7>::zeros();
+        jacobian
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&Matrix3::identity());
+        jacobian
+            .fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&-hat(&moved));
+        jacobian.fixed_view_mut::<3, 1>(0, 6).copy_from(&moved);
+        jacobian
+    }
+}
+
+impl Mul for Sim3 {
+    type Output = Sim3;
+
+    fn mul(self, other: Sim3) -> Sim3 {
+        Sim3::new(
+            self.rotation * other.rotation,
+            self.act(&other.translation),
+            self.scale * other.scale,
+        )
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f64 = 1e-6;
+
+    fn some_rotation() -> SO3 {
+        SO3::exp(&Vector3::new(0.3, -0.5, 0.8))
+    }
+
+    fn some_se3() -> SE3 {
+        SE3::new(some_rotation(), Vector3::new(1.0, -2.0, 0.5))
+    }
+
+    fn some_sim3() -> Sim3 {
+        Sim3::new(some_rotation(), Vector3::new(1.0, -2.0, 0.5), 1.7)
+    }
+
+    macro_rules! assert_close {
+        ($a:expr, $b:expr, $tolerance:expr $(,)?) => {{
+            let difference = ($a - $b).norm();
+            assert!(
+                difference < $tolerance,
+                "differ by {}:\n{}\n{}",
+                difference,
+                $a,
+                $b
+            );
+        }};
+    }
+
+    #[test]
+    fn test_hat_vee() {
+        let v = Vector3::new(1.0, 2.0, 3.0);
+        let u = Vector3::new(-0.5, 0.3, 2.0);
+        assert_close!(&(hat(&v) * u), &v.cross(&u), 1e-12);
+        assert_eq!(vee(&hat(&v)), v);
+    }
+
+    #[test]
+    fn test_so3_exp_log() {
+        for omega in [
+            Vector3::new(0.3, -0.5, 0.8),
+            Vector3::new(1e-9, 0.0, -2e-9),
+            Vector3::new(0.0, 3.0, 0.0),
+        ] {
+            assert_close!(&SO3::exp(&omega).log(), &omega, 1e-9);
+        }
+        let r = some_rotation();
+        assert_close!(
+            &(r * r.inverse()).matrix(),
+            &Matrix3::<f64>::identity(),
+            1e-12
+        );
+    }
+
+    #[test]
+    fn test_so3_conversions() {
+        let r = some_rotation();
+        assert_close!(
+            &SO3::from_quaternion(&r.to_quaternion()).matrix(),
+            &r.matrix(),
+            1e-12,
+        );
+        let (axis, angle) = r.to_axis_angle();
+        assert_close!(
+            &SO3::from_axis_angle(&axis, angle).matrix(),
+            &r.matrix(),
+            1e-12,
+        );
+        assert_eq!(SO3::identity().to_axis_angle().1, 0.0);
+
+        let about_z =
+            SO3::from_axis_angle(&Vector3::new(0.0, 0.0, 2.0), std::f64::consts::FRAC_PI_2);
+        assert_close!(&about_z.act(&Vector3::x()), &Vector3::<f64>::y(), 1e-12);
+    }
+
+    #[test]
+    fn test_so3_interpolate() {
+        let a = some_rotation();
+        let b = SO3::exp(&Vector3::new(-0.2, 0.1, 0.4)) * a;
+        assert_close!(&a.interpolate(&b, 0.0).matrix(), &a.matrix(), 1e-12);
+        assert_close!(&a.interpolate(&b, 1.0).matrix(), &b.matrix(), 1e-12);
+        // halfway is the same angle from both ends
+        let half = a.interpolate(&b, 0.5);
+        let to_a = (half.inverse() * a).log().norm();
+        let to_b = (half.inverse() * b).log().norm();
+        assert!((to_a - to_b).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_so3_jacobians() {
+        let omega = Vector3::new(0.3, -0.5, 0.8);
+        let left = SO3::left_jacobian(&omega);
+        let right = SO3::right_jacobian(&omega);
+
+        // exp(ω + δ) ≈ exp(J_l·δ)·exp(ω) ≈ exp(ω)·exp(J_r·δ), checked one direction at a time
+        for i in 0..3 {
+            let mut delta = Vector3::zeros();
+            delta[i] = EPSILON;
+            let forward = SO3::exp(&(omega + delta));
+            let backward = SO3::exp(&(omega - delta));
+            let numeric_left = (forward * backward.inverse()).log() / (2.0 * EPSILON);
+            let numeric_right = (backward.inverse() * forward).log() / (2.0 * EPSILON);
+            assert_close!(&numeric_left, &left.column(i), 1e-5);
+            assert_close!(&numeric_right, &right.column(i), 1e-5);
+        }
+
+        assert_close!(
+            &(SO3::left_jacobian_inverse(&omega) * left),
+            &Matrix3::<f64>::identity(),
+            1e-12,
+        );
+        let tiny = Vector3::new(1e-9, 0.0, 0.0);
+        assert_close!(
+            &(SO3::left_jacobian_inverse(&tiny) * SO3::left_jacobian(&tiny)),
+            &Matrix3::<f64>::identity(),
+            1e-12,
+        );
+    }
+
+    #[test]
+    fn test_so3_act_jacobian() {
+        let r = some_rotation();
+        let point = Vector3::new(1.0, 2.0, -3.0);
+        let jacobian = r.act_jacobian(&point);
+        for i in 0..3 {
+            let mut delta = Vector3::zeros();
+            delta[i] = EPSILON;
+            let numeric = ((SO3::exp(&delta) * r).act(&point)
+                - (SO3::exp(&-delta) * r).act(&point))
+                / (2.0 * EPSILON);
+            assert_close!(&numeric, &jacobian.column(i), 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_se3_exp_log() {
+        let xi = Vector6::new(1.0, -2.0, 0.5, 0.3, -0.5, 0.8);
+        assert_close!(&SE3::exp(&xi).log(), &xi, 1e-9);
+        let t = some_se3();
+        assert_close!(&SE3::exp(&t.log()).matrix(), &t.matrix(), 1e-9);
+
+        // without rotation exp is just a translation
+        let pure = Vector6::new(1.0, 2.0, 3.0, 0.0, 0.0, 0.0);
+        assert_close!(
+            &SE3::exp(&pure).translation,
+            &Vector3::new(1.0, 2.0, 3.0),
+            1e-12,
+        );
+    }
+
+    #[test]
+    fn test_se3_compose_inverse() {
+        let a = some_se3();
+        let b = SE3::exp(&Vector6::new(0.1, 0.2, 0.3, -0.2, 0.1, 0.05));
+        let point = Vector3::new(1.0, 2.0, -3.0);
+        assert_close!(&(a * b).act(&point), &a.act(&b.act(&point)), 1e-12);
+        assert_close!(&(a * b).matrix(), &(a.matrix() * b.matrix()), 1e-12);
+        assert_close!(
+            &(a * a.inverse()).matrix(),
+            &Matrix4::<f64>::identity(),
+            1e-12
+        );
+
+        let pose = a.to_pose();
+        assert_close!(&SE3::from_pose(&pose).matrix(), &a.matrix(), 1e-12);
+    }
+
+    #[test]
+    fn test_se3_adjoint() {
+        let t = some_se3();
+        let xi = Vector6::new(0.1, -0.2, 0.3, 0.05, 0.1, -0.15);
+        let conjugated = t * SE3::exp(&xi) * t.inverse();
+        assert_close!(&conjugated.log(), &(t.adjoint() * xi), 1e-9);
+    }
+
+    #[test]
+    fn test_se3_interpolate() {
+        let a = some_se3();
+        let b = SE3::exp(&Vector6::new(0.4, 0.2, -0.3, -0.2, 0.1, 0.3)) * a;
+        assert_close!(&a.interpolate(&b, 0.0).matrix(), &a.matrix(), 1e-12);
+        assert_close!(&a.interpolate(&b, 1.0).matrix(), &b.matrix(), 1e-9);
+        // two half steps make a whole one
+        let half = a.interpolate(&b, 0.5);
+        assert_close!(&half.interpolate(&b, 1.0).matrix(), &b.matrix(), 1e-9);
+        let twice = a * (a.inverse() * half) * (a.inverse() * half);
+        assert_close!(&twice.matrix(), &b.matrix(), 1e-9);
+    }
+
+    #[test]
+    fn test_se3_act_jacobian() {
+        let t = some_se3();
+        let point = Vector3::new(1.0, 2.0, -3.0);
+        let jacobian = t.act_jacobian(&point);
+        for i in 0..6 {
+            let mut delta = Vector6::zeros();
+            delta[i] = EPSILON;
+            let numeric = ((SE3::exp(&delta) * t).act(&point)
+                - (SE3::exp(&-delta) * t).act(&point))
+                / (2.0 * EPSILON);
+            assert_close!(&numeric, &jacobian.column(i), 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_sim3_exp_log() {
+        let zeta = Vector7::from_column_slice(&[1.0, -2.0, 0.5, 0.3, -0.5, 0.8, 0.4]);
+        assert_close!(&Sim3::exp(&zeta).log(), &zeta, 1e-9);
+
+        // with no scale change it is SE(3)
+        let xi = Vector6::new(1.0, -2.0, 0.5, 0.3, -0.5, 0.8);
+        let zeta = Vector7::from_column_slice(&[1.0, -2.0, 0.5, 0.3, -0.5, 0.8, 0.0]);
+        assert_close!(
+            &Sim3::exp(&zeta).matrix(),
+            &Sim3::from_se3(&SE3::exp(&xi)).matrix(),
+            1e-9,
+        );
+
+        // and the matrix exponential of its generator
+        let mut generator = Matrix4::zeros();
+        generator
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(hat(&Vector3::new(0.3, -0.5, 0.8)) + Matrix3::identity() * 0.4));
+        generator
+            .fixed_view_mut::<3, 1>(0, 3)
+            .copy_from(&Vector3::new(1.0, -2.0, 0.5));
+        let zeta = Vector7::from_column_slice(&[1.0, -2.0, 0.5, 0.3, -0.5, 0.8, 0.4]);
+        assert_close!(&Sim3::exp(&zeta).matrix(), &generator.exp(), 1e-9);
+    }
+
+    #[test]
+    fn test_sim3_compose_inverse() {
+        let a = some_sim3();
+        let b = Sim3::exp(&Vector7::from_column_slice(&[
+            0.1, 0.2, 0.3, -0.2, 0.1, 0.05, -0.3,
+        ]));
+        let point = Vector3::new(1.0, 2.0, -3.0);
+        assert_close!(&(a * b).act(&point), &a.act(&b.act(&point)), 1e-12);
+        assert_close!(&(a * b).matrix(), &(a.matrix() * b.matrix()), 1e-12);
+        assert_close!(
+            &(a * a.inverse()).matrix(),
+            &Matrix4::<f64>::identity(),
+            1e-12
+        );
+    }
+
+    #[test]
+    fn test_sim3_adjoint() {
+        let s = some_sim3();
+        let zeta = Vector7::from_column_slice(&[0.1, -0.2, 0.3, 0.05, 0.1, -0.15, 0.2]);
+        let conjugated = s * Sim3::exp(&zeta) * s.inverse();
+        assert_close!(&conjugated.log(), &(s.adjoint() * zeta), 1e-9);
+    }
+
+    #[test]
+    fn test_sim3_interpolate() {
+        let a = some_sim3();
+        let b = Sim3::exp(&Vector7::from_column_slice(&[
+            0.4, 0.2, -0.3, -0.2, 0.1, 0.3, 0.5,
+        ])) * a;
+        assert_close!(&a.interpolate(&b, 1.0).matrix(), &b.matrix(), 1e-9);
+        // the scale changes geometrically
+        let half = a.interpolate(&b, 0.5);
+        assert!((half.scale - (a.scale * b.scale).sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_sim3_act_jacobian() {
+        let s = some_sim3();
+        let point = Vector3::new(1.0, 2.0, -3.0);
+        let jacobian = s.act_jacobian(&point);
+        for i in 0..7 {
+            let mut delta = Vector7::zeros();
+            delta[i] = EPSILON;
+            let numeric = ((Sim3::exp(&delta) * s).act(&point)
+                - (Sim3::exp(&-delta) * s).act(&point))
+                / (2.0 * EPSILON);
+            assert_close!(&numeric, &jacobian.column(i), 1e-6);
+        }
+    }
+}
@@ -0,0 +1,430 @@
+// When everything the camera sees lies on a plane (a table, a wall, a floor) the essential matrix
+// can't be pinned down, because many different motions explain the matches equally well. A
+// homography H is what relates two views of a plane instead: p2 ~ H·p1. For a plane n·X = d in
+// front of the first camera and a motion X2 = R·X1 + t it is H = R + t·nᵀ/d, so we can recover
+// the motion from it.
+
+use nalgebra::{DMatrix, Matrix3, Vector3};
+
+use crate::common::KeyPoint;
+use crate::phase_5;
+use crate::phase_6;
+use crate::rand::Rand;
+use crate::ransac::{self, Estimator, RansacOptions, RansacResult};
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct HomographyDecomposition {
+    pub rotation: Matrix3<f64>,
+    // only known up to scale, this has length 1
+    pub translation: Vector3<f64>,
+    // the normal of the plane in the first camera's coordinates
+    pub normal: Vector3<f64>,
+}
+
+// The direct linear transform from four or more matches, with the points normalized first the same
+// way as the eight-point algorithm. Each match gives two equations from p2 × (H·p1) = 0.
+pub fn keypoints_to_homography(keypoints: &[(KeyPoint, KeyPoint)]) -> Matrix3<f64> {
+    let points_1: Vec<(f64, f64)> = keypoints
+        .iter()
+        .map(|(p1, _)| (p1.x as f64, p1.y as f64))
+        .collect();
+    let points_2: Vec<(f64, f64)> = keypoints
+        .iter()
+        .map(|(_, p2)| (p2.x as f64, p2.y as f64))
+        .collect();
+    let t1 = phase_5::hartley_normalization(&points_1);
+    let t2 = phase_5::hartley_normalization(&points_2);
+
+    let mut a = DMatrix::<f64>::zeros(keypoints.len() * 2, 9);
+    for (i, (p1, p2)) in points_1.iter().zip(&points_2).enumerate() {
+        let p1 = t1 * Vector3::new(p1.0, p1.1, 1.0);
+        let p2 = t2 * Vector3::new(p2.0, p2.1, 1.0);
+
+        a[(2 * i, 3)] = -p1.x;
+        a[(2 * i, 4)] = -p1.y;
+        a[(2 * i, 5)] = -1.0;
+        a[(2 * i, 6)] = p2.y * p1.x;
+        a[(2 * i, 7)] = p2.y * p1.y;
+        a[(2 * i, 8)] = p2.y;
+
+        a[(2 * i + 1, 0)] = p1.x;
+        a[(2 * i + 1, 1)] = p1.y;
+        a[(2 * i + 1, 2)] = 1.0;
+        a[(2 * i + 1, 6)] = -p2.x * p1.x;
+        a[(2 * i + 1, 7)] = -p2.x * p1.y;
+        a[(2 * i + 1, 8)] = -p2.x;
+    }
+
+    let h = Matrix3::from_row_slice(&phase_5::null_vector(&a));
+
+    // Undo the normalization
+    match t2.try_inverse() {
+        Some(t2_inv) => t2_inv * h * t1,
+        None => h,
+    }
+}
+
+fn transfer_squared_error(homography: &Matrix3<f64>, from: &KeyPoint, to: &KeyPoint) -> f64 {
+    let projected = homography * Vector3::new(from.x as f64, from.y as f64, 1.0);
+    if projected.z == 0.0 {
+        return f64::INFINITY;
+    }
+    (projected.x / projected.z - to.x as f64).powi(2)
+        + (projected.y / projected.z - to.y as f64).powi(2)
+}
+
+// How far each point lands from its match when mapped across by the homography, in both directions.
+fn symmetric_transfer_error(homography: &Matrix3<f64>, p1: &KeyPoint, p2: &KeyPoint) -> f64 {
+    let inverse = match homography.try_inverse() {
+        Some(inverse) => inverse,
+        None => return f64::INFINITY,
+    };
+    transfer_squared_error(homography, p1, p2).sqrt()
+        + transfer_squared_error(&inverse, p2, p1).sqrt()
+}
+
+struct HomographyEstimator<'a> {
+    matches: &'a [(KeyPoint, KeyPoint)],
+}
+
+impl<'a> Estimator for HomographyEstimator<'a> {
+    type Model = Matrix3<f64>;
+
+    fn sample_size(&self) -> usize {
+        4
+    }
+
+    fn num_data(&self) -> usize {
+        self.matches.len()
+    }
+
+    fn estimate(&self, sample: &[usize]) -> Vec<Matrix3<f64>> {
+        vec![keypoints_to_homography(&phase_5::sample_matches(
+            self.matches,
+            sample,
+        ))]
+    }
+
+    fn residual(&self, homography: &Matrix3<f64>, index: usize) -> f64 {
+        let (p1, p2) = &self.matches[index];
+        symmetric_transfer_error(homography, p1, p2)
+    }
+
+    fn refine(&self, inliers: &[usize]) -> Option<Matrix3<f64>> {
+        Some(keypoints_to_homography(&phase_5::sample_matches(
+            self.matches,
+            inliers,
+        )))
+    }
+}
+
+pub fn estimate_homography_ransac(
+    key_points: &[(KeyPoint, KeyPoint)],
+    options: &RansacOptions,
+    rnd: &mut Rand,
+) -> Option<RansacResult<Matrix3<f64>>> {
+    let estimator = HomographyEstimator {
+        matches: key_points,
+    };
+    if key_points.len() < estimator.sample_size() {
+        return None;
+    }
+    ransac::estimate(&estimator, options, rnd)
+}
+
+// How well a homography explains the matches, scored the way ORB-SLAM does so it can be compared
+// against `phase_5::score_essential`.
+pub fn score_homography(
+    homography: &Matrix3<f64>,
+    key_points: &[(KeyPoint, KeyPoint)],
+    sigma: f64,
+) -> f64 {
+    let inverse = match homography.try_inverse() {
+        Some(inverse) => inverse,
+        None => return 0.0,
+    };
+    // chi-squared threshold at 95% for two degrees of freedom
+    let threshold = 5.991;
+    let inv_sigma_squared = 1.0 / (sigma * sigma);
+
+    key_points
+        .iter()
+        .map(|(p1, p2)| {
+            [
+                transfer_squared_error(homography, p1, p2) * inv_sigma_squared,
+                transfer_squared_error(&inverse, p2, p1) * inv_sigma_squared,
+            ]
+            .iter()
+            .filter(|&&chi_squared| chi_squared <= threshold)
+            .map(|chi_squared| threshold - chi_squared)
+            .sum::<f64>()
+        })
+        .sum()
+}
+
+// ORB-SLAM's heuristic: the homography wins if it accounts for more than 45% of the combined score.
+pub fn prefer_homography(homography_score: f64, essential_score: f64) -> bool {
+    let total = homography_score + essential_score;
+    total > 0.0 && homography_score / total > 0.45
+}
+
+// Recovers the up to 8 possible motions and plane normals from a homography between normalized
+// image coordinates, following Faugeras' SVD based method. Returns nothing when the motion can't be
+// recovered, which happens when the singular values are (nearly) equal, such as for a pure rotation.
+pub fn decompose_homography(homography: &Matrix3<f64>) -> Vec<HomographyDecomposition> {
+    let svd = homography.svd(true, true);
+    let u = svd.u.unwrap();
+    let v_t = svd.v_t.unwrap();
+    let v = v_t.transpose();
+    let s = u.determinant() * v_t.determinant();
+
+    let d1 = svd.singular_values[0];
+    let d2 = svd.singular_values[1];
+    let d3 = svd.singular_values[2];
+    if d1 / d2 < 1.00001 || d2 / d3 < 1.00001 {
+        return vec![];
+    }
+
+    let aux1 = ((d1 * d1 - d2 * d2) / (d1 * d1 - d3 * d3)).sqrt();
+    let aux3 = ((d2 * d2 - d3 * d3) / (d1 * d1 - d3 * d3)).sqrt();
+    let x1 = [aux1, aux1, -aux1, -aux1];
+    let x3 = [aux3, -aux3, aux3, -aux3];
+
+    let mut decompositions = Vec::with_capacity(8);
+    let mut push = |rotation_prime: Matrix3<f64>, translation_prime: Vector3<f64>, i: usize| {
+        let rotation = u * rotation_prime * v_t * s;
+        let translation = (u * translation_prime).normalize();
+        let mut normal = v * Vector3::new(x1[i], 0.0, x3[i]);
+        if normal.z < 0.0 {
+            normal = -normal;
+        }
+        decompositions.push(HomographyDecomposition {
+            rotation,
+            translation,
+            normal,
+        });
+    };
+
+    // case d' = d2
+    let aux_sin_theta = ((d1 * d1 - d2 * d2) * (d2 * d2 - d3 * d3)).sqrt() / ((d1 + d3) * d2);
+    let cos_theta = (d2 * d2 + d1 * d3) / ((d1 + d3) * d2);
+    let sin_theta = [aux_sin_theta, -aux_sin_theta, -aux_sin_theta, aux_sin_theta];
+    for i in 0..4 {
+        let rotation_prime = Matrix3::new(
+            cos_theta,
+            0.0,
+            -sin_theta[i],
+            0.0,
+            1.0,
+            0.0,
+            sin_theta[i],
+            0.0,
+            cos_theta,
+        );
+        let translation_prime = Vector3::new(x1[i], 0.0, -x3[i]) * (d1 - d3);
+        push(rotation_prime, translation_prime, i);
+    }
+
+    // case d' = -d2
+    let aux_sin_phi = ((d1 * d1 - d2 * d2) * (d2 * d2 - d3 * d3)).sqrt() / ((d1 - d3) * d2);
+    let cos_phi = (d1 * d3 - d2 * d2) / ((d1 - d3) * d2);
+    let sin_phi = [aux_sin_phi, -aux_sin_phi, -aux_sin_phi, aux_sin_phi];
+    for i in 0..4 {
+        let rotation_prime = Matrix3::new(
+            cos_phi, 0.0, sin_phi[i], 0.0, -1.0, 0.0, sin_phi[i], 0.0, -cos_phi,
+        );
+        let translation_prime = Vector3::new(x1[i], 0.0, x3[i]) * (d1 + d3);
+        push(rotation_prime, translation_prime, i);
+    }
+
+    decompositions
+}
+
+// Picks the decomposition that best agrees with the matches, the one that triangulates the most
+// of them in front of both cameras (the same cheirality check used for the essential matrix).
+pub fn select_decomposition(
+    decompositions: &[HomographyDecomposition],
+    key_points: &[(KeyPoint, KeyPoint)],
+) -> Option<HomographyDecomposition> {
+    decompositions
+        .iter()
+        .map(|decomposition| {
+            let hypothesis = phase_6::evaluate_pose(
+                &decomposition.rotation,
+                &decomposition.translation,
+                key_points,
+            );
+            (decomposition, hypothesis.num_good_points)
+        })
+        // of equally good ones, prefer the plane facing both cameras (n·p1 > 0 and (R·n)·p2 > 0)
+        .max_by_key(|(decomposition, num_good_points)| {
+            let normal_2 = decomposition.rotation * decomposition.normal;
+            let num_visible = key_points
+                .iter()
+                .filter(|(p1, p2)| {
+                    let p1 = Vector3::new(p1.x as f64, p1.y as f64, 1.0);
+                    let p2 = Vector3::new(p2.x as f64, p2.y as f64, 1.0);
+                    decomposition.normal.dot(&p1) > 0.0 && normal_2.dot(&p2) > 0.0
+                })
+                .count();
+            (*num_good_points, num_visible)
+        })
+        .map(|(decomposition, _)| *decomposition)
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::*;
+
+    // points on the plane n·X = d seen by two cameras
+    fn planar_scene(rng: &mut Rand, normal: &Vector3<f64>, distance: f64) -> Vec<Vector3<f64>> {
+        (0..40)
+            .map(|_| {
+                let x = rng.gen_range(-1.0..=1.0) as f64;
+                let y = rng.gen_range(-1.0..=1.0) as f64;
+                // solve for z so the point lies on the plane
+                let z = (distance - normal.x * x - normal.y * y) / normal.z;
+                Vector3::new(x, y, z)
+            })
+            .collect()
+    }
+
+    fn homography_from_plane(
+        rotation: &Matrix3<f64>,
+        translation: &Vector3<f64>,
+        normal: &Vector3<f64>,
+        distance: f64,
+    ) -> Matrix3<f64> {
+        rotation + translation * normal.transpose() / distance
+    }
+
+    #[test]
+    fn test_keypoints_to_homography() {
+        let mut rng = Rand::new_with_seed(21);
+        let normal = Vector3::new(0.1, -0.2, 1.0).normalize();
+        let points = planar_scene(&mut rng, &normal, 5.0);
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.5, 0.1, 0.2);
+        let matches = two_view_matches(&points, &rotation, &translation);
+
+        let homography = keypoints_to_homography(&matches);
+        assert_matrices_equal_up_to_scale(
+            &homography,
+            &homography_from_plane(&rotation, &translation, &normal, 5.0),
+            1e-4,
+        );
+        for (p1, p2) in matches.iter() {
+            assert!(symmetric_transfer_error(&homography, p1, p2) < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_decompose_homography() {
+        let normal = Vector3::new(0.2, 0.1, 1.0).normalize();
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.4, -0.3, 0.2);
+        let homography = homography_from_plane(&rotation, &translation, &normal, 3.0);
+
+        let decompositions = decompose_homography(&homography);
+        assert_eq!(decompositions.len(), 8);
+
+        // one of the decompositions is the motion we made
+        assert!(decompositions.iter().any(|decomposition| {
+            (decomposition.rotation - rotation).norm() < 1e-9
+                && (decomposition.translation - translation.normalize()).norm() < 1e-9
+                && (decomposition.normal - normal).norm() < 1e-9
+        }));
+
+        // and they all are rotations
+        for decomposition in decompositions.iter() {
+            assert!((decomposition.rotation.determinant() - 1.0).abs() < 1e-9);
+        }
+
+        // a pure rotation can't be decomposed
+        assert!(decompose_homography(&rotation).is_empty());
+    }
+
+    #[test]
+    fn test_estimate_homography_ransac() {
+        let mut rng = Rand::new_with_seed(2);
+        let normal = Vector3::new(0.0, 0.0, 1.0);
+        let points = planar_scene(&mut rng, &normal, 4.0);
+        let rotation = small_rotation();
+        let translation = Vector3::new(-0.3, 0.2, 0.1);
+        let mut matches = two_view_matches(&points, &rotation, &translation);
+
+        // some of the matches are wrong
+        for i in (0..matches.len()).step_by(5) {
+            matches[i].1.x += 0.3;
+        }
+
+        let options = RansacOptions::new(0.001, 100);
+        let result = estimate_homography_ransac(&matches, &options, &mut rng).unwrap();
+        let homography = result.model;
+        assert_matrices_equal_up_to_scale(
+            &homography,
+            &homography_from_plane(&rotation, &translation, &normal, 4.0),
+            1e-4,
+        );
+
+        let decomposition =
+            select_decomposition(&decompose_homography(&homography), &matches).unwrap();
+        assert!((decomposition.normal - normal).norm() < 1e-3);
+
+        // exactly the matches we broke are outliers
+        for (i, &inlier) in result.inliers.iter().enumerate() {
+            assert_eq!(inlier, i % 5 != 0);
+            assert_eq!(inlier, result.residuals[i] < 0.001);
+        }
+        assert_eq!(
+            result.num_inliers,
+            matches.len() - matches.len().div_ceil(5)
+        );
+
+        // four matches are enough for a homography, three aren't
+        let good: Vec<_> = matches.iter().skip(1).take(4).copied().collect();
+        let result = estimate_homography_ransac(&good, &options, &mut rng).unwrap();
+        assert_eq!(result.num_inliers, 4);
+        assert!(estimate_homography_ransac(&good[..3], &options, &mut rng).is_none());
+    }
+
+    #[test]
+    fn test_model_selection() {
+        let mut rng = Rand::new_with_seed(17);
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.5, 0.2, 0.1);
+        let sigma = 0.002;
+
+        // a planar scene is explained by a homography as well as by an essential matrix
+        let normal = Vector3::new(0.1, 0.1, 1.0).normalize();
+        let matches = two_view_matches(
+            &planar_scene(&mut rng, &normal, 4.0),
+            &rotation,
+            &translation,
+        );
+        let homography_score =
+            score_homography(&keypoints_to_homography(&matches), &matches, sigma);
+        let essential_score = phase_5::score_essential(
+            &essential_from_pose(&rotation, &translation),
+            &matches,
+            sigma,
+        );
+        assert!(prefer_homography(homography_score, essential_score));
+
+        // but a scene with depth is not explained by any homography
+        let matches = two_view_matches(&random_points(&mut rng, 40), &rotation, &translation);
+        let homography_score =
+            score_homography(&keypoints_to_homography(&matches), &matches, sigma);
+        let essential_score = phase_5::score_essential(
+            &essential_from_pose(&rotation, &translation),
+            &matches,
+            sigma,
+        );
+        assert!(!prefer_homography(homography_score, essential_score));
+    }
+}
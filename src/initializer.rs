@@ -0,0 +1,309 @@
+// Before a camera can be tracked against a map, there has to be a map. With a single camera it is
+// built from two frames: the motion between them is found from their matches, and the matches are
+// triangulated into the first 3D points.
+//
+// Not every pair of frames will do. If the camera has barely moved the rays to each point are
+// almost parallel, the depths are guesses and the pose that comes with them is too. So the first
+// frame is kept as a reference and every new frame is compared with it, until one has moved far
+// enough from it that the map would be a sound one.
+//
+// The distance between the two cameras can't be known, so the map's scale is fixed by choice:
+// the median depth of its points in the reference camera is 1.
+
+use nalgebra::Vector3;
+
+use crate::common::*;
+use crate::frame::Frame;
+use crate::slam::{Slam, SlamResult};
+
+// The first map, in the reference camera's coordinates.
+pub struct InitialMap {
+    pub reference: Frame,
+    pub current: Frame,
+    // maps points from the reference camera into the current one, with the translation in units
+    // of the map's scale
+    pub pose: Pose,
+    pub points: Vec<Vector3<f64>>,
+    // the keypoints each point was seen at in the reference and current frames, in pixels
+    pub observations: Vec<(KeyPoint, KeyPoint)>,
+    // the median parallax of the points in degrees
+    pub parallax: f64,
+}
+
+// What became of a frame passed to the initializer.
+pub enum Initialization {
+    // the frame was taken as the reference
+    Started,
+    // the camera hasn't moved far enough from the reference yet, with the parallax in degrees
+    NotEnoughParallax(f64),
+    // too few matches triangulated in front of both cameras for the pose to be trusted
+    TooFewPoints(usize),
+    // too few matches with the reference were left, so the frame became the new reference
+    Restarted,
+    Initialized(Box<InitialMap>),
+}
+
+pub struct Initializer {
+    reference: Option<Frame>,
+    configure: fn(&mut Slam),
+    // fixed camera intrinsics, overriding whatever the configuration sets
+    intrinsics: Option<CameraIntrinsics>,
+    min_matches: usize,
+    min_points: usize,
+    min_parallax: f64,
+}
+
+impl Initializer {
+    pub fn new() -> Self {
+        Initializer {
+            reference: None,
+            configure: |_| {},
+            intrinsics: None,
+            min_matches: 100,
+            min_points: 50,
+            min_parallax: 1.0,
+        }
+    }
+
+    // Applied to the `Slam` comparing each frame with the reference, to pass on its settings.
+    pub fn set_configuration(&mut self, configure: fn(&mut Slam)) {
+        self.configure = configure;
+    }
+
+    // The camera's intrinsics, when they are known. The map is built with these rather than any the
+    // configuration sets or self-calibration would find, so it agrees with whoever tracks in it.
+    pub fn set_intrinsics(&mut self, intrinsics: CameraIntrinsics) {
+        self.intrinsics = Some(intrinsics);
+    }
+
+    // Below this many matches with the reference the view has changed too much to keep waiting
+    // for it, and the initialization starts over from the new frame.
+    pub fn set_min_matches(&mut self, min_matches: usize) {
+        self.min_matches = min_matches;
+    }
+
+    // How many good 3D points the initial map needs.
+    pub fn set_min_points(&mut self, min_points: usize) {
+        self.min_points = min_points;
+    }
+
+    // The median parallax in degrees the points must be seen with.
+    pub fn set_min_parallax(&mut self, min_parallax: f64) {
+        self.min_parallax = min_parallax;
+    }
+
+    pub fn reset(&mut self) {
+        self.reference = None;
+    }
+
+    pub fn process_frame(&mut self, frame: Frame) -> Initialization {
+        let reference = match self.reference.take() {
+            Some(reference)
+                if reference.width == frame.width && reference.height == frame.height =>
+            {
+                reference
+            }
+            _ => {
+                self.reference = Some(frame);
+                return Initialization::Started;
+            }
+        };
+
+        let mut slam = Slam::new(&reference, &frame);
+        (self.configure)(&mut slam);
+        if let Some(intrinsics) = self.intrinsics {
+            slam.set_intrinsics(intrinsics);
+            slam.set_self_calibration(false);
+        }
+        let result = slam.calculate_pose();
+
+        match self.assess(&result) {
+            Some(initialization @ Initialization::Restarted) => {
+                self.reference = Some(frame);
+                initialization
+            }
+            Some(initialization) => {
+                self.reference = Some(reference);
+                initialization
+            }
+            None => match fix_scale(&result) {
+                Some((pose, points)) => Initialization::Initialized(Box::new(InitialMap {
+                    reference,
+                    current: frame,
+                    pose,
+                    points,
+                    observations: result.point_matches,
+                    parallax: result.parallax,
+                })),
+                // without points, or only ones at no depth, there is no scale to give the map
+                None => {
+                    self.reference = Some(reference);
+                    Initialization::TooFewPoints(result.points.len())
+                }
+            },
+        }
+    }
+
+    // Why the two frames can't start the map, or None if they can.
+    fn assess(&self, result: &SlamResult) -> Option<Initialization> {
+        if result.matches.len() < self.min_matches {
+            return Some(Initialization::Restarted);
+        }
+        if result.pose.is_none() || result.points.len() < self.min_points {
+            return Some(Initialization::TooFewPoints(result.points.len()));
+        }
+        if result.parallax < self.min_parallax {
+            return Some(Initialization::NotEnoughParallax(result.parallax));
+        }
+        None
+    }
+}
+
+impl Default for Initializer {
+    fn default() -> Self {
+        Initializer::new()
+    }
+}
+
+// Scales the pose and points so the median depth of the points is 1, or None if there is no
+// pose or no median depth to scale by.
+fn fix_scale(result: &SlamResult) -> Option<(Pose, Vec<Vector3<f64>>)> {
+    let (rotation, translation) = result.pose?;
+    let mut depths: Vec<f64> = result.points.iter().map(|p| p.position.z).collect();
+    depths.sort_by(f64::total_cmp);
+    let median = *depths.get(depths.len() / 2)?;
+    if !(median > 0.0 && median.is_finite()) {
+        return None;
+    }
+    let scale = 1.0 / median;
+
+    let points = result
+        .points
+        .iter()
+        .map(|point| point.position * scale)
+        .collect();
+    Some(((rotation, translation * scale), points))
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand::Rand;
+    use crate::synthetic::*;
+    use crate::triangulation::{self, TriangulationMethod};
+
+    // What the two-view pipeline would find for a synthetic scene seen from two cameras.
+    fn synthetic_result(translation: Vector3<f64>, num_points: usize) -> SlamResult {
+        let mut rng = Rand::new_with_seed(16);
+        let rotation = small_rotation();
+        let translation = translation.normalize();
+        let matches = two_view_matches(
+            &random_points(&mut rng, num_points),
+            &rotation,
+            &translation,
+        );
+        let points: Vec<_> = triangulation::triangulate_matches(
+            TriangulationMethod::Optimal,
+            &rotation,
+            &translation,
+            &matches,
+        )
+        .into_iter()
+        .flatten()
+        .collect();
+        let mut parallaxes: Vec<f64> = points.iter().map(|p| p.parallax).collect();
+        parallaxes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        SlamResult {
+            pose: Some((rotation, translation)),
+            inliers: vec![true; matches.len()],
+            residuals: vec![0.0; matches.len()],
+            num_inliers: matches.len(),
+            num_good_points: points.len(),
+            parallax: parallaxes[parallaxes.len() / 2],
+            points,
+            point_matches: matches.clone(),
+            matches,
+            keypoints_a: vec![],
+            keypoints_b: vec![],
+            blurred_image_a: vec![],
+            intrinsics: CameraIntrinsics::new(1.0, 1.0, 0.0, 0.0, 0.0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_assess() {
+        let initializer = Initializer::new();
+
+        let good = synthetic_result(Vector3::new(0.6, -0.3, 0.2), 200);
+        assert!(initializer.assess(&good).is_none());
+
+        let few_matches = synthetic_result(Vector3::new(0.6, -0.3, 0.2), 60);
+        assert!(matches!(
+            initializer.assess(&few_matches),
+            Some(Initialization::Restarted)
+        ));
+
+        let mut few_points = synthetic_result(Vector3::new(0.6, -0.3, 0.2), 200);
+        few_points.points.truncate(10);
+        assert!(matches!(
+            initializer.assess(&few_points),
+            Some(Initialization::TooFewPoints(10))
+        ));
+
+        // the same scene seen from cameras a hundredth of the distance apart
+        let mut small_baseline = synthetic_result(Vector3::new(0.6, -0.3, 0.2), 200);
+        small_baseline.parallax /= 100.0;
+        assert!(matches!(
+            initializer.assess(&small_baseline),
+            Some(Initialization::NotEnoughParallax(_))
+        ));
+    }
+
+    #[test]
+    fn test_fix_scale() {
+        let result = synthetic_result(Vector3::new(0.6, -0.3, 0.2), 101);
+        let (pose, points) = fix_scale(&result).unwrap();
+
+        let mut depths: Vec<f64> = points.iter().map(|p| p.z).collect();
+        depths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert!((depths[50] - 1.0).abs() < 1e-9);
+
+        // the points still project where they were seen
+        for (point, (_, p2)) in points.iter().zip(&result.point_matches) {
+            let projected = project(&(pose.0 * point + pose.1));
+            assert!((projected.x - p2.x).abs() < 1e-4);
+            assert!((projected.y - p2.y).abs() < 1e-4);
+        }
+
+        // nothing to take the median of
+        let mut empty = synthetic_result(Vector3::new(0.6, -0.3, 0.2), 101);
+        empty.points.clear();
+        assert!(fix_scale(&empty).is_none());
+    }
+
+    #[test]
+    fn test_first_frame_is_the_reference() {
+        let data = vec![128; 32 * 24 * 4];
+        let image = Image {
+            data: &data,
+            width: 32,
+            height: 24,
+        };
+
+        let mut initializer = Initializer::new();
+        assert!(matches!(
+            initializer.process_frame(Frame::new(image)),
+            Initialization::Started
+        ));
+        // nothing to match in a flat image, so the reference is given up on
+        assert!(matches!(
+            initializer.process_frame(Frame::new(image)),
+            Initialization::Restarted
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+// Which tracked frames should become keyframes. Too few and the map has holes where the camera
+// wandered off, too many and the map fills up with near copies of the same view that cost time to
+// optimize and add nothing. A new keyframe is wanted when the view has changed enough since the
+// last one: the camera moved or turned, or many of the points the last keyframe sees are no
+// longer tracked.
+
+use crate::common::*;
+use crate::geometry::SE3;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct KeyFrameOptions {
+    // never insert keyframes closer together than this many frames
+    pub min_frames: usize,
+    // always insert one after this many frames
+    pub max_frames: usize,
+    // insert one when fewer than this fraction of the reference keyframe's points are tracked
+    pub min_tracked_ratio: f64,
+    // insert one when the camera moved further than this (in the map's units) from the reference
+    // keyframe
+    pub max_translation: f64,
+    // or turned more than this many degrees
+    pub max_rotation: f64,
+}
+
+impl KeyFrameOptions {
+    pub fn new() -> Self {
+        KeyFrameOptions {
+            min_frames: 2,
+            max_frames: 30,
+            min_tracked_ratio: 0.9,
+            // the map is scaled so its points are about 1 away
+            max_translation: 0.1,
+            max_rotation: 10.0,
+        }
+    }
+}
+
+impl Default for KeyFrameOptions {
+    fn default() -> Self {
+        KeyFrameOptions::new()
+    }
+}
+
+// Whether a frame became a keyframe, and why.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum KeyFrameDecision {
+    // the frame wasn't located, so it can't be added to the map
+    NotTracked,
+    TooSoon,
+    // the view hasn't changed enough since the reference keyframe
+    NotNeeded,
+    // one of the frames the first map was built from
+    Initialization,
+    MaxFrames,
+    FewTrackedPoints,
+    Translation,
+    Rotation,
+}
+
+impl KeyFrameDecision {
+    pub fn is_keyframe(&self) -> bool {
+        !matches!(
+            self,
+            KeyFrameDecision::NotTracked | KeyFrameDecision::TooSoon | KeyFrameDecision::NotNeeded
+        )
+    }
+}
+
+// Decides on a tracked frame at `pose` that matched `num_tracked` map points, with the reference
+// keyframe at `reference_pose` seeing `num_reference_points`.
+pub fn decide(
+    options: &KeyFrameOptions,
+    frames_since_keyframe: usize,
+    pose: &Pose,
+    num_tracked: usize,
+    reference_pose: &Pose,
+    num_reference_points: usize,
+) -> KeyFrameDecision {
+    if frames_since_keyframe < options.min_frames {
+        return KeyFrameDecision::TooSoon;
+    }
+    if frames_since_keyframe >= options.max_frames {
+        return KeyFrameDecision::MaxFrames;
+    }
+    if (num_tracked as f64) < options.min_tracked_ratio * num_reference_points as f64 {
+        return KeyFrameDecision::FewTrackedPoints;
+    }
+
+    // the motion from the reference keyframe to the frame
+    let motion = SE3::from_pose(pose) * SE3::from_pose(reference_pose).inverse();
+    let translation = motion.inverse().translation.norm();
+    if translation > options.max_translation {
+        return KeyFrameDecision::Translation;
+    }
+    let rotation = motion.rotation.log().norm().to_degrees();
+    if rotation > options.max_rotation {
+        return KeyFrameDecision::Rotation;
+    }
+    KeyFrameDecision::NotNeeded
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Matrix3, Rotation3, Vector3};
+
+    #[test]
+    fn test_decide() {
+        let options = KeyFrameOptions::new();
+        let origin = (Matrix3::identity(), Vector3::zeros());
+        let nearby = (Matrix3::identity(), Vector3::new(0.05, 0.0, 0.0));
+
+        assert_eq!(
+            decide(&options, 1, &nearby, 100, &origin, 100),
+            KeyFrameDecision::TooSoon
+        );
+        assert_eq!(
+            decide(&options, 5, &nearby, 100, &origin, 100),
+            KeyFrameDecision::NotNeeded
+        );
+        assert_eq!(
+            decide(&options, 30, &nearby, 100, &origin, 100),
+            KeyFrameDecision::MaxFrames
+        );
+        assert_eq!(
+            decide(&options, 5, &nearby, 80, &origin, 100),
+            KeyFrameDecision::FewTrackedPoints
+        );
+
+        // moved 0.2 along x, seen from a camera that is turned, so t alone isn't the distance
+        let rotation = Rotation3::from_euler_angles(0.0, 0.05, 0.0).into_inner();
+        let moved = (rotation, -rotation * Vector3::new(0.2, 0.0, 0.0));
+        assert_eq!(
+            decide(&options, 5, &moved, 100, &origin, 100),
+            KeyFrameDecision::Translation
+        );
+
+        let turned = (
+            Rotation3::from_euler_angles(0.0, 0.3, 0.0).into_inner(),
+            Vector3::zeros(),
+        );
+        assert_eq!(
+            decide(&options, 5, &turned, 100, &origin, 100),
+            KeyFrameDecision::Rotation
+        );
+
+        assert!(KeyFrameDecision::Rotation.is_keyframe());
+        assert!(!KeyFrameDecision::NotNeeded.is_keyframe());
+    }
+}
@@ -1,17 +1,38 @@
-use common::KeyPoint;
+#![allow(clippy::missing_safety_doc)]
+use common::{CameraIntrinsics, KeyPoint};
 use std::alloc::{alloc, Layout};
+use std::ptr::null_mut;
 use std::slice;
-mod common;
+pub mod bundle_adjustment;
+pub mod common;
+mod five_point;
+pub mod frame;
+pub mod geometry;
+mod homography;
+pub mod initializer;
+pub mod keyframe_policy;
+pub mod local_mapping;
+pub mod map;
+pub mod odometry;
 mod phase_1;
 mod phase_2;
 mod phase_3;
 mod phase_4;
 mod phase_5;
 mod phase_6;
-mod rand;
+mod phase_7;
+pub mod pnp;
+mod polynomial;
+pub mod rand;
+pub mod ransac;
 mod slam;
+#[cfg(test)]
+mod synthetic;
+pub mod tracking;
+mod triangulation;
+pub mod vocabulary;
 
-static mut VEC_PTR_SLOT_0: *mut u8 = 0 as *mut u8;
+static mut VEC_PTR_SLOT_0: *mut u8 = null_mut();
 static mut VEC_LEN_SLOT_0: usize = 0;
 
 #[no_mangle]
@@ -19,13 +40,14 @@ pub unsafe extern "C" fn allocate_slot_0(size: usize) -> *mut u8 {
     let layout = Layout::array::<u8>(size).unwrap();
     let ptr = alloc(layout);
 
-    VEC_PTR_SLOT_0 = ptr as *mut u8;
+    VEC_PTR_SLOT_0 = ptr;
     VEC_LEN_SLOT_0 = size;
+    FRAMES[0] = None;
 
     VEC_PTR_SLOT_0
 }
 
-static mut VEC_PTR_SLOT_1: *mut u8 = 0 as *mut u8;
+static mut VEC_PTR_SLOT_1: *mut u8 = null_mut();
 static mut VEC_LEN_SLOT_1: usize = 0;
 
 #[no_mangle]
@@ -33,161 +55,714 @@ pub unsafe extern "C" fn allocate_slot_1(size: usize) -> *mut u8 {
     let layout = Layout::array::<u8>(size).unwrap();
     let ptr = alloc(layout);
 
-    VEC_PTR_SLOT_1 = ptr as *mut u8;
+    VEC_PTR_SLOT_1 = ptr;
     VEC_LEN_SLOT_1 = size;
+    FRAMES[1] = None;
 
     VEC_PTR_SLOT_1
 }
 
-static mut VEC_KEYPOINTS_SLOT_0_PTR: *mut KeyPoint = 0 as *mut KeyPoint;
-static mut VEC_KEYPOINTS_SLOT_0_LEN: usize = 0;
+// The features extracted from the image in each slot, so an image is only processed once even
+// though it is compared twice, first with the frame before it and then with the one after it.
+static mut FRAMES: [Option<frame::Frame>; 2] = [None, None];
 
-static mut VEC_KEYPOINTS_SLOT_1_PTR: *mut KeyPoint = 0 as *mut KeyPoint;
-static mut VEC_KEYPOINTS_SLOT_1_LEN: usize = 0;
+// What `store_result` found, refilled on every frame rather than allocated anew. A frame that
+// couldn't be compared leaves some of them empty, which a fresh allocation can't be.
+static mut KEYPOINTS_SLOT_0: Vec<KeyPoint> = Vec::new();
 
-static mut VEC_KEYPOINTS_MATCHES_PTR: *mut KeyPoint = 0 as *mut KeyPoint;
-static mut VEC_KEYPOINTS_MATCHES_LEN: usize = 0;
+static mut KEYPOINTS_SLOT_1: Vec<KeyPoint> = Vec::new();
+
+static mut KEYPOINTS_MATCHES: Vec<KeyPoint> = Vec::new();
+
+static mut GUIDED_MATCHING: bool = false;
 
 #[no_mangle]
-pub unsafe fn calculate(width: usize, height: usize, slot: usize) -> usize {
-    let slice_0 = slice::from_raw_parts_mut(VEC_PTR_SLOT_0, VEC_LEN_SLOT_0);
-    let slice_1 = slice::from_raw_parts_mut(VEC_PTR_SLOT_1, VEC_LEN_SLOT_1);
+pub unsafe fn set_guided_matching(enabled: usize) {
+    GUIDED_MATCHING = enabled != 0;
+}
 
-    let mut image_a = common::Image {
-        data: slice_0,
-        width,
-        height,
+static mut MODEL_SELECTION: bool = true;
+
+// Whether a homography is considered alongside the essential matrix, for planar scenes.
+#[no_mangle]
+pub unsafe fn set_model_selection(enabled: usize) {
+    MODEL_SELECTION = enabled != 0;
+}
+
+static mut ESSENTIAL_SOLVER: phase_5::MinimalSolver = phase_5::MinimalSolver::FivePoint;
+
+// Pick the minimal solver RANSAC uses by the number of points it needs, 5 or 8.
+#[no_mangle]
+pub unsafe fn set_essential_solver(num_points: usize) {
+    ESSENTIAL_SOLVER = if num_points == 8 {
+        phase_5::MinimalSolver::EightPoint
+    } else {
+        phase_5::MinimalSolver::FivePoint
     };
+}
 
-    let slice = slice::from_raw_parts_mut(VEC_PTR_SLOT_1, VEC_LEN_SLOT_1);
-    core::mem::forget(slice);
+static mut EPIPOLAR_RESIDUAL: phase_5::EpipolarResidual = phase_5::EpipolarResidual::Sampson;
 
-    let mut image_b = common::Image {
-        data: slice_1,
-        width,
-        height,
+// Pick how matches are measured against the essential matrix: 0 for the Sampson error, 1 for the
+// symmetric epipolar distance and 2 for the reprojection error.
+#[no_mangle]
+pub unsafe fn set_epipolar_residual(residual: usize) {
+    EPIPOLAR_RESIDUAL = match residual {
+        1 => phase_5::EpipolarResidual::Symmetric,
+        2 => phase_5::EpipolarResidual::Reprojection,
+        _ => phase_5::EpipolarResidual::Sampson,
     };
+}
 
-    if slot == 1 {
-        let image = image_a;
-        image_a = image_b;
-        image_b = image;
-    }
+static mut POSE_REFINEMENT: Option<phase_7::Loss> = Some(phase_7::Loss::Huber(1.0));
 
-    let mut slam = slam::Slam::new(image_a, image_b);
+// Refine the pose after RANSAC: 0 turns it off, 1 uses a squared loss, and 2 and 3 use the robust
+// Huber and Cauchy losses, which down-weight residuals bigger than `scale` pixels.
+#[no_mangle]
+pub unsafe fn set_pose_refinement(mode: usize, scale: f64) {
+    POSE_REFINEMENT = match mode {
+        0 => None,
+        1 => Some(phase_7::Loss::Squared),
+        2 => Some(phase_7::Loss::Huber(scale)),
+        _ => Some(phase_7::Loss::Cauchy(scale)),
+    };
+}
 
-    let (result, matched_keypoints, keypoints_and_descriptors_a, keypoints_and_descriptors_b) =
-        slam.calculate_pose();
+static mut TRIANGULATION_METHOD: triangulation::TriangulationMethod =
+    triangulation::TriangulationMethod::Optimal;
 
-    let layout = Layout::array::<KeyPoint>(keypoints_and_descriptors_a.0.len()).unwrap();
-    let ptr = alloc(layout) as *mut KeyPoint;
+// Pick how the 3D points are triangulated: 0 for the linear method, 1 for the midpoint method and
+// 2 for the optimal method.
+#[no_mangle]
+pub unsafe fn set_triangulation_method(method: usize) {
+    TRIANGULATION_METHOD = match method {
+        0 => triangulation::TriangulationMethod::Linear,
+        1 => triangulation::TriangulationMethod::Midpoint,
+        _ => triangulation::TriangulationMethod::Optimal,
+    };
+}
 
-    VEC_KEYPOINTS_SLOT_0_PTR = ptr;
-    VEC_KEYPOINTS_SLOT_0_LEN = keypoints_and_descriptors_a.0.len();
+static mut INTRINSICS: Option<CameraIntrinsics> = None;
 
-    // copy data to the allocated memory
-    ptr.copy_from_nonoverlapping(
-        keypoints_and_descriptors_a.0.as_ptr(),
-        keypoints_and_descriptors_a.0.len(),
-    );
+// The camera's intrinsics in pixels, ignored unless both focal lengths are positive.
+#[no_mangle]
+pub unsafe fn set_intrinsics(fx: f64, fy: f64, cx: f64, cy: f64, skew: f64) {
+    if let Some(intrinsics) = CameraIntrinsics::new(fx, fy, cx, cy, skew) {
+        INTRINSICS = Some(intrinsics);
+    }
+}
 
-    let layout = Layout::array::<KeyPoint>(keypoints_and_descriptors_b.0.len()).unwrap();
-    let ptr = alloc(layout) as *mut KeyPoint;
+// For when all we know is the device's horizontal field of view in degrees, which must be between
+// 0 and 180 or it is ignored.
+#[no_mangle]
+pub unsafe fn set_intrinsics_from_fov(horizontal_fov_degrees: f64, width: usize, height: usize) {
+    if let Some(intrinsics) =
+        CameraIntrinsics::from_field_of_view(horizontal_fov_degrees.to_radians(), width, height)
+    {
+        INTRINSICS = Some(intrinsics);
+    }
+}
 
-    VEC_KEYPOINTS_SLOT_1_PTR = ptr;
-    VEC_KEYPOINTS_SLOT_1_LEN = keypoints_and_descriptors_b.0.len();
+static mut RANSAC_OPTIONS: Option<ransac::RansacOptions> = None;
 
-    // copy data to the allocated memory
-    ptr.copy_from_nonoverlapping(
-        keypoints_and_descriptors_b.0.as_ptr(),
-        keypoints_and_descriptors_b.0.len(),
-    );
+// Tune the robust estimation: the inlier threshold in pixels, how many iterations to run at most,
+// and which of the RANSAC improvements to use (MSAC scoring, PROSAC sampling, local optimization).
+#[no_mangle]
+pub unsafe fn set_ransac(
+    threshold: f64,
+    max_iterations: usize,
+    msac: usize,
+    prosac: usize,
+    local_optimization: usize,
+) {
+    let mut options = ransac::RansacOptions::new(threshold, max_iterations);
+    if msac != 0 {
+        options.scoring = ransac::Scoring::Msac;
+    }
+    if prosac != 0 {
+        options.sampling = ransac::Sampling::Prosac;
+    }
+    options.local_optimization = local_optimization != 0;
+    // stop early once we are confident enough, but never before a few iterations
+    options.confidence = 0.999;
+    options.min_iterations = max_iterations.min(20);
+    RANSAC_OPTIONS = Some(options);
+}
 
-    let grey = keypoints_and_descriptors_a.2;
-    let mut rgb_grey = vec![0; grey.len() * 4];
+static mut SELF_CALIBRATION: bool = false;
 
-    grey.iter().enumerate().for_each(|(i, &v)| {
-        rgb_grey[i * 4] = v;
-        rgb_grey[i * 4 + 1] = v;
-        rgb_grey[i * 4 + 2] = v;
-        rgb_grey[i * 4 + 3] = 255;
-    });
+// The estimates self-calibration found with `calculate`, `process_frame` keeps its own.
+static mut CALIBRATION: Option<odometry::CalibrationEstimates> = None;
 
-    let layout = Layout::array::<u8>(rgb_grey.len()).unwrap();
-    let ptr = alloc(layout) as *mut u8;
+// Estimate the camera's focal length from the video itself, when no intrinsics were set. Every
+// pair of frames is calibrated on its own until there are enough estimates, then their median is
+// kept as the intrinsics for the following frames.
+#[no_mangle]
+pub unsafe fn set_self_calibration(enabled: usize) {
+    SELF_CALIBRATION = enabled != 0;
+}
 
-    VEC_GRAYSCALE_PTR = ptr;
-    VEC_GRAYSCALE_LEN = rgb_grey.len();
+// The intrinsics that were set, or else the ones self-calibration settled on.
+unsafe fn known_intrinsics() -> Option<CameraIntrinsics> {
+    INTRINSICS
+        .or_else(|| {
+            (*std::ptr::addr_of!(ODOMETRY))
+                .as_ref()
+                .and_then(|odometry| odometry.estimated_intrinsics())
+        })
+        .or_else(|| {
+            (*std::ptr::addr_of!(CALIBRATION))
+                .as_ref()
+                .and_then(|calibration| calibration.locked())
+        })
+}
 
-    // copy data to the allocated memory
-    ptr.copy_from_nonoverlapping(rgb_grey.as_ptr(), rgb_grey.len());
+#[no_mangle]
+pub unsafe fn get_focal_length() -> f64 {
+    known_intrinsics()
+        .map(|intrinsics| intrinsics.fx)
+        .unwrap_or(0.0)
+}
 
-    let mut matched_keypoints_flattened: Vec<KeyPoint> =
-        Vec::with_capacity(matched_keypoints.len() * 2);
+#[no_mangle]
+pub unsafe fn calculate(width: usize, height: usize, slot: usize) -> usize {
+    let slice_0 = slice::from_raw_parts(VEC_PTR_SLOT_0, VEC_LEN_SLOT_0);
+    let slice_1 = slice::from_raw_parts(VEC_PTR_SLOT_1, VEC_LEN_SLOT_1);
+
+    let images = [
+        common::Image {
+            data: slice_0,
+            width,
+            height,
+        },
+        common::Image {
+            data: slice_1,
+            width,
+            height,
+        },
+    ];
+
+    // `slot` is the slot that gets written next, so the other one holds the image that was just
+    // written and needs its features extracted. The features of `slot` are still cached from the
+    // last call, unless there weren't any yet or they were for another image size.
+    let frames = &mut *std::ptr::addr_of_mut!(FRAMES);
+    let newest = if slot == 1 { 0 } else { 1 };
+    let oldest = 1 - newest;
+    frames[newest] = Some(frame::Frame::new(images[newest]));
+    let cached = frames[oldest]
+        .as_ref()
+        .is_some_and(|frame| frame.width == width && frame.height == height);
+    if !cached {
+        frames[oldest] = Some(frame::Frame::new(images[oldest]));
+    }
+    let frame_a = frames[oldest].as_ref().unwrap();
+    let frame_b = frames[newest].as_ref().unwrap();
 
-    matched_keypoints.into_iter().for_each(|(a, b)| {
-        matched_keypoints_flattened.push(a);
-        matched_keypoints_flattened.push(b);
+    let mut slam = slam::Slam::new(frame_a, frame_b);
+    configure_slam(&mut slam);
+    let calibration = (*std::ptr::addr_of_mut!(CALIBRATION)).get_or_insert_with(|| {
+        odometry::CalibrationEstimates::new(odometry::CALIBRATION_ESTIMATES)
     });
+    if let Some(intrinsics) = calibration.locked() {
+        slam.set_intrinsics(intrinsics);
+        slam.set_self_calibration(false);
+    }
+
+    let result = slam.calculate_pose();
 
-    let layout = Layout::array::<KeyPoint>(matched_keypoints_flattened.len()).unwrap();
-    let ptr = alloc(layout) as *mut KeyPoint;
+    if let Some(intrinsics) = slam.estimated_intrinsics() {
+        calibration.add(intrinsics);
+    }
 
-    VEC_KEYPOINTS_MATCHES_PTR = ptr;
-    VEC_KEYPOINTS_MATCHES_LEN = matched_keypoints_flattened.len();
+    store_result(&result);
 
-    // copy data to the allocated memory
-    ptr.copy_from_nonoverlapping(
-        matched_keypoints_flattened.as_ptr(),
-        matched_keypoints_flattened.len(),
-    );
+    if let Some(pose) = result.pose {
+        POSE = pose_to_array(&pose);
+    }
 
-    if let Some(r) = result {
+    if result.pose.is_some() {
         1
     } else {
         0
     }
 }
 
-static mut VEC_GRAYSCALE_PTR: *mut u8 = 0 as *mut u8;
-static mut VEC_GRAYSCALE_LEN: usize = 0;
+// Passes the settings made through the exports above on to a `Slam`.
+fn configure_slam(slam: &mut slam::Slam) {
+    unsafe {
+        slam.set_guided_matching(GUIDED_MATCHING);
+        slam.set_essential_solver(ESSENTIAL_SOLVER);
+        slam.set_epipolar_residual(EPIPOLAR_RESIDUAL);
+        let pose_refinement = POSE_REFINEMENT;
+        slam.set_pose_refinement(pose_refinement.is_some());
+        if let Some(loss) = pose_refinement {
+            slam.set_pose_refinement_loss(loss);
+        }
+        slam.set_triangulation_method(TRIANGULATION_METHOD);
+        slam.set_model_selection(MODEL_SELECTION);
+        if let Some(options) = RANSAC_OPTIONS {
+            slam.set_ransac_options(options);
+        }
+        if let Some(intrinsics) = INTRINSICS {
+            slam.set_intrinsics(intrinsics);
+        } else if SELF_CALIBRATION {
+            slam.set_self_calibration(true);
+        }
+    }
+}
+
+// Copies what was found into the buffers the getters below hand out.
+unsafe fn store_result(result: &slam::SlamResult) {
+    let keypoints_slot_0 = &mut *std::ptr::addr_of_mut!(KEYPOINTS_SLOT_0);
+    keypoints_slot_0.clear();
+    keypoints_slot_0.extend_from_slice(&result.keypoints_a);
+
+    let keypoints_slot_1 = &mut *std::ptr::addr_of_mut!(KEYPOINTS_SLOT_1);
+    keypoints_slot_1.clear();
+    keypoints_slot_1.extend_from_slice(&result.keypoints_b);
+
+    // four bytes per pixel, the grey value three times and an opaque alpha
+    let grayscale = &mut *std::ptr::addr_of_mut!(GRAYSCALE);
+    grayscale.clear();
+    grayscale.extend(result.blurred_image_a.iter().flat_map(|&v| [v, v, v, 255]));
+
+    // the keypoints of each match one after the other
+    let keypoints_matches = &mut *std::ptr::addr_of_mut!(KEYPOINTS_MATCHES);
+    keypoints_matches.clear();
+    keypoints_matches.extend(result.matches.iter().flat_map(|&(a, b)| [a, b]));
+
+    // one byte per match, 1 for inliers and 0 for outliers
+    let inliers = &mut *std::ptr::addr_of_mut!(INLIERS);
+    inliers.clear();
+    inliers.extend(result.inliers.iter().map(|&inlier| inlier as u8));
+
+    // residuals in pixels, as f32 like the keypoints
+    let residuals = &mut *std::ptr::addr_of_mut!(RESIDUALS);
+    residuals.clear();
+    residuals.extend(result.residuals.iter().map(|&residual| residual as f32));
+
+    // five floats per point: x, y, z, the reprojection error in pixels and the parallax in degrees
+    let pixels_per_unit = 1.0 / result.intrinsics.pixels_to_normalized(1.0);
+    let points = &mut *std::ptr::addr_of_mut!(POINTS);
+    points.clear();
+    points.extend(result.points.iter().flat_map(|point| {
+        [
+            point.position.x as f32,
+            point.position.y as f32,
+            point.position.z as f32,
+            (point.reprojection_error * pixels_per_unit) as f32,
+            point.parallax as f32,
+        ]
+    }));
+
+    NUM_INLIERS = result.num_inliers;
+    NUM_GOOD_POINTS = result.num_good_points;
+    PARALLAX = result.parallax;
+}
+
+// A result with the frame's blurred image and keypoints and nothing else, for when it couldn't be
+// compared with another frame.
+unsafe fn unmatched_result(frame: &frame::Frame) -> slam::SlamResult {
+    slam::SlamResult {
+        pose: None,
+        matches: vec![],
+        inliers: vec![],
+        residuals: vec![],
+        num_inliers: 0,
+        num_good_points: 0,
+        parallax: 0.0,
+        points: vec![],
+        point_matches: vec![],
+        keypoints_a: frame.keypoints.clone(),
+        keypoints_b: vec![],
+        blurred_image_a: frame.blurred_image.clone(),
+        intrinsics: known_intrinsics()
+            .unwrap_or_else(|| CameraIntrinsics::typical_webcam(frame.width, frame.height)),
+    }
+}
+
+static mut INLIERS: Vec<u8> = Vec::new();
+
+static mut RESIDUALS: Vec<f32> = Vec::new();
+
+static mut POINTS: Vec<f32> = Vec::new();
+
+static mut NUM_INLIERS: usize = 0;
+static mut NUM_GOOD_POINTS: usize = 0;
+static mut PARALLAX: f64 = 0.0;
+
+static mut GRAYSCALE: Vec<u8> = Vec::new();
 
 #[no_mangle]
 pub unsafe fn get_grayscale() -> *mut u8 {
-    VEC_GRAYSCALE_PTR
+    (*std::ptr::addr_of_mut!(GRAYSCALE)).as_mut_ptr()
 }
 
 #[no_mangle]
 pub unsafe fn get_grayscale_len() -> usize {
-    VEC_GRAYSCALE_LEN
+    (*std::ptr::addr_of!(GRAYSCALE)).len()
 }
 
 #[no_mangle]
 pub unsafe fn get_keypoints_slot_0() -> *mut KeyPoint {
-    VEC_KEYPOINTS_SLOT_0_PTR
+    (*std::ptr::addr_of_mut!(KEYPOINTS_SLOT_0)).as_mut_ptr()
 }
 
 #[no_mangle]
 pub unsafe fn get_keypoints_slot_0_len() -> usize {
-    VEC_KEYPOINTS_SLOT_0_LEN
+    (*std::ptr::addr_of!(KEYPOINTS_SLOT_0)).len()
 }
 
 #[no_mangle]
 pub unsafe fn get_keypoints_slot_1() -> *mut KeyPoint {
-    VEC_KEYPOINTS_SLOT_1_PTR
+    (*std::ptr::addr_of_mut!(KEYPOINTS_SLOT_1)).as_mut_ptr()
 }
 
 #[no_mangle]
 pub unsafe fn get_keypoints_slot_1_len() -> usize {
-    VEC_KEYPOINTS_SLOT_1_LEN
+    (*std::ptr::addr_of!(KEYPOINTS_SLOT_1)).len()
 }
 
 #[no_mangle]
 pub unsafe fn get_keypoints_matches() -> *mut KeyPoint {
-    VEC_KEYPOINTS_MATCHES_PTR
+    (*std::ptr::addr_of_mut!(KEYPOINTS_MATCHES)).as_mut_ptr()
 }
 
 #[no_mangle]
 pub unsafe fn get_keypoints_matches_len() -> usize {
-    VEC_KEYPOINTS_MATCHES_LEN
+    (*std::ptr::addr_of!(KEYPOINTS_MATCHES)).len()
+}
+
+// The inlier mask lines up with the matches, one entry per pair of keypoints.
+#[no_mangle]
+pub unsafe fn get_inliers() -> *mut u8 {
+    (*std::ptr::addr_of_mut!(INLIERS)).as_mut_ptr()
+}
+
+#[no_mangle]
+pub unsafe fn get_inliers_len() -> usize {
+    (*std::ptr::addr_of!(INLIERS)).len()
+}
+
+#[no_mangle]
+pub unsafe fn get_residuals() -> *mut f32 {
+    (*std::ptr::addr_of_mut!(RESIDUALS)).as_mut_ptr()
+}
+
+#[no_mangle]
+pub unsafe fn get_residuals_len() -> usize {
+    (*std::ptr::addr_of!(RESIDUALS)).len()
+}
+
+#[no_mangle]
+pub unsafe fn get_num_inliers() -> usize {
+    NUM_INLIERS
+}
+
+// How many inliers ended up in front of both cameras with the pose we picked.
+#[no_mangle]
+pub unsafe fn get_num_good_points() -> usize {
+    NUM_GOOD_POINTS
+}
+
+// The median parallax of the inliers in degrees, the pose is only reliable when it is a degree or
+// more.
+#[no_mangle]
+pub unsafe fn get_parallax() -> f64 {
+    PARALLAX
+}
+
+// The triangulated points, five floats each, see `calculate`.
+#[no_mangle]
+pub unsafe fn get_points() -> *mut f32 {
+    (*std::ptr::addr_of_mut!(POINTS)).as_mut_ptr()
+}
+
+#[no_mangle]
+pub unsafe fn get_points_len() -> usize {
+    (*std::ptr::addr_of!(POINTS)).len() / 5
+}
+
+// The rotation (row by row) followed by the translation of the last pose found by `calculate` or
+// `solve_pnp`.
+static mut POSE: [f64; 12] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0];
+
+fn pose_to_array((rotation, translation): &common::Pose) -> [f64; 12] {
+    let mut array = [0.0; 12];
+    for row in 0..3 {
+        for col in 0..3 {
+            array[row * 3 + col] = rotation[(row, col)];
+        }
+        array[9 + row] = translation[row];
+    }
+    array
+}
+
+#[no_mangle]
+pub unsafe fn get_pose() -> *const f64 {
+    std::ptr::addr_of!(POSE) as *const f64
+}
+
+static mut CORRESPONDENCES: Vec<f32> = Vec::new();
+
+// Room for `count` 3D to 2D correspondences of five floats each: the point's x, y and z followed
+// by the pixel it was seen at. The same buffer is resized on every call, so only the pointer
+// returned by the latest call is valid.
+#[no_mangle]
+pub unsafe fn allocate_correspondences(count: usize) -> *mut f32 {
+    let correspondences = &mut *std::ptr::addr_of_mut!(CORRESPONDENCES);
+    correspondences.resize(count * 5, 0.0);
+    correspondences.as_mut_ptr()
+}
+
+// Finds the camera pose from the correspondences written to `allocate_correspondences` using the
+// intrinsics set with `set_intrinsics`, and returns how many of them are inliers. The pose can be
+// read with `get_pose`. The minimal solver is picked by the number of points it needs, 3 for P3P or
+// 6 for EPnP, and the inlier threshold is in pixels.
+#[no_mangle]
+pub unsafe fn solve_pnp(count: usize, threshold: f64, num_points: usize) -> usize {
+    let intrinsics = match INTRINSICS {
+        Some(intrinsics) => intrinsics,
+        None => return 0,
+    };
+    let buffer = &*std::ptr::addr_of!(CORRESPONDENCES);
+    let data = match buffer.get(..count * 5) {
+        Some(data) => data,
+        None => return 0,
+    };
+    let correspondences: Vec<_> = data
+        .chunks(5)
+        .map(|c| {
+            let point = nalgebra::Vector3::new(c[0] as f64, c[1] as f64, c[2] as f64);
+            let key_point = KeyPoint {
+                x: c[3],
+                y: c[4],
+                orientation: 0.0,
+            };
+            (point, key_point)
+        })
+        .collect();
+
+    let options = ransac::RansacOptions {
+        scoring: ransac::Scoring::Msac,
+        local_optimization: true,
+        confidence: 0.999,
+        min_iterations: 20,
+        ..ransac::RansacOptions::new(threshold, 1000)
+    };
+    let solver = if num_points == 6 {
+        pnp::PnpSolver::EPnP
+    } else {
+        pnp::PnpSolver::P3P
+    };
+    let mut random = rand::Rand::new_with_seed(2523523);
+    match pnp::estimate_pose_pnp(&correspondences, &intrinsics, &options, solver, &mut random) {
+        Some(result) => {
+            POSE = pose_to_array(&result.model);
+            result.num_inliers
+        }
+        None => 0,
+    }
+}
+
+static mut ODOMETRY: Option<odometry::VisualOdometry> = None;
+
+// Thirteen doubles per frame: the timestamp followed by the pose as in `get_pose`. It only grows
+// by the frames added since the last call, so the whole trajectory isn't copied on every frame.
+static mut TRAJECTORY: Vec<f64> = Vec::new();
+
+// Follows the camera through a stream of frames. `slot` is the slot the new frame was written to,
+// and it is compared with the frame before it whichever slot that was in. Returns 1 if the motion
+// since the previous frame was found. The camera's pose in the world (the first frame) can be read
+// with `get_pose`, and the rest of what was found with the same getters as `calculate`.
+#[no_mangle]
+pub unsafe fn process_frame(width: usize, height: usize, slot: usize, timestamp: f64) -> usize {
+    let data = if slot == 1 {
+        slice::from_raw_parts(VEC_PTR_SLOT_1, VEC_LEN_SLOT_1)
+    } else {
+        slice::from_raw_parts(VEC_PTR_SLOT_0, VEC_LEN_SLOT_0)
+    };
+    let image = common::Image {
+        data,
+        width,
+        height,
+    };
+
+    let odometry = (*std::ptr::addr_of_mut!(ODOMETRY)).get_or_insert_with(|| {
+        let mut odometry = odometry::VisualOdometry::new();
+        odometry.set_configuration(configure_slam);
+        odometry
+    });
+    let frame = odometry.process_frame(image, timestamp);
+
+    match &frame.slam {
+        Some(result) => store_result(result),
+        // there is nothing to compare the first frame with yet, but it can still be shown
+        None => {
+            if let Some(latest) = odometry.latest_frame() {
+                store_result(&unmatched_result(latest));
+            }
+        }
+    }
+    POSE = pose_to_array(&frame.pose);
+
+    let trajectory = &mut *std::ptr::addr_of_mut!(TRAJECTORY);
+    for (timestamp, pose) in &odometry.trajectory()[trajectory.len() / 13..] {
+        trajectory.push(*timestamp);
+        trajectory.extend(pose_to_array(pose));
+    }
+
+    frame.tracked as usize
+}
+
+// Forgets the trajectory, the next frame passed to `process_frame` starts a new one.
+#[no_mangle]
+pub unsafe fn reset_odometry() {
+    if let Some(odometry) = (*std::ptr::addr_of_mut!(ODOMETRY)).as_mut() {
+        odometry.reset();
+    }
+    (*std::ptr::addr_of_mut!(TRAJECTORY)).clear();
+}
+
+#[no_mangle]
+pub unsafe fn get_trajectory() -> *mut f64 {
+    (*std::ptr::addr_of_mut!(TRAJECTORY)).as_mut_ptr()
+}
+
+#[no_mangle]
+pub unsafe fn get_trajectory_len() -> usize {
+    (*std::ptr::addr_of!(TRAJECTORY)).len() / 13
+}
+
+static mut TRACKER: Option<tracking::Tracker> = None;
+
+static mut KEYFRAME_OPTIONS: Option<keyframe_policy::KeyFrameOptions> = None;
+
+// Tune when tracked frames become keyframes: after at least `min_frames` and at most `max_frames`
+// frames since the last one, when fewer than `min_tracked_ratio` of its points are still tracked,
+// or when the camera moved `max_translation` (in the map's units, where the first points were
+// about 1 away) or turned `max_rotation` degrees from it.
+#[no_mangle]
+pub unsafe fn set_keyframe_policy(
+    min_frames: usize,
+    max_frames: usize,
+    min_tracked_ratio: f64,
+    max_translation: f64,
+    max_rotation: f64,
+) {
+    let options = keyframe_policy::KeyFrameOptions {
+        min_frames,
+        max_frames,
+        min_tracked_ratio,
+        max_translation,
+        max_rotation,
+    };
+    KEYFRAME_OPTIONS = Some(options);
+    if let Some(tracker) = (*std::ptr::addr_of_mut!(TRACKER)).as_mut() {
+        tracker.set_keyframe_options(options);
+    }
+}
+
+static mut KEYFRAME_DECISION: usize = 0;
+
+// Tracks the camera against a map built from the frames, rather than only from frame to frame.
+// `slot` is the slot the new frame was written to. Returns the tracking state: 0 while the map is
+// still being initialized, 1 when the frame was located and 2 when the camera is lost. When it was
+// located the camera's pose in the map can be read with `get_pose`.
+#[no_mangle]
+pub unsafe fn track_frame(width: usize, height: usize, slot: usize) -> usize {
+    let data = if slot == 1 {
+        slice::from_raw_parts(VEC_PTR_SLOT_1, VEC_LEN_SLOT_1)
+    } else {
+        slice::from_raw_parts(VEC_PTR_SLOT_0, VEC_LEN_SLOT_0)
+    };
+    let frame = frame::Frame::new(common::Image {
+        data,
+        width,
+        height,
+    });
+
+    let tracker = (*std::ptr::addr_of_mut!(TRACKER)).get_or_insert_with(|| {
+        let intrinsics =
+            known_intrinsics().unwrap_or_else(|| CameraIntrinsics::typical_webcam(width, height));
+        let mut tracker = tracking::Tracker::new(intrinsics);
+        tracker.set_configuration(configure_slam);
+        if let Some(options) = KEYFRAME_OPTIONS {
+            tracker.set_keyframe_options(options);
+        }
+        tracker
+    });
+    let result = tracker.process_frame(frame);
+
+    KEYFRAME_DECISION = match result.keyframe {
+        keyframe_policy::KeyFrameDecision::NotTracked => 0,
+        keyframe_policy::KeyFrameDecision::TooSoon => 1,
+        keyframe_policy::KeyFrameDecision::NotNeeded => 2,
+        keyframe_policy::KeyFrameDecision::Initialization => 3,
+        keyframe_policy::KeyFrameDecision::MaxFrames => 4,
+        keyframe_policy::KeyFrameDecision::FewTrackedPoints => 5,
+        keyframe_policy::KeyFrameDecision::Translation => 6,
+        keyframe_policy::KeyFrameDecision::Rotation => 7,
+    };
+
+    if let Some(pose) = result.pose {
+        POSE = pose_to_array(&pose);
+    }
+    NUM_INLIERS = result.matches.len();
+
+    match result.state {
+        tracking::TrackingState::NotInitialized => 0,
+        tracking::TrackingState::Ok => 1,
+        tracking::TrackingState::Lost => 2,
+    }
+}
+
+// Throws the tracker's map away, the next frames initialize a new one.
+#[no_mangle]
+pub unsafe fn reset_tracking() {
+    if let Some(tracker) = (*std::ptr::addr_of_mut!(TRACKER)).as_mut() {
+        tracker.reset();
+    }
+}
+
+// Why the last frame passed to `track_frame` did or didn't become a keyframe: 0 it wasn't tracked,
+// 1 too soon after the last keyframe, 2 not needed, and it became one for 3 building the first
+// map, 4 too many frames since the last one, 5 too few points tracked, 6 the camera moved and 7
+// the camera turned.
+#[no_mangle]
+pub unsafe fn get_keyframe_decision() -> usize {
+    KEYFRAME_DECISION
+}
+
+#[no_mangle]
+pub unsafe fn get_num_keyframes() -> usize {
+    match (*std::ptr::addr_of!(TRACKER)).as_ref() {
+        Some(tracker) => tracker.map().num_keyframes(),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe fn get_num_map_points() -> usize {
+    match (*std::ptr::addr_of!(TRACKER)).as_ref() {
+        Some(tracker) => tracker.map().num_map_points(),
+        None => 0,
+    }
+}
+
+// What local mapping has culled since the map was started: 0 map points found in too few of the
+// frames that should have seen them, 1 map points seen by too few keyframes and 2 redundant
+// keyframes.
+#[no_mangle]
+pub unsafe fn get_culling_counter(counter: usize) -> usize {
+    let counters = match (*std::ptr::addr_of!(TRACKER)).as_ref() {
+        Some(tracker) => tracker.local_mapping().counters(),
+        None => return 0,
+    };
+    match counter {
+        0 => counters.points_rarely_found,
+        1 => counters.points_few_observations,
+        2 => counters.keyframes,
+        _ => 0,
+    }
 }
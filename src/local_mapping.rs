@@ -0,0 +1,529 @@
+// Tracking only ever adds observations of points the map already has, so on its own the map would
+// stop at the edge of what the initializer saw. Local mapping grows it: whenever a keyframe is
+// added, its keypoints that aren't map points yet are matched with the same kind of keypoints in
+// the keyframes around it, and each match is triangulated into a new point.
+//
+// Those matches can't be found by projecting points, as there is no point yet. But the two poses
+// are known, so a keypoint in one keyframe must lie on a line in the other (its epipolar line), and
+// only the keypoints near that line are compared. The new points then go through the checks a first
+// map's points do, and the keyframe and its neighbours are optimized together with bundle
+// adjustment.
+//
+// Left at that the map would only ever grow, bad points included. So new points are on probation
+// for their first few keyframes and are culled if tracking keeps failing to find them where they
+// should be, and keyframes whose points are nearly all seen well enough by other keyframes are
+// culled as redundant.
+
+use nalgebra::{Matrix3, Vector3};
+
+use crate::bundle_adjustment::{self, BundleAdjustmentSummary};
+use crate::common::*;
+use crate::geometry::hat;
+use crate::map::{KeyFrame, KeyFrameId, Map, MapPointId, DISTANCE_TOLERANCE};
+use crate::phase_4::hamming_distance;
+use crate::phase_7::Loss;
+use crate::triangulation::{self, TriangulationMethod};
+
+// How long a new point is on probation, in keyframes added since it was created.
+const PROBATION_KEYFRAMES: usize = 3;
+// A point seen by this many other keyframes is seen well enough without a keyframe.
+const REDUNDANT_OBSERVATIONS: usize = 3;
+
+pub struct LocalMappingResult {
+    pub new_points: usize,
+    // None when there was no window to optimize
+    pub bundle_adjustment: Option<BundleAdjustmentSummary>,
+    pub culled_points: usize,
+    pub culled_keyframes: usize,
+}
+
+// Running totals of what was culled and why.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct CullingCounters {
+    // found in too few of the frames that should have seen them
+    pub points_rarely_found: usize,
+    // seen by too few keyframes by the end of their probation
+    pub points_few_observations: usize,
+    pub keyframes: usize,
+}
+
+pub struct LocalMapping {
+    intrinsics: CameraIntrinsics,
+    num_neighbours: usize,
+    max_hamming_distance: usize,
+    // in pixels
+    max_epipolar_distance: f64,
+    // in degrees
+    min_parallax: f64,
+    // in pixels
+    max_reprojection_error: f64,
+    bundle_adjustment_iterations: usize,
+    min_found_ratio: f64,
+    max_redundancy: f64,
+    // the points still on probation, with the keyframe they were created with
+    recent_points: Vec<(MapPointId, KeyFrameId)>,
+    counters: CullingCounters,
+}
+
+impl LocalMapping {
+    pub fn new(intrinsics: CameraIntrinsics) -> Self {
+        LocalMapping {
+            intrinsics,
+            num_neighbours: 10,
+            max_hamming_distance: 100,
+            max_epipolar_distance: 2.0,
+            min_parallax: 1.0,
+            max_reprojection_error: 3.0,
+            bundle_adjustment_iterations: 10,
+            min_found_ratio: 0.25,
+            max_redundancy: 0.9,
+            recent_points: vec![],
+            counters: CullingCounters::default(),
+        }
+    }
+
+    // How many of the most covisible keyframes new points are triangulated with and optimized
+    // with.
+    pub fn set_num_neighbours(&mut self, num_neighbours: usize) {
+        self.num_neighbours = num_neighbours;
+    }
+
+    // New points are made from keypoints that look alike, so this is usually stricter than when
+    // tracking known points.
+    pub fn set_max_hamming_distance(&mut self, max_hamming_distance: usize) {
+        self.max_hamming_distance = max_hamming_distance;
+    }
+
+    // The parallax in degrees a new point must be seen with.
+    pub fn set_min_parallax(&mut self, min_parallax: f64) {
+        self.min_parallax = min_parallax;
+    }
+
+    // 0 turns the local bundle adjustment off.
+    pub fn set_bundle_adjustment_iterations(&mut self, iterations: usize) {
+        self.bundle_adjustment_iterations = iterations;
+    }
+
+    // A new point found in fewer than this share of the frames that should have seen it is
+    // culled.
+    pub fn set_min_found_ratio(&mut self, min_found_ratio: f64) {
+        self.min_found_ratio = min_found_ratio;
+    }
+
+    // A keyframe is culled when at least this share of its points is seen well enough by others.
+    pub fn set_max_redundancy(&mut self, max_redundancy: f64) {
+        self.max_redundancy = max_redundancy;
+    }
+
+    pub fn counters(&self) -> CullingCounters {
+        self.counters
+    }
+
+    // Starts over for a new map, forgetting the points on probation and what was culled.
+    pub fn reset(&mut self) {
+        self.recent_points.clear();
+        self.counters = CullingCounters::default();
+    }
+
+    // Grows the map around a keyframe that was just added to it, and prunes it.
+    pub fn process_keyframe(&mut self, map: &mut Map, id: KeyFrameId) -> LocalMappingResult {
+        let culled_points = self.cull_map_points(map, id);
+        let new_points = self.create_new_points(map, id);
+
+        let mut window = vec![id];
+        if let Some(keyframe) = map.keyframe(id) {
+            window.extend(
+                keyframe
+                    .covisible_keyframes(1)
+                    .into_iter()
+                    .take(self.num_neighbours),
+            );
+        }
+        let bundle_adjustment =
+            (self.bundle_adjustment_iterations > 0 && window.len() > 1).then(|| {
+                bundle_adjustment::local_bundle_adjustment(
+                    map,
+                    &window,
+                    &self.intrinsics,
+                    Loss::Huber(self.max_reprojection_error),
+                    self.bundle_adjustment_iterations,
+                )
+            });
+
+        let culled_keyframes = self.cull_keyframes(map, id);
+
+        LocalMappingResult {
+            new_points,
+            bundle_adjustment,
+            culled_points,
+            culled_keyframes,
+        }
+    }
+
+    // Culls the points on probation that tracking rarely found, or that too few keyframes saw
+    // once they had the chance to. Returns how many were removed.
+    fn cull_map_points(&mut self, map: &mut Map, id: KeyFrameId) -> usize {
+        let mut culled = 0;
+        let mut recent_points = std::mem::take(&mut self.recent_points);
+        recent_points.retain(|&(point_id, created)| {
+            let point = match map.map_point(point_id) {
+                Some(point) => point,
+                None => return false,
+            };
+            let age = id.saturating_sub(created);
+            if point.found_ratio() < self.min_found_ratio {
+                self.counters.points_rarely_found += 1;
+            } else if age >= 2 && point.observations.len() <= 2 {
+                self.counters.points_few_observations += 1;
+            } else {
+                return age < PROBATION_KEYFRAMES;
+            }
+            map.remove_map_point(point_id);
+            culled += 1;
+            false
+        });
+        self.recent_points = recent_points;
+        culled
+    }
+
+    // Culls the keyframe's neighbours whose points are nearly all seen by enough other keyframes,
+    // as they add little but work. The root of the spanning tree is kept, as it holds the map in
+    // place. Returns how many were removed.
+    fn cull_keyframes(&mut self, map: &mut Map, id: KeyFrameId) -> usize {
+        let neighbours = match map.keyframe(id) {
+            Some(keyframe) => keyframe.covisible_keyframes(1),
+            None => return 0,
+        };
+
+        let mut culled = 0;
+        for neighbour in neighbours {
+            let keyframe = match map.keyframe(neighbour) {
+                Some(keyframe) if keyframe.parent.is_some() => keyframe,
+                _ => continue,
+            };
+            let mut num_points = 0;
+            let mut num_redundant = 0;
+            for point in keyframe.observed_map_points() {
+                num_points += 1;
+                let observations = map.map_point(point).unwrap().observations.len();
+                if observations > REDUNDANT_OBSERVATIONS {
+                    num_redundant += 1;
+                }
+            }
+            if num_points > 0 && num_redundant as f64 >= self.max_redundancy * num_points as f64 {
+                map.remove_keyframe(neighbour);
+                self.counters.keyframes += 1;
+                culled += 1;
+            }
+        }
+        culled
+    }
+
+    // Triangulates the keyframe's keypoints that aren't map points with the ones of its
+    // neighbours, and returns how many points were added.
+    fn create_new_points(&mut self, map: &mut Map, id: KeyFrameId) -> usize {
+        let neighbours: Vec<KeyFrameId> = match map.keyframe(id) {
+            Some(keyframe) => keyframe
+                .covisible_keyframes(1)
+                .into_iter()
+                .take(self.num_neighbours)
+                .collect(),
+            None => return 0,
+        };
+
+        let mut new_points = 0;
+        for neighbour in neighbours {
+            let points = self.triangulate_with(map, id, neighbour);
+            for (position, index, other_index) in points {
+                let point = map.add_map_point(position);
+                map.add_observation(point, id, index);
+                map.add_observation(point, neighbour, other_index);
+                self.recent_points.push((point, id));
+                new_points += 1;
+            }
+        }
+        new_points
+    }
+
+    // The new points between two keyframes, with the keypoints they were seen at in each.
+    fn triangulate_with(
+        &self,
+        map: &Map,
+        id: KeyFrameId,
+        neighbour: KeyFrameId,
+    ) -> Vec<(Vector3<f64>, usize, usize)> {
+        let (keyframe, other) = match (map.keyframe(id), map.keyframe(neighbour)) {
+            (Some(keyframe), Some(other)) => (keyframe, other),
+            _ => return vec![],
+        };
+
+        // cameras barely apart compared with how far away the scene is can't triangulate anything
+        let baseline = (keyframe.camera_center() - other.camera_center()).norm();
+        match median_depth(map, other) {
+            Some(depth) if baseline / depth >= 0.01 => {}
+            _ => return vec![],
+        }
+
+        // the pose of the other keyframe relative to this one
+        let (r1, t1) = keyframe.pose;
+        let (r2, t2) = other.pose;
+        let rotation = r2 * r1.transpose();
+        let translation = t2 - rotation * t1;
+
+        self.search_for_triangulation(keyframe, other, &rotation, &translation)
+            .into_iter()
+            .filter_map(|(index, other_index)| {
+                let p1 = self.intrinsics.normalize(&keyframe.keypoints[index]);
+                let p2 = self.intrinsics.normalize(&other.keypoints[other_index]);
+                let point = triangulation::triangulate(
+                    TriangulationMethod::Linear,
+                    &rotation,
+                    &translation,
+                    &p1,
+                    &p2,
+                )?;
+                if !point.is_in_front(&rotation, &translation) || point.parallax < self.min_parallax
+                {
+                    return None;
+                }
+
+                // close enough to both keypoints in pixels
+                let in_other = rotation * point.position + translation;
+                for (position, keypoint) in [
+                    (point.position, keyframe.keypoints[index]),
+                    (in_other, other.keypoints[other_index]),
+                ] {
+                    let (u, v) = self.intrinsics.project(&position)?;
+                    let error = (u - keypoint.x as f64).hypot(v - keypoint.y as f64);
+                    if error > self.max_reprojection_error {
+                        return None;
+                    }
+                }
+
+                // a keypoint looks the same from as far as it would still be recognized, so the
+                // point can't be much closer to one camera than to the other
+                let ratio = point.position.norm() / in_other.norm();
+                if !(1.0 / DISTANCE_TOLERANCE..=DISTANCE_TOLERANCE).contains(&ratio) {
+                    return None;
+                }
+
+                let world = r1.transpose() * (point.position - t1);
+                Some((world, index, other_index))
+            })
+            .collect()
+    }
+
+    // Matches the keypoints of the two keyframes that aren't map points yet, comparing each only
+    // with the keypoints close to its epipolar line in the other keyframe.
+    fn search_for_triangulation(
+        &self,
+        keyframe: &KeyFrame,
+        other: &KeyFrame,
+        rotation: &Matrix3<f64>,
+        translation: &Vector3<f64>,
+    ) -> Vec<(usize, usize)> {
+        let essential = hat(translation) * rotation;
+        let max_distance = self
+            .intrinsics
+            .pixels_to_normalized(self.max_epipolar_distance);
+
+        let free = |keyframe: &KeyFrame| -> Vec<(usize, Vector3<f64>)> {
+            keyframe
+                .map_points
+                .iter()
+                .enumerate()
+                .filter(|(_, point)| point.is_none())
+                .map(|(index, _)| {
+                    let p = self.intrinsics.normalize(&keyframe.keypoints[index]);
+                    (index, Vector3::new(p.x as f64, p.y as f64, 1.0))
+                })
+                .collect()
+        };
+        let other_keypoints = free(other);
+
+        let mut candidates: Vec<(usize, usize, usize)> = free(keyframe)
+            .into_iter()
+            .filter_map(|(index, p1)| {
+                let line = essential * p1;
+                let norm = line.x.hypot(line.y);
+                let (distance, other_index) = other_keypoints
+                    .iter()
+                    .filter(|(_, p2)| (line.dot(p2) / norm).abs() <= max_distance)
+                    .map(|&(other_index, _)| {
+                        let distance = hamming_distance(
+                            &keyframe.descriptors[index].0,
+                            &other.descriptors[other_index].0,
+                        );
+                        (distance, other_index)
+                    })
+                    .min()?;
+                (distance <= self.max_hamming_distance).then_some((distance, index, other_index))
+            })
+            .collect();
+
+        // each keypoint of the other keyframe goes to the closest descriptor that wants it
+        candidates.sort();
+        let mut taken = vec![false; other.keypoints.len()];
+        candidates
+            .into_iter()
+            .filter(|&(_, _, other_index)| !std::mem::replace(&mut taken[other_index], true))
+            .map(|(_, index, other_index)| (index, other_index))
+            .collect()
+    }
+}
+
+// The median depth of the points a keyframe sees, in its own camera.
+fn median_depth(map: &Map, keyframe: &KeyFrame) -> Option<f64> {
+    let (rotation, translation) = keyframe.pose;
+    let mut depths: Vec<f64> = keyframe
+        .observed_map_points()
+        .filter_map(|point| map.map_point(point))
+        .map(|point| (rotation * point.position + translation).z)
+        .collect();
+    if depths.is_empty() {
+        return None;
+    }
+    depths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    Some(depths[depths.len() / 2])
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand::Rand;
+    use crate::synthetic::*;
+    use nalgebra::Rotation3;
+
+    // Two keyframes `baseline` apart seeing the same scene, sharing only its first 30 points.
+    fn two_keyframe_map(baseline: f64) -> (Map, Vec<Vector3<f64>>, KeyFrameId) {
+        let mut rng = Rand::new_with_seed(22);
+        let points = random_points(&mut rng, 150);
+        let descriptors = random_descriptors(&mut rng, points.len());
+
+        let first = (nalgebra::Matrix3::identity(), Vector3::zeros());
+        let rotation = Rotation3::from_euler_angles(0.0, -0.05, 0.0).into_inner();
+        let second = (rotation, -rotation * Vector3::new(baseline, 0.0, 0.0));
+
+        let mut map = Map::new();
+        let a = map.add_keyframe(first, &frame_at(&first, &points, &descriptors));
+        let b = map.add_keyframe(second, &frame_at(&second, &points, &descriptors));
+        for (index, position) in points.iter().enumerate().take(30) {
+            let point = map.add_map_point(*position);
+            map.add_observation(point, a, index);
+            map.add_observation(point, b, index);
+        }
+        map.attach_keyframe(b);
+        (map, points, b)
+    }
+
+    #[test]
+    fn test_create_new_points() {
+        let (mut map, points, keyframe) = two_keyframe_map(0.5);
+        let mut local_mapping = LocalMapping::new(intrinsics());
+        let result = local_mapping.process_keyframe(&mut map, keyframe);
+
+        // far points are seen with less parallax than needed, the rest all become map points
+        let expected = points[30..]
+            .iter()
+            .filter(|point| {
+                let center = Vector3::new(0.5, 0.0, 0.0);
+                let cos = point.dot(&(*point - center)) / (point.norm() * (*point - center).norm());
+                cos.acos().to_degrees() >= 1.0
+            })
+            .count();
+        assert!(expected > 100);
+        assert_eq!(result.new_points, expected);
+        assert_eq!(map.num_map_points(), 30 + expected);
+        assert!(result.bundle_adjustment.unwrap().final_cost < 1e-6);
+
+        // each went to the keypoints of the same point in both keyframes, where it should be
+        for point in map.map_points() {
+            let (a, index_a) = point.observations[0];
+            let (_, index_b) = point.observations[1];
+            assert_eq!(index_a, index_b);
+            assert_eq!(map.keyframe(a).unwrap().map_points[index_a], Some(point.id));
+            assert!((point.position - points[index_a]).norm() < 1e-3);
+        }
+        assert_eq!(
+            map.keyframe(keyframe).unwrap().covisibility[&0],
+            30 + expected
+        );
+    }
+
+    #[test]
+    fn test_cull_map_points() {
+        let (mut map, _, keyframe) = two_keyframe_map(0.5);
+        let mut local_mapping = LocalMapping::new(intrinsics());
+        local_mapping.set_bundle_adjustment_iterations(0);
+        let new_points = local_mapping
+            .process_keyframe(&mut map, keyframe)
+            .new_points;
+
+        // tracking looked for all the new points in a frame and only found the first 10
+        let recent: Vec<MapPointId> = local_mapping.recent_points.iter().map(|p| p.0).collect();
+        for &point in &recent {
+            for _ in 0..4 {
+                map.increase_visible(point);
+            }
+        }
+        for &point in &recent[..10] {
+            map.increase_found(point);
+        }
+        assert_eq!(
+            local_mapping.cull_map_points(&mut map, keyframe + 1),
+            new_points - 10
+        );
+        assert_eq!(map.num_map_points(), 30 + 10);
+
+        // two keyframes later the rest are still only seen by the keyframes they were made from
+        assert_eq!(local_mapping.cull_map_points(&mut map, keyframe + 2), 10);
+        assert_eq!(map.num_map_points(), 30);
+        assert!(local_mapping.recent_points.is_empty());
+        assert_eq!(
+            local_mapping.counters(),
+            CullingCounters {
+                points_rarely_found: new_points - 10,
+                points_few_observations: 10,
+                keyframes: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cull_keyframes() {
+        let (mut map, points, _) = two_keyframe_map(0.5);
+        let descriptors = vec![Descriptor(vec![0]); points.len()];
+        // three more keyframes seeing the points the first two share
+        for x in [1.0, 1.5, 2.0] {
+            let pose = (nalgebra::Matrix3::identity(), Vector3::new(-x, 0.0, 0.0));
+            let keyframe = map.add_keyframe(pose, &frame_at(&pose, &points, &descriptors));
+            for index in 0..30 {
+                let point = map.keyframe(0).unwrap().map_points[index].unwrap();
+                map.add_observation(point, keyframe, index);
+            }
+            map.attach_keyframe(keyframe);
+        }
+
+        // every point is seen by all 5 keyframes, so the neighbours can go one by one until the
+        // points are seen by only 3, but never the root
+        let mut local_mapping = LocalMapping::new(intrinsics());
+        assert_eq!(local_mapping.cull_keyframes(&mut map, 4), 2);
+        let remaining: Vec<KeyFrameId> = map.keyframes().map(|keyframe| keyframe.id).collect();
+        assert_eq!(remaining, vec![0, 3, 4]);
+        assert_eq!(local_mapping.counters().keyframes, 2);
+    }
+
+    #[test]
+    fn test_no_points_without_baseline() {
+        let (mut map, _, keyframe) = two_keyframe_map(0.01);
+        let mut local_mapping = LocalMapping::new(intrinsics());
+        local_mapping.set_bundle_adjustment_iterations(0);
+        let result = local_mapping.process_keyframe(&mut map, keyframe);
+        assert_eq!(result.new_points, 0);
+        assert!(result.bundle_adjustment.is_none());
+    }
+}
@@ -0,0 +1,968 @@
+// The map is what turns visual odometry into SLAM. Instead of forgetting a frame as soon as the
+// next one arrives, a few of them are kept as keyframes, and the 3D points triangulated between
+// them are kept as map points. Later frames are located against the map, so the camera can come
+// back to a place it has seen and land where it was before rather than wherever its drift says.
+//
+// The two are tied together by observations: a map point remembers which keypoint of which
+// keyframe saw it, and a keyframe remembers which map point each of its keypoints is. Every
+// operation below keeps both sides of that link in agreement.
+//
+// The observations also link keyframes with each other. Two keyframes that see the same points are
+// covisible, and the number of points they share says how strongly. That graph tells which
+// keyframes belong together in a local window, but it is dense: every keyframe is connected to
+// every other one it shares a single point with. For optimizing the whole map a sparser skeleton is
+// kept too, the essential graph: a spanning tree where each keyframe hangs from the one it shares
+// the most points with, plus the strongest covisibility edges and the edges closing loops.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use nalgebra::Vector3;
+
+use crate::common::*;
+use crate::frame::Frame;
+use crate::initializer::InitialMap;
+use crate::phase_4::hamming_distance;
+
+pub type KeyFrameId = usize;
+pub type MapPointId = usize;
+
+// Without an image pyramid the scale a point was detected at isn't known, so a point is expected
+// to be recognizable from this many times closer or further than it was seen from.
+pub const DISTANCE_TOLERANCE: f64 = 2.0;
+
+// Covisibility edges between keyframes sharing at least this many points are in the essential
+// graph.
+pub const ESSENTIAL_GRAPH_MIN_WEIGHT: usize = 100;
+
+pub struct KeyFrame {
+    pub id: KeyFrameId,
+    // maps points from the world into the camera
+    pub pose: Pose,
+    pub width: usize,
+    pub height: usize,
+    pub keypoints: Vec<KeyPoint>,
+    pub descriptors: Vec<Descriptor>,
+    // the map point each keypoint is, if any
+    pub map_points: Vec<Option<MapPointId>>,
+    // the keyframes sharing points with this one, and how many
+    pub covisibility: BTreeMap<KeyFrameId, usize>,
+    // the spanning tree, in which the first keyframe is the root
+    pub parent: Option<KeyFrameId>,
+    pub children: BTreeSet<KeyFrameId>,
+    // the keyframes this one was found to close a loop with
+    pub loop_edges: BTreeSet<KeyFrameId>,
+}
+
+impl KeyFrame {
+    // Where the camera is in the world, -Rᵀ·t.
+    pub fn camera_center(&self) -> Vector3<f64> {
+        -self.pose.0.transpose() * self.pose.1
+    }
+
+    // The map points this keyframe sees.
+    pub fn observed_map_points(&self) -> impl Iterator<Item = MapPointId> + '_ {
+        self.map_points.iter().flatten().copied()
+    }
+
+    // The keyframes sharing at least `min_weight` points with this one, the most covisible first.
+    pub fn covisible_keyframes(&self, min_weight: usize) -> Vec<KeyFrameId> {
+        let mut keyframes: Vec<(KeyFrameId, usize)> = self
+            .covisibility
+            .iter()
+            .filter(|(_, &weight)| weight >= min_weight)
+            .map(|(&id, &weight)| (id, weight))
+            .collect();
+        keyframes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        keyframes.into_iter().map(|(id, _)| id).collect()
+    }
+
+    // The keyframe's edges in the essential graph.
+    pub fn essential_graph_neighbours(&self) -> BTreeSet<KeyFrameId> {
+        let mut neighbours: BTreeSet<KeyFrameId> = self
+            .covisible_keyframes(ESSENTIAL_GRAPH_MIN_WEIGHT)
+            .into_iter()
+            .collect();
+        neighbours.extend(self.parent);
+        neighbours.extend(&self.children);
+        neighbours.extend(&self.loop_edges);
+        neighbours
+    }
+}
+
+pub struct MapPoint {
+    pub id: MapPointId,
+    pub position: Vector3<f64>,
+    // the observation's descriptor closest to all the others, used to match the point in new
+    // frames
+    pub descriptor: Descriptor,
+    // the mean direction the point is seen from, a unit vector from the cameras towards it
+    pub viewing_direction: Vector3<f64>,
+    // the range of distances the point can be expected to be recognized from
+    pub min_distance: f64,
+    pub max_distance: f64,
+    // the keyframes that see the point and the index of the keypoint it is in each of them
+    pub observations: Vec<(KeyFrameId, usize)>,
+    // how many tracked frames the point was expected to be in view of, and how many of those it was
+    // actually found in
+    pub visible: usize,
+    pub found: usize,
+}
+
+impl MapPoint {
+    // Whether a camera at `center` is in the range of distances the point can be recognized from.
+    pub fn is_in_range(&self, center: &Vector3<f64>) -> bool {
+        let distance = (self.position - center).norm();
+        distance >= self.min_distance && distance <= self.max_distance
+    }
+
+    // The share of the frames that should have seen the point that did. A point that is rarely
+    // found where it should be is probably a bad triangulation.
+    pub fn found_ratio(&self) -> f64 {
+        self.found as f64 / self.visible as f64
+    }
+}
+
+#[derive(Default)]
+pub struct Map {
+    keyframes: BTreeMap<KeyFrameId, KeyFrame>,
+    map_points: BTreeMap<MapPointId, MapPoint>,
+    next_keyframe_id: KeyFrameId,
+    next_map_point_id: MapPointId,
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Map::default()
+    }
+
+    // The first map, with the initializer's two frames as keyframes and its points as map points.
+    pub fn from_initial_map(initial: &InitialMap) -> Map {
+        let mut map = Map::new();
+        let identity = (nalgebra::Matrix3::identity(), Vector3::zeros());
+        let reference = map.add_keyframe(identity, &initial.reference);
+        let current = map.add_keyframe(initial.pose, &initial.current);
+
+        for (position, (p1, p2)) in initial.points.iter().zip(&initial.observations) {
+            let index_1 = initial.reference.keypoints.iter().position(|k| k == p1);
+            let index_2 = initial.current.keypoints.iter().position(|k| k == p2);
+            if let (Some(index_1), Some(index_2)) = (index_1, index_2) {
+                let point = map.add_map_point(*position);
+                map.add_observation(point, reference, index_1);
+                map.add_observation(point, current, index_2);
+            }
+        }
+        map.attach_keyframe(current);
+        map
+    }
+
+    pub fn add_keyframe(&mut self, pose: Pose, frame: &Frame) -> KeyFrameId {
+        let id = self.next_keyframe_id;
+        self.next_keyframe_id += 1;
+        self.keyframes.insert(
+            id,
+            KeyFrame {
+                id,
+                pose,
+                width: frame.width,
+                height: frame.height,
+                keypoints: frame.keypoints.clone(),
+                descriptors: frame.descriptors.clone(),
+                map_points: vec![None; frame.keypoints.len()],
+                covisibility: BTreeMap::new(),
+                parent: None,
+                children: BTreeSet::new(),
+                loop_edges: BTreeSet::new(),
+            },
+        );
+        id
+    }
+
+    // A new point with no observations yet. It gets its descriptor and viewing direction from the
+    // observations added with `add_observation`.
+    pub fn add_map_point(&mut self, position: Vector3<f64>) -> MapPointId {
+        let id = self.next_map_point_id;
+        self.next_map_point_id += 1;
+        self.map_points.insert(
+            id,
+            MapPoint {
+                id,
+                position,
+                descriptor: Descriptor(vec![]),
+                viewing_direction: Vector3::zeros(),
+                min_distance: 0.0,
+                max_distance: f64::INFINITY,
+                observations: vec![],
+                // counting the frames the point was made from
+                visible: 1,
+                found: 1,
+            },
+        );
+        id
+    }
+
+    pub fn keyframe(&self, id: KeyFrameId) -> Option<&KeyFrame> {
+        self.keyframes.get(&id)
+    }
+
+    // Hangs a new keyframe in the spanning tree, from the keyframe it shares the most points with.
+    // Called once its observations are in, as before that it isn't covisible with anything.
+    pub fn attach_keyframe(&mut self, id: KeyFrameId) {
+        let parent = match self.keyframes.get(&id) {
+            Some(keyframe) if keyframe.parent.is_none() => {
+                match keyframe.covisible_keyframes(1).first() {
+                    Some(&parent) => parent,
+                    None => return,
+                }
+            }
+            _ => return,
+        };
+        // a keyframe already in the tree below this one can't become its parent
+        if self.is_ancestor(id, parent) {
+            return;
+        }
+        self.set_parent(id, Some(parent));
+    }
+
+    // Records that the two keyframes see the same place, found by recognizing it rather than by
+    // tracking.
+    pub fn add_loop_edge(&mut self, a: KeyFrameId, b: KeyFrameId) {
+        if a == b || !self.keyframes.contains_key(&a) || !self.keyframes.contains_key(&b) {
+            return;
+        }
+        self.keyframes.get_mut(&a).unwrap().loop_edges.insert(b);
+        self.keyframes.get_mut(&b).unwrap().loop_edges.insert(a);
+    }
+
+    // Every edge of the essential graph once, with the lower id first.
+    pub fn essential_graph_edges(&self) -> Vec<(KeyFrameId, KeyFrameId)> {
+        self.keyframes()
+            .flat_map(|keyframe| {
+                keyframe
+                    .essential_graph_neighbours()
+                    .into_iter()
+                    .filter(move |&other| other > keyframe.id)
+                    .map(move |other| (keyframe.id, other))
+            })
+            .collect()
+    }
+
+    pub fn map_point(&self, id: MapPointId) -> Option<&MapPoint> {
+        self.map_points.get(&id)
+    }
+
+    pub fn keyframes(&self) -> impl Iterator<Item = &KeyFrame> {
+        self.keyframes.values()
+    }
+
+    pub fn map_points(&self) -> impl Iterator<Item = &MapPoint> {
+        self.map_points.values()
+    }
+
+    pub fn num_keyframes(&self) -> usize {
+        self.keyframes.len()
+    }
+
+    pub fn num_map_points(&self) -> usize {
+        self.map_points.len()
+    }
+
+    // Moving a keyframe changes where its points are seen from, so their viewing directions and
+    // distances are worked out again.
+    pub fn set_keyframe_pose(&mut self, id: KeyFrameId, pose: Pose) {
+        let points: Vec<MapPointId> = match self.keyframes.get_mut(&id) {
+            Some(keyframe) => {
+                keyframe.pose = pose;
+                keyframe.observed_map_points().collect()
+            }
+            None => return,
+        };
+        for point in points {
+            self.update_geometry(point);
+        }
+    }
+
+    pub fn increase_visible(&mut self, id: MapPointId) {
+        if let Some(point) = self.map_points.get_mut(&id) {
+            point.visible += 1;
+        }
+    }
+
+    pub fn increase_found(&mut self, id: MapPointId) {
+        if let Some(point) = self.map_points.get_mut(&id) {
+            point.found += 1;
+        }
+    }
+
+    pub fn set_map_point_position(&mut self, id: MapPointId, position: Vector3<f64>) {
+        if let Some(point) = self.map_points.get_mut(&id) {
+            point.position = position;
+            self.update_geometry(id);
+        }
+    }
+
+    // Records that keypoint `index` of the keyframe is the map point. A keypoint can only be one
+    // point and a point is only seen once per keyframe, so any association this replaces is
+    // removed first.
+    pub fn add_observation(&mut self, point: MapPointId, keyframe: KeyFrameId, index: usize) {
+        let valid = self.map_points.contains_key(&point)
+            && self
+                .keyframes
+                .get(&keyframe)
+                .is_some_and(|keyframe| index < keyframe.keypoints.len());
+        if !valid {
+            return;
+        }
+
+        if let Some(previous) = self.keyframes[&keyframe].map_points[index] {
+            if previous == point {
+                return;
+            }
+            self.remove_observation(previous, keyframe);
+        }
+
+        let map_point = self.map_points.get_mut(&point).unwrap();
+        let keyframe_entry = self.keyframes.get_mut(&keyframe).unwrap();
+        let mut newly_covisible = vec![];
+        match map_point
+            .observations
+            .iter_mut()
+            .find(|(id, _)| *id == keyframe)
+        {
+            // the keyframe already saw the point at another keypoint, which no longer is it
+            Some(observation) => {
+                keyframe_entry.map_points[observation.1] = None;
+                observation.1 = index;
+            }
+            None => {
+                newly_covisible = map_point.observations.iter().map(|&(id, _)| id).collect();
+                map_point.observations.push((keyframe, index));
+            }
+        }
+        keyframe_entry.map_points[index] = Some(point);
+        for other in newly_covisible {
+            self.connect(keyframe, other);
+        }
+        self.update_descriptor(point);
+        self.update_geometry(point);
+    }
+
+    // Forgets that the keyframe sees the point. A point nobody sees any more is removed.
+    pub fn remove_observation(&mut self, point: MapPointId, keyframe: KeyFrameId) {
+        let map_point = match self.map_points.get_mut(&point) {
+            Some(map_point) => map_point,
+            None => return,
+        };
+        let position = match map_point
+            .observations
+            .iter()
+            .position(|&(id, _)| id == keyframe)
+        {
+            Some(position) => position,
+            None => return,
+        };
+        let (_, index) = map_point.observations.remove(position);
+        let orphaned = map_point.observations.is_empty();
+        let others: Vec<KeyFrameId> = map_point.observations.iter().map(|&(id, _)| id).collect();
+        for other in others {
+            self.disconnect(keyframe, other);
+        }
+
+        if let Some(keyframe) = self.keyframes.get_mut(&keyframe) {
+            keyframe.map_points[index] = None;
+        }
+        if orphaned {
+            self.map_points.remove(&point);
+        } else {
+            self.update_descriptor(point);
+            self.update_geometry(point);
+        }
+    }
+
+    pub fn remove_map_point(&mut self, id: MapPointId) {
+        if let Some(point) = self.map_points.remove(&id) {
+            self.disconnect_all(&point.observations);
+            for (keyframe, index) in point.observations {
+                if let Some(keyframe) = self.keyframes.get_mut(&keyframe) {
+                    keyframe.map_points[index] = None;
+                }
+            }
+        }
+    }
+
+    // Removes the keyframe along with its observations, and with them any point only it saw. Its
+    // children in the spanning tree are hung from other keyframes.
+    pub fn remove_keyframe(&mut self, id: KeyFrameId) {
+        let points: Vec<MapPointId> = match self.keyframes.get(&id) {
+            Some(keyframe) => keyframe.observed_map_points().collect(),
+            None => return,
+        };
+        for point in points {
+            self.remove_observation(point, id);
+        }
+
+        let keyframe = &self.keyframes[&id];
+        let parent = keyframe.parent;
+        let mut children = keyframe.children.clone();
+        let loop_edges = keyframe.loop_edges.clone();
+        self.set_parent(id, None);
+        for &child in &children {
+            self.set_parent(child, None);
+        }
+        for other in loop_edges {
+            self.keyframes
+                .get_mut(&other)
+                .unwrap()
+                .loop_edges
+                .remove(&id);
+        }
+        self.keyframes.remove(&id);
+
+        // Each child goes to whichever keyframe already placed it shares the most points with,
+        // starting from the removed keyframe's parent and adding the children as they are placed.
+        // Without a parent the first child becomes the root.
+        let mut placed: Vec<KeyFrameId> = match parent {
+            Some(parent) => vec![parent],
+            None => children.pop_first().into_iter().collect(),
+        };
+        loop {
+            let best = children
+                .iter()
+                .flat_map(|&child| placed.iter().map(move |&candidate| (child, candidate)))
+                .map(|(child, candidate)| {
+                    let weight = self.keyframes[&child].covisibility.get(&candidate).copied();
+                    (weight.unwrap_or(0), child, candidate)
+                })
+                .filter(|&(weight, _, _)| weight > 0)
+                .max_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+            match best {
+                Some((_, child, candidate)) => {
+                    self.set_parent(child, Some(candidate));
+                    children.remove(&child);
+                    placed.push(child);
+                }
+                None => break,
+            }
+        }
+        // children sharing nothing with the rest of the tree still need a place in it
+        if let Some(&root) = placed.first() {
+            for child in children {
+                self.set_parent(child, Some(root));
+            }
+        }
+    }
+
+    // When two map points turn out to be the same one, `replaced` is folded into `kept`: its
+    // observations move over (except where a keyframe already sees `kept`) and it is removed.
+    pub fn merge_map_points(&mut self, kept: MapPointId, replaced: MapPointId) {
+        if kept == replaced || !self.map_points.contains_key(&kept) {
+            return;
+        }
+        let (observations, visible, found) = match self.map_points.remove(&replaced) {
+            Some(point) => (point.observations, point.visible, point.found),
+            None => return,
+        };
+        self.disconnect_all(&observations);
+        let kept_point = self.map_points.get_mut(&kept).unwrap();
+        kept_point.visible += visible;
+        kept_point.found += found;
+
+        for (keyframe, index) in observations {
+            let observers: Vec<KeyFrameId> = self.map_points[&kept]
+                .observations
+                .iter()
+                .map(|&(id, _)| id)
+                .collect();
+            let entry = &mut self.keyframes.get_mut(&keyframe).unwrap().map_points[index];
+            if observers.contains(&keyframe) {
+                *entry = None;
+            } else {
+                *entry = Some(kept);
+                for other in observers {
+                    self.connect(keyframe, other);
+                }
+                self.map_points
+                    .get_mut(&kept)
+                    .unwrap()
+                    .observations
+                    .push((keyframe, index));
+            }
+        }
+        self.update_descriptor(kept);
+        self.update_geometry(kept);
+    }
+
+    // One more point shared between two keyframes.
+    fn connect(&mut self, a: KeyFrameId, b: KeyFrameId) {
+        *self
+            .keyframes
+            .get_mut(&a)
+            .unwrap()
+            .covisibility
+            .entry(b)
+            .or_insert(0) += 1;
+        *self
+            .keyframes
+            .get_mut(&b)
+            .unwrap()
+            .covisibility
+            .entry(a)
+            .or_insert(0) += 1;
+    }
+
+    // One less point shared between two keyframes, with the edge gone when none are left.
+    fn disconnect(&mut self, a: KeyFrameId, b: KeyFrameId) {
+        for (from, to) in [(a, b), (b, a)] {
+            let covisibility = &mut self.keyframes.get_mut(&from).unwrap().covisibility;
+            if let Some(weight) = covisibility.get_mut(&to) {
+                *weight -= 1;
+                if *weight == 0 {
+                    covisibility.remove(&to);
+                }
+            }
+        }
+    }
+
+    // Takes away the links a point made between every two keyframes seeing it.
+    fn disconnect_all(&mut self, observations: &[(KeyFrameId, usize)]) {
+        for (i, &(a, _)) in observations.iter().enumerate() {
+            for &(b, _) in &observations[i + 1..] {
+                self.disconnect(a, b);
+            }
+        }
+    }
+
+    fn set_parent(&mut self, id: KeyFrameId, parent: Option<KeyFrameId>) {
+        let previous = std::mem::replace(&mut self.keyframes.get_mut(&id).unwrap().parent, parent);
+        if let Some(previous) = previous {
+            if let Some(previous) = self.keyframes.get_mut(&previous) {
+                previous.children.remove(&id);
+            }
+        }
+        if let Some(parent) = parent {
+            self.keyframes.get_mut(&parent).unwrap().children.insert(id);
+        }
+    }
+
+    // Whether `ancestor` is `id` or above it in the spanning tree.
+    fn is_ancestor(&self, ancestor: KeyFrameId, mut id: KeyFrameId) -> bool {
+        loop {
+            if id == ancestor {
+                return true;
+            }
+            match self.keyframes[&id].parent {
+                Some(parent) => id = parent,
+                None => return false,
+            }
+        }
+    }
+
+    // Picks the observation's descriptor with the smallest median distance to all the others, the
+    // one most like how the point usually looks.
+    fn update_descriptor(&mut self, id: MapPointId) {
+        let descriptors: Vec<&Descriptor> = self.map_points[&id]
+            .observations
+            .iter()
+            .map(|(keyframe, index)| &self.keyframes[keyframe].descriptors[*index])
+            .collect();
+
+        let best = descriptors
+            .iter()
+            .min_by_key(|a| {
+                let mut distances: Vec<usize> = descriptors
+                    .iter()
+                    .map(|b| hamming_distance(&a.0, &b.0))
+                    .collect();
+                distances.sort();
+                distances[distances.len() / 2]
+            })
+            .map(|descriptor| (*descriptor).clone());
+
+        if let Some(best) = best {
+            self.map_points.get_mut(&id).unwrap().descriptor = best;
+        }
+    }
+
+    fn update_geometry(&mut self, id: MapPointId) {
+        let point = &self.map_points[&id];
+        let mut direction_sum = Vector3::zeros();
+        let mut closest = f64::INFINITY;
+        let mut furthest: f64 = 0.0;
+        for (keyframe, _) in &point.observations {
+            let ray = point.position - self.keyframes[keyframe].camera_center();
+            let distance = ray.norm();
+            if distance > 0.0 {
+                direction_sum += ray / distance;
+            }
+            closest = closest.min(distance);
+            furthest = furthest.max(distance);
+        }
+
+        let point = self.map_points.get_mut(&id).unwrap();
+        point.viewing_direction = direction_sum.try_normalize(0.0).unwrap_or_default();
+        if furthest > 0.0 {
+            point.min_distance = closest / DISTANCE_TOLERANCE;
+            point.max_distance = furthest * DISTANCE_TOLERANCE;
+        }
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Matrix3;
+
+    fn test_frame(num_keypoints: usize, seed: u8) -> Frame {
+        Frame {
+            width: 640,
+            height: 480,
+            blurred_image: vec![],
+            keypoints: (0..num_keypoints)
+                .map(|i| KeyPoint {
+                    x: i as f32,
+                    y: seed as f32,
+                    orientation: 0.0,
+                })
+                .collect(),
+            descriptors: (0..num_keypoints)
+                .map(|i| Descriptor(vec![i as u8 ^ seed; 4]))
+                .collect(),
+        }
+    }
+
+    fn pose_at(x: f64) -> Pose {
+        (Matrix3::identity(), Vector3::new(-x, 0.0, 0.0))
+    }
+
+    // every observation a point lists is mirrored by the keyframe, and the other way around, the
+    // covisibility weights are the shared points and the spanning tree links go both ways
+    fn assert_consistent(map: &Map) {
+        for keyframe in map.keyframes() {
+            let mut expected = BTreeMap::new();
+            for point in keyframe.observed_map_points() {
+                for &(other, _) in &map.map_point(point).unwrap().observations {
+                    if other != keyframe.id {
+                        *expected.entry(other).or_insert(0) += 1;
+                    }
+                }
+            }
+            assert_eq!(keyframe.covisibility, expected);
+            if let Some(parent) = keyframe.parent {
+                assert!(map
+                    .keyframe(parent)
+                    .unwrap()
+                    .children
+                    .contains(&keyframe.id));
+            }
+            for &child in &keyframe.children {
+                assert_eq!(map.keyframe(child).unwrap().parent, Some(keyframe.id));
+            }
+        }
+        for point in map.map_points() {
+            assert!(!point.observations.is_empty());
+            for &(keyframe, index) in &point.observations {
+                assert_eq!(
+                    map.keyframe(keyframe).unwrap().map_points[index],
+                    Some(point.id)
+                );
+            }
+        }
+        for keyframe in map.keyframes() {
+            for (index, point) in keyframe.map_points.iter().enumerate() {
+                if let Some(point) = point {
+                    let observations = &map.map_point(*point).unwrap().observations;
+                    assert!(observations.contains(&(keyframe.id, index)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_observations() {
+        let mut map = Map::new();
+        let a = map.add_keyframe(pose_at(0.0), &test_frame(10, 0));
+        let b = map.add_keyframe(pose_at(1.0), &test_frame(10, 1));
+        let point = map.add_map_point(Vector3::new(0.5, 0.0, 2.0));
+        map.add_observation(point, a, 3);
+        map.add_observation(point, b, 4);
+        assert_consistent(&map);
+
+        let map_point = map.map_point(point).unwrap();
+        assert_eq!(map_point.observations, vec![(a, 3), (b, 4)]);
+        // seen from both sides, so straight ahead on average
+        assert!((map_point.viewing_direction - Vector3::z()).norm() < 1e-9);
+        let distance = (0.25_f64 + 4.0).sqrt();
+        assert!((map_point.min_distance - distance / DISTANCE_TOLERANCE).abs() < 1e-9);
+        assert!(map_point.is_in_range(&Vector3::new(0.5, 0.0, 0.0)));
+        assert!(!map_point.is_in_range(&Vector3::new(0.5, 0.0, 100.0)));
+
+        // a keypoint can only be one point, and a keyframe sees a point only once
+        map.add_observation(point, a, 5);
+        assert_eq!(map.keyframe(a).unwrap().map_points[3], None);
+        assert_eq!(map.map_point(point).unwrap().observations.len(), 2);
+        assert_consistent(&map);
+
+        map.remove_observation(point, a);
+        map.remove_observation(point, b);
+        assert_eq!(map.num_map_points(), 0);
+        assert_consistent(&map);
+    }
+
+    #[test]
+    fn test_representative_descriptor() {
+        let mut map = Map::new();
+        let frames = [
+            Descriptor(vec![0b0000_0001]),
+            Descriptor(vec![0b0000_0011]),
+            Descriptor(vec![0b0000_0111]),
+            Descriptor(vec![0b1111_0000]),
+        ];
+        let point = map.add_map_point(Vector3::new(0.0, 0.0, 1.0));
+        for descriptor in frames {
+            let mut frame = test_frame(1, 0);
+            frame.descriptors = vec![descriptor];
+            let keyframe = map.add_keyframe(pose_at(0.0), &frame);
+            map.add_observation(point, keyframe, 0);
+        }
+        // the one closest to the others, not the outlier
+        assert_eq!(
+            map.map_point(point).unwrap().descriptor.0,
+            vec![0b0000_0011]
+        );
+    }
+
+    #[test]
+    fn test_remove_keyframe_and_point() {
+        let mut map = Map::new();
+        let a = map.add_keyframe(pose_at(0.0), &test_frame(10, 0));
+        let b = map.add_keyframe(pose_at(1.0), &test_frame(10, 1));
+        let shared = map.add_map_point(Vector3::new(0.0, 0.0, 5.0));
+        let only_b = map.add_map_point(Vector3::new(1.0, 0.0, 5.0));
+        map.add_observation(shared, a, 0);
+        map.add_observation(shared, b, 0);
+        map.add_observation(only_b, b, 1);
+
+        map.remove_keyframe(b);
+        assert_eq!(map.num_keyframes(), 1);
+        assert!(map.map_point(only_b).is_none());
+        assert_eq!(map.map_point(shared).unwrap().observations, vec![(a, 0)]);
+        assert_consistent(&map);
+
+        map.remove_map_point(shared);
+        assert_eq!(map.keyframe(a).unwrap().map_points[0], None);
+        assert_eq!(map.num_map_points(), 0);
+    }
+
+    #[test]
+    fn test_merge_map_points() {
+        let mut map = Map::new();
+        let a = map.add_keyframe(pose_at(0.0), &test_frame(10, 0));
+        let b = map.add_keyframe(pose_at(1.0), &test_frame(10, 1));
+        let c = map.add_keyframe(pose_at(2.0), &test_frame(10, 2));
+        let kept = map.add_map_point(Vector3::new(1.0, 0.0, 5.0));
+        let replaced = map.add_map_point(Vector3::new(1.0, 0.0, 5.01));
+        map.add_observation(kept, a, 0);
+        map.add_observation(kept, b, 0);
+        map.add_observation(replaced, b, 1);
+        map.add_observation(replaced, c, 2);
+
+        map.increase_visible(replaced);
+        map.merge_map_points(kept, replaced);
+        assert!(map.map_point(replaced).is_none());
+        // both were looked for as many times as the two of them were
+        assert_eq!(map.map_point(kept).unwrap().visible, 3);
+        assert_eq!(map.map_point(kept).unwrap().found_ratio(), 2.0 / 3.0);
+        // b already saw the kept point, so only c's observation moves over
+        assert_eq!(
+            map.map_point(kept).unwrap().observations,
+            vec![(a, 0), (b, 0), (c, 2)]
+        );
+        assert_eq!(map.keyframe(b).unwrap().map_points[1], None);
+        assert_consistent(&map);
+    }
+
+    // A keyframe at each x seeing `num_points` points in common with the next one.
+    fn chain(map: &mut Map, num_points: &[usize]) -> Vec<KeyFrameId> {
+        let mut keyframes = vec![map.add_keyframe(pose_at(0.0), &test_frame(200, 0))];
+        for (i, &n) in num_points.iter().enumerate() {
+            let keyframe = map.add_keyframe(pose_at(i as f64 + 1.0), &test_frame(200, 0));
+            for index in 0..n {
+                let point = map.add_map_point(Vector3::new(i as f64, index as f64, 5.0));
+                map.add_observation(point, keyframes[i], 100 + index);
+                map.add_observation(point, keyframe, index);
+            }
+            map.attach_keyframe(keyframe);
+            keyframes.push(keyframe);
+        }
+        keyframes
+    }
+
+    #[test]
+    fn test_covisibility() {
+        let mut map = Map::new();
+        let k = chain(&mut map, &[20, 30]);
+        // a point everyone sees links the first and last keyframes too
+        let point = map.add_map_point(Vector3::new(0.0, 0.0, 5.0));
+        for &keyframe in &k {
+            map.add_observation(point, keyframe, 199);
+        }
+        assert_consistent(&map);
+        assert_eq!(map.keyframe(k[1]).unwrap().covisibility[&k[0]], 21);
+        assert_eq!(
+            map.keyframe(k[1]).unwrap().covisible_keyframes(1),
+            vec![k[2], k[0]]
+        );
+        assert_eq!(
+            map.keyframe(k[1]).unwrap().covisible_keyframes(25),
+            vec![k[2]]
+        );
+        assert_eq!(map.keyframe(k[0]).unwrap().covisibility[&k[2]], 1);
+
+        map.remove_observation(point, k[1]);
+        assert_eq!(map.keyframe(k[1]).unwrap().covisibility[&k[0]], 20);
+        map.remove_map_point(point);
+        assert!(!map.keyframe(k[0]).unwrap().covisibility.contains_key(&k[2]));
+        assert_consistent(&map);
+
+        // merging a point k[0] sees into one k[2] sees connects them
+        let a = map.keyframe(k[0]).unwrap().map_points[100].unwrap();
+        let b = map.keyframe(k[2]).unwrap().map_points[0].unwrap();
+        map.merge_map_points(a, b);
+        assert_eq!(map.keyframe(k[0]).unwrap().covisibility[&k[2]], 1);
+        assert_consistent(&map);
+
+        map.remove_keyframe(k[1]);
+        assert_eq!(
+            map.keyframe(k[0]).unwrap().covisibility,
+            BTreeMap::from([(k[2], 1)])
+        );
+        assert_consistent(&map);
+    }
+
+    // Adds `n` points seen by keypoints from `index_a` on in `a` and from `index_b` on in `b`.
+    fn share(
+        map: &mut Map,
+        (a, index_a): (KeyFrameId, usize),
+        (b, index_b): (KeyFrameId, usize),
+        n: usize,
+    ) {
+        for i in 0..n {
+            let point = map.add_map_point(Vector3::new(0.0, i as f64, 5.0));
+            map.add_observation(point, a, index_a + i);
+            map.add_observation(point, b, index_b + i);
+        }
+    }
+
+    #[test]
+    fn test_spanning_tree() {
+        let mut map = Map::new();
+        let mut k = chain(&mut map, &[20, 30]);
+        k.push(map.add_keyframe(pose_at(3.0), &test_frame(200, 0)));
+        share(&mut map, (k[1], 150), (k[3], 0), 40);
+        share(&mut map, (k[2], 150), (k[3], 40), 10);
+        share(&mut map, (k[0], 150), (k[3], 50), 5);
+        map.attach_keyframe(k[3]);
+        k.push(map.add_keyframe(pose_at(4.0), &test_frame(200, 0)));
+        share(&mut map, (k[2], 160), (k[4], 0), 10);
+        map.attach_keyframe(k[4]);
+
+        assert_eq!(map.keyframe(k[0]).unwrap().parent, None);
+        assert_eq!(map.keyframe(k[1]).unwrap().parent, Some(k[0]));
+        assert_eq!(map.keyframe(k[2]).unwrap().parent, Some(k[1]));
+        assert_eq!(map.keyframe(k[3]).unwrap().parent, Some(k[1]));
+        assert_eq!(map.keyframe(k[4]).unwrap().parent, Some(k[2]));
+        assert_consistent(&map);
+
+        // k[3] shares points with k[0], and then k[2] with k[3]
+        map.remove_keyframe(k[1]);
+        assert_eq!(map.keyframe(k[3]).unwrap().parent, Some(k[0]));
+        assert_eq!(map.keyframe(k[2]).unwrap().parent, Some(k[3]));
+        assert_consistent(&map);
+
+        // k[4] shares nothing with the rest, but stays in the tree
+        map.remove_keyframe(k[2]);
+        assert_eq!(map.keyframe(k[4]).unwrap().parent, Some(k[3]));
+        assert_consistent(&map);
+
+        // without the root, its child takes over
+        map.remove_keyframe(k[0]);
+        assert_eq!(map.keyframe(k[3]).unwrap().parent, None);
+        assert_eq!(map.keyframe(k[4]).unwrap().parent, Some(k[3]));
+        assert_consistent(&map);
+    }
+
+    #[test]
+    fn test_essential_graph() {
+        let mut map = Map::new();
+        let k = chain(&mut map, &[100, 30, 99]);
+        // strongly covisible with k[1] without being its child
+        for index in 100..200 {
+            let point = map.add_map_point(Vector3::new(9.0, index as f64, 5.0));
+            map.add_observation(point, k[1], index);
+            map.add_observation(point, k[3], index);
+        }
+        map.add_loop_edge(k[0], k[2]);
+
+        assert_eq!(
+            map.essential_graph_edges(),
+            vec![
+                (k[0], k[1]),
+                (k[0], k[2]),
+                (k[1], k[2]),
+                (k[1], k[3]),
+                (k[2], k[3])
+            ]
+        );
+        // a loop edge goes with its keyframe
+        map.remove_keyframe(k[2]);
+        assert!(map.keyframe(k[0]).unwrap().loop_edges.is_empty());
+        assert_consistent(&map);
+    }
+
+    #[test]
+    fn test_set_keyframe_pose() {
+        let mut map = Map::new();
+        let a = map.add_keyframe(pose_at(0.0), &test_frame(1, 0));
+        let point = map.add_map_point(Vector3::new(0.0, 0.0, 2.0));
+        map.add_observation(point, a, 0);
+        assert!((map.map_point(point).unwrap().viewing_direction - Vector3::z()).norm() < 1e-9);
+
+        // seen from the side now
+        map.set_keyframe_pose(a, pose_at(-2.0));
+        let direction = map.map_point(point).unwrap().viewing_direction;
+        assert!((direction - Vector3::new(1.0, 0.0, 1.0).normalize()).norm() < 1e-9);
+        assert_eq!(
+            map.keyframe(a).unwrap().camera_center(),
+            Vector3::new(-2.0, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn test_from_initial_map() {
+        let reference = test_frame(5, 0);
+        let current = test_frame(5, 1);
+        let initial = InitialMap {
+            observations: vec![
+                (reference.keypoints[1], current.keypoints[2]),
+                (reference.keypoints[3], current.keypoints[4]),
+            ],
+            reference,
+            current,
+            pose: pose_at(1.0),
+            points: vec![Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.5, 0.0, 1.0)],
+            parallax: 30.0,
+        };
+
+        let map = Map::from_initial_map(&initial);
+        assert_eq!(map.num_keyframes(), 2);
+        assert_eq!(map.num_map_points(), 2);
+        let second = map.map_points().nth(1).unwrap();
+        assert_eq!(second.position, Vector3::new(0.5, 0.0, 1.0));
+        assert_eq!(second.observations, vec![(0, 3), (1, 4)]);
+        assert_eq!(map.keyframe(1).unwrap().pose, pose_at(1.0));
+        assert_consistent(&map);
+    }
+}
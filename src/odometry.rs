@@ -0,0 +1,321 @@
+// Visual odometry: rather than looking at two images in isolation, follow the camera through a
+// stream of frames. Each new frame is compared with the one before it, and the motions found
+// between them are chained into a trajectory that starts where the first frame was taken.
+//
+// A single camera can't tell how far it moved, only in which direction, so every step of the
+// trajectory has unit length. The shape of the path is right, its scale drifts from step to step.
+
+use crate::common::*;
+use crate::frame::Frame;
+use crate::geometry::SE3;
+use crate::slam::{Slam, SlamResult};
+
+// How many pairs of frames are self-calibrated before the median of their estimates is kept.
+pub const CALIBRATION_ESTIMATES: usize = 10;
+
+// What happened to the camera with the latest frame.
+pub struct FrameResult {
+    pub timestamp: f64,
+    // the camera's pose after this frame, mapping points from the first camera's coordinates (the
+    // world) into this one's
+    pub pose: Pose,
+    // how the camera moved since the previous frame, if that could be worked out
+    pub relative_pose: Option<Pose>,
+    // false for the first frame and whenever the motion couldn't be trusted, in which case the
+    // pose stays where it was
+    pub tracked: bool,
+    // everything the two-view pipeline found between the previous frame and this one, there is
+    // nothing to compare the first frame with
+    pub slam: Option<SlamResult>,
+}
+
+// The intrinsics self-calibration finds from a single pair of frames can be far off, when the
+// motion between them says little about the focal length. So the estimates of many pairs are
+// collected, and only once there are enough of them is the one with the median focal length
+// settled on.
+pub struct CalibrationEstimates {
+    estimates: Vec<CameraIntrinsics>,
+    required: usize,
+    locked: Option<CameraIntrinsics>,
+}
+
+impl CalibrationEstimates {
+    pub fn new(required: usize) -> Self {
+        CalibrationEstimates {
+            estimates: vec![],
+            required: required.max(1),
+            locked: None,
+        }
+    }
+
+    // Adds the estimate from another pair of frames, unless the intrinsics are already settled.
+    pub fn add(&mut self, intrinsics: CameraIntrinsics) {
+        if self.locked.is_some() {
+            return;
+        }
+        self.estimates.push(intrinsics);
+        if self.estimates.len() >= self.required {
+            self.locked = self.median();
+        }
+    }
+
+    // The estimate with the median focal length of the ones so far.
+    pub fn median(&self) -> Option<CameraIntrinsics> {
+        let mut estimates = self.estimates.clone();
+        estimates.sort_by(|a, b| a.fx.total_cmp(&b.fx));
+        estimates.get(estimates.len() / 2).copied()
+    }
+
+    // The intrinsics settled on, once there were enough estimates.
+    pub fn locked(&self) -> Option<CameraIntrinsics> {
+        self.locked
+    }
+}
+
+pub struct VisualOdometry {
+    // kept with its features so the next frame only needs its own extracted
+    previous: Option<Frame>,
+    pose: SE3,
+    trajectory: Vec<(f64, Pose)>,
+    // applied to the `Slam` of every pair of frames before it runs, to pass on its settings
+    configure: fn(&mut Slam),
+    min_good_points: usize,
+    min_parallax: f64,
+    calibration: CalibrationEstimates,
+}
+
+impl VisualOdometry {
+    pub fn new() -> Self {
+        VisualOdometry {
+            previous: None,
+            pose: SE3::identity(),
+            trajectory: vec![],
+            configure: |_| {},
+            min_good_points: 20,
+            min_parallax: 0.5,
+            calibration: CalibrationEstimates::new(CALIBRATION_ESTIMATES),
+        }
+    }
+
+    pub fn set_configuration(&mut self, configure: fn(&mut Slam)) {
+        self.configure = configure;
+    }
+
+    // A motion is only trusted when at least this many matches triangulate in front of both
+    // cameras. Otherwise the frame is skipped and the camera is considered lost until the next.
+    pub fn set_min_good_points(&mut self, min_good_points: usize) {
+        self.min_good_points = min_good_points;
+    }
+
+    // Below this parallax in degrees the camera has barely moved (or only turned), the direction
+    // of the translation is mostly noise, so only the rotation is applied.
+    pub fn set_min_parallax(&mut self, min_parallax: f64) {
+        self.min_parallax = min_parallax;
+    }
+
+    // The camera's current pose, mapping world points into the camera.
+    pub fn pose(&self) -> Pose {
+        self.pose.to_pose()
+    }
+
+    // Where the camera currently is in the world, its center -Rᵀ·t.
+    pub fn position(&self) -> nalgebra::Vector3<f64> {
+        self.pose.inverse().translation
+    }
+
+    // The timestamp and pose of every frame processed so far.
+    pub fn trajectory(&self) -> &[(f64, Pose)] {
+        &self.trajectory
+    }
+
+    // The last frame processed, with its features, which the next one will be compared with.
+    pub fn latest_frame(&self) -> Option<&Frame> {
+        self.previous.as_ref()
+    }
+
+    // The intrinsics self-calibration settled on, which are kept for all the following frames.
+    // Until then every pair of frames is calibrated on its own.
+    pub fn estimated_intrinsics(&self) -> Option<CameraIntrinsics> {
+        self.calibration.locked()
+    }
+
+    // Starts over, the next frame becomes the first one of a new trajectory.
+    pub fn reset(&mut self) {
+        self.previous = None;
+        self.pose = SE3::identity();
+        self.trajectory.clear();
+    }
+
+    pub fn process_frame(&mut self, image: Image, timestamp: f64) -> FrameResult {
+        let frame = Frame::new(image);
+        let slam_result = match &self.previous {
+            // frames of a different size can't be compared, so treat it as a fresh start
+            Some(previous) if previous.width == frame.width && previous.height == frame.height => {
+                let mut slam = Slam::new(previous, &frame);
+                (self.configure)(&mut slam);
+                if let Some(intrinsics) = self.calibration.locked() {
+                    slam.set_intrinsics(intrinsics);
+                    slam.set_self_calibration(false);
+                }
+                let result = slam.calculate_pose();
+                // only set when the configuration asked for self-calibration
+                if let Some(intrinsics) = slam.estimated_intrinsics() {
+                    self.calibration.add(intrinsics);
+                }
+                Some(result)
+            }
+            _ => None,
+        };
+        self.previous = Some(frame);
+
+        let relative_pose = slam_result.as_ref().and_then(|result| {
+            self.accept_motion(result.pose, result.num_good_points, result.parallax)
+        });
+        self.advance(timestamp, relative_pose);
+
+        FrameResult {
+            timestamp,
+            pose: self.pose(),
+            relative_pose,
+            tracked: relative_pose.is_some(),
+            slam: slam_result,
+        }
+    }
+
+    // Decides whether a motion found between two frames can be trusted, and what part of it.
+    fn accept_motion(
+        &self,
+        pose: Option<Pose>,
+        num_good_points: usize,
+        parallax: f64,
+    ) -> Option<Pose> {
+        let (rotation, translation) = pose?;
+        if num_good_points < self.min_good_points {
+            return None;
+        }
+        if parallax < self.min_parallax {
+            return Some((rotation, nalgebra::Vector3::zeros()));
+        }
+        Some((rotation, translation.normalize()))
+    }
+
+    // The relative pose maps points from the previous camera into the new one, so composing it on
+    // the left of the previous pose gives the new camera's pose in the world.
+    fn advance(&mut self, timestamp: f64, relative_pose: Option<Pose>) {
+        if let Some(relative_pose) = relative_pose {
+            self.pose = SE3::from_pose(&relative_pose) * self.pose;
+        }
+        self.trajectory.push((timestamp, self.pose()));
+    }
+}
+
+impl Default for VisualOdometry {
+    fn default() -> Self {
+        VisualOdometry::new()
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::*;
+    use nalgebra::{Matrix3, Vector3};
+
+    #[test]
+    fn test_chaining_motions() {
+        let mut odometry = VisualOdometry::new();
+        let step = (small_rotation(), Vector3::new(0.6, -0.3, 0.2).normalize());
+
+        odometry.advance(0.0, None);
+        for i in 1..=3 {
+            let motion = odometry.accept_motion(Some(step), 50, 5.0);
+            odometry.advance(i as f64, motion);
+        }
+
+        // three steps of the same motion, each one composed on the left
+        let expected = SE3::from_pose(&step) * SE3::from_pose(&step) * SE3::from_pose(&step);
+        let (rotation, translation) = odometry.pose();
+        assert!((rotation - expected.rotation.matrix()).norm() < 1e-9);
+        assert!((translation - expected.translation).norm() < 1e-9);
+
+        let trajectory = odometry.trajectory();
+        assert_eq!(trajectory.len(), 4);
+        assert_eq!(trajectory[0].1 .0, Matrix3::identity());
+        assert_eq!(trajectory[3].0, 3.0);
+
+        // the camera center is where the world origin is seen from
+        let center = odometry.position();
+        assert!((rotation * center + translation).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_accept_motion() {
+        let odometry = VisualOdometry::new();
+        let step = (small_rotation(), Vector3::new(0.0, 0.0, 2.0));
+
+        // too few points to trust
+        assert_eq!(odometry.accept_motion(Some(step), 5, 5.0), None);
+        assert_eq!(odometry.accept_motion(None, 100, 5.0), None);
+
+        // the translation is made unit length
+        let (_, translation) = odometry.accept_motion(Some(step), 50, 5.0).unwrap();
+        assert_eq!(translation, Vector3::new(0.0, 0.0, 1.0));
+
+        // barely any parallax, only the rotation is kept
+        let (rotation, translation) = odometry.accept_motion(Some(step), 50, 0.1).unwrap();
+        assert_eq!(rotation, step.0);
+        assert_eq!(translation, Vector3::zeros());
+    }
+
+    #[test]
+    fn test_calibration_estimates() {
+        let estimate = |focal| CameraIntrinsics::new(focal, focal, 320.0, 240.0, 0.0).unwrap();
+        let mut calibration = CalibrationEstimates::new(5);
+        assert!(calibration.median().is_none());
+
+        // an outlier among the first estimates doesn't decide the focal length
+        for focal in [2000.0, 510.0, 490.0, 505.0] {
+            calibration.add(estimate(focal));
+            assert!(calibration.locked().is_none());
+        }
+        assert_eq!(calibration.median().unwrap().fx, 510.0);
+        calibration.add(estimate(480.0));
+        assert_eq!(calibration.locked().unwrap().fx, 505.0);
+
+        // once settled, later estimates are ignored
+        calibration.add(estimate(100.0));
+        calibration.add(estimate(100.0));
+        assert_eq!(calibration.locked().unwrap().fx, 505.0);
+    }
+
+    #[test]
+    fn test_process_frame_without_features() {
+        let data = vec![128; 32 * 24 * 4];
+        let image = Image {
+            data: &data,
+            width: 32,
+            height: 24,
+        };
+
+        let mut odometry = VisualOdometry::new();
+        let first = odometry.process_frame(image, 0.0);
+        assert!(!first.tracked);
+        assert!(first.slam.is_none());
+        assert_eq!(first.pose.0, Matrix3::identity());
+
+        // a flat grey image has nothing to match, so the camera stays put
+        let second = odometry.process_frame(image, 0.1);
+        assert!(!second.tracked);
+        assert!(second.slam.is_some());
+        assert_eq!(second.pose.1, Vector3::zeros());
+        assert_eq!(odometry.trajectory().len(), 2);
+
+        odometry.reset();
+        assert!(odometry.trajectory().is_empty());
+        assert!(odometry.process_frame(image, 0.2).slam.is_none());
+    }
+}
@@ -30,17 +30,17 @@ pub fn greyscale_gaussian_blur(
     let mut kernel = vec![0f32; kernel_size];
     let mut kernel_sum = 0f32;
 
-    for i in 0..kernel_size {
+    for (i, k) in kernel.iter_mut().enumerate() {
         let x = i as f32 - half_kernel as f32;
         let value =
             (-x * x / (2.0 * blur_radius * blur_radius)).exp() / (blur_radius * (2.0 * PI).sqrt());
-        kernel[i] = value;
+        *k = value;
         kernel_sum += value;
     }
 
     // Normalize the kernel
-    for i in 0..kernel_size {
-        kernel[i] /= kernel_sum;
+    for k in kernel.iter_mut() {
+        *k /= kernel_sum;
     }
 
     let mut output = vec![0u8; img.len()];
@@ -50,10 +50,10 @@ pub fn greyscale_gaussian_blur(
     for y in 0..height {
         for x in 0..width {
             let mut sum = 0.0;
-            for i in 0..kernel_size {
+            for (i, k) in kernel.iter().enumerate() {
                 let index = (x as i32 - half_kernel + i as i32).clamp(0, width as i32 - 1) as usize
                     + y * width;
-                sum += kernel[i] * img[index] as f32;
+                sum += k * img[index] as f32;
             }
             buffer[x + y * width] = sum;
         }
@@ -63,11 +63,11 @@ pub fn greyscale_gaussian_blur(
     for y in 0..height {
         for x in 0..width {
             let mut sum = 0.0;
-            for i in 0..kernel_size {
+            for (i, k) in kernel.iter().enumerate() {
                 let index = x
                     + (y as i32 - half_kernel + i as i32).clamp(0, height as i32 - 1) as usize
                         * width;
-                sum += kernel[i] * buffer[index];
+                sum += k * buffer[index];
             }
             output[x + y * width] = sum.round() as u8;
         }
@@ -179,7 +179,7 @@ mod tests {
             threshold_for_intensity_difference,
             needed_consecutive_intensity_differences,
         );
-        assert_eq!(is_corner, true);
+        assert!(is_corner);
 
         // Test with a simple spiral pattern where the center pixel is not a corner
         let circle: SpiralIntensity = [10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10];
@@ -192,7 +192,7 @@ mod tests {
             threshold_for_intensity_difference,
             needed_consecutive_intensity_differences,
         );
-        assert_eq!(is_corner, false);
+        assert!(!is_corner);
 
         // Test with a more complex spiral pattern where the center pixel is a corner
         let circle: SpiralIntensity = [10, 10, 100, 10, 10, 10, 200, 10, 10, 10, 10, 10];
@@ -205,7 +205,7 @@ mod tests {
             threshold_for_intensity_difference,
             needed_consecutive_intensity_differences,
         );
-        assert_eq!(is_corner, true);
+        assert!(is_corner);
 
         // Test with a more complex spiral pattern where the center pixel is a corner
         // notice we have multiple consecutive intensity differences that are greater than the threshold
@@ -219,7 +219,7 @@ mod tests {
             threshold_for_intensity_difference,
             needed_consecutive_intensity_differences,
         );
-        assert_eq!(is_corner, true);
+        assert!(is_corner);
     }
 
     #[test]
@@ -276,7 +276,10 @@ mod tests {
 
         let keypoints_with_orientation = compute_orientations(&img, 9, &keypoints);
         assert_eq!(keypoints_with_orientation.len(), 2);
-        assert_eq!(keypoints_with_orientation[0].orientation, 0.7853982);
+        assert_eq!(
+            keypoints_with_orientation[0].orientation,
+            std::f32::consts::FRAC_PI_4
+        );
         // 0.7853982 is the angle of the vector (1, 1)
         assert_eq!(keypoints_with_orientation[1].orientation, -2.3561945);
         // -2.3561945 is the angle of the vector (-1, 1)
@@ -4,12 +4,15 @@ use crate::{
 };
 use std::iter;
 
+// A pair of offsets from the keypoint whose intensities get compared.
+pub type SamplePair = ((f32, f32), (f32, f32));
+
 pub fn compute_brief_descriptors(
     image: &[u8],
     width: u32,
     height: u32,
     keypoints: &[KeyPoint],
-    sampling_pattern: &[((f32, f32), (f32, f32))],
+    sampling_pattern: &[SamplePair],
 ) -> Vec<Descriptor> {
     keypoints
         .iter()
@@ -21,7 +24,7 @@ pub fn generate_sampling_pattern(
     rng: &mut Rand,
     patch_size: usize,
     num_pairs: usize,
-) -> Vec<((f32, f32), (f32, f32))> {
+) -> Vec<SamplePair> {
     iter::repeat_with(|| {
         let x1 = rng.gen_range(-(patch_size as f32 / 2.0)..=(patch_size as f32 / 2.0));
         let y1 = rng.gen_range(-(patch_size as f32 / 2.0)..=(patch_size as f32 / 2.0));
@@ -38,7 +41,7 @@ fn compute_descriptor(
     width: u32,
     height: u32,
     keypoint: &KeyPoint,
-    sampling_pattern: &[((f32, f32), (f32, f32))],
+    sampling_pattern: &[SamplePair],
 ) -> Descriptor {
     let mut descriptor = Vec::new();
     let mut bit_index = 0;
@@ -49,20 +52,12 @@ fn compute_descriptor(
         let (x2_rotated, y2_rotated) = rotate_point(x2, y2, keypoint.orientation);
 
         let (x1_final, y1_final) = (
-            (keypoint.x as f32 + x1_rotated)
-                .min(width as f32 - 1.0)
-                .max(0.0) as u32,
-            (keypoint.y as f32 + y1_rotated)
-                .min(height as f32 - 1.0)
-                .max(0.0) as u32,
+            (keypoint.x + x1_rotated).min(width as f32 - 1.0).max(0.0) as u32,
+            (keypoint.y + y1_rotated).min(height as f32 - 1.0).max(0.0) as u32,
         );
         let (x2_final, y2_final) = (
-            (keypoint.x as f32 + x2_rotated)
-                .min(width as f32 - 1.0)
-                .max(0.0) as u32,
-            (keypoint.y as f32 + y2_rotated)
-                .min(height as f32 - 1.0)
-                .max(0.0) as u32,
+            (keypoint.x + x2_rotated).min(width as f32 - 1.0).max(0.0) as u32,
+            (keypoint.y + y2_rotated).min(height as f32 - 1.0).max(0.0) as u32,
         );
 
         let intensity1 = image[(y1_final * width + x1_final) as usize];
@@ -1,12 +1,19 @@
+use nalgebra::{Matrix3, Vector3};
+
 use crate::common::{Descriptor, KeyPoint};
 
+// A match with the Hamming distance between its descriptors.
+pub type ScoredMatch = (usize, (KeyPoint, KeyPoint));
+
+// Matches are returned sorted from the most to the least similar descriptors, so the most
+// trustworthy come first.
 pub fn match_features(
     keypoints1: &[KeyPoint],
     descriptors1: &[Descriptor],
     keypoints2: &[KeyPoint],
     descriptors2: &[Descriptor],
     max_hamming_distance: usize,
-) -> Vec<(KeyPoint, KeyPoint)> {
+) -> Vec<ScoredMatch> {
     let mut matches = Vec::new();
 
     for (keypoint1, descriptor1) in keypoints1.iter().zip(descriptors1) {
@@ -22,14 +29,98 @@ pub fn match_features(
         }
 
         if let Some(matched_keypoint) = best_match {
-            matches.push((*keypoint1, *matched_keypoint));
+            matches.push((best_distance, (*keypoint1, *matched_keypoint)));
         }
     }
 
+    sort_by_distance(matches)
+}
+
+fn sort_by_distance(mut matches: Vec<ScoredMatch>) -> Vec<ScoredMatch> {
+    matches.sort_by_key(|(distance, _)| *distance);
     matches
 }
 
-fn hamming_distance(bytes1: &[u8], bytes2: &[u8]) -> usize {
+// Merges two lists of matches sorted by distance into one that still is, with the matches of the
+// first list ahead of equally distant ones of the second.
+pub fn merge_by_distance(first: Vec<ScoredMatch>, second: Vec<ScoredMatch>) -> Vec<ScoredMatch> {
+    let mut merged = Vec::with_capacity(first.len() + second.len());
+    let mut second = second.into_iter().peekable();
+    for scored in first {
+        while let Some(next) = second.next_if(|next| next.0 < scored.0) {
+            merged.push(next);
+        }
+        merged.push(scored);
+    }
+    merged.extend(second);
+    merged
+}
+
+// Once we have an estimate of the geometry between the two images we know that the match for a
+// keypoint in image A must lie on (or very near) its epipolar line in image B. The geometry is
+// given as a fundamental matrix so the lines are in pixels. This second pass only searches
+// keypoints inside a band of `band_width` pixels around that line, which lets us accept
+// descriptors that were too far apart to be trusted when searching the whole image. Each keypoint
+// in B is matched at most once, with the keypoint in A closest to it, and the keypoints already
+// matched in the first pass should be left out of both images. Like `match_features` the matches
+// are sorted from most to least similar.
+pub fn guided_match_features(
+    keypoints1: &[KeyPoint],
+    descriptors1: &[Descriptor],
+    keypoints2: &[KeyPoint],
+    descriptors2: &[Descriptor],
+    fundamental: &Matrix3<f64>,
+    band_width: f64,
+    max_hamming_distance: usize,
+) -> Vec<ScoredMatch> {
+    // the best candidate in B of every keypoint in A, as (distance, index in A, index in B)
+    let mut candidates = Vec::new();
+
+    for (index1, (keypoint1, descriptor1)) in keypoints1.iter().zip(descriptors1).enumerate() {
+        // the epipolar line in image B of a point in image A
+        let line = fundamental * Vector3::new(keypoint1.x as f64, keypoint1.y as f64, 1.0);
+        let line_norm = (line[0].powi(2) + line[1].powi(2)).sqrt();
+        if line_norm == 0.0 {
+            continue;
+        }
+
+        let mut best_distance = max_hamming_distance;
+        let mut best_match = None;
+
+        for (index2, (keypoint2, descriptor2)) in keypoints2.iter().zip(descriptors2).enumerate() {
+            let distance_to_line = line
+                .dot(&Vector3::new(keypoint2.x as f64, keypoint2.y as f64, 1.0))
+                .abs()
+                / line_norm;
+            if distance_to_line > band_width {
+                continue;
+            }
+
+            let distance = hamming_distance(&descriptor1.0, &descriptor2.0);
+            if distance <= best_distance {
+                best_distance = distance;
+                best_match = Some(index2);
+            }
+        }
+
+        if let Some(index2) = best_match {
+            candidates.push((best_distance, index1, index2));
+        }
+    }
+
+    // the most similar pairs get first pick of the keypoints in B
+    candidates.sort();
+    let mut taken = vec![false; keypoints2.len()];
+    let matches = candidates
+        .into_iter()
+        .filter(|&(_, _, index2)| !std::mem::replace(&mut taken[index2], true))
+        .map(|(distance, index1, index2)| (distance, (keypoints1[index1], keypoints2[index2])))
+        .collect();
+
+    sort_by_distance(matches)
+}
+
+pub fn hamming_distance(bytes1: &[u8], bytes2: &[u8]) -> usize {
     let a: u32 = bytes1
         .iter()
         .zip(bytes2)
@@ -44,7 +135,13 @@ fn hamming_distance(bytes1: &[u8], bytes2: &[u8]) -> usize {
 
 #[cfg(test)]
 mod tests {
+    use super::ScoredMatch;
     use crate::common::{Descriptor, KeyPoint};
+    use nalgebra::Matrix3;
+
+    fn without_distances(matches: Vec<ScoredMatch>) -> Vec<(KeyPoint, KeyPoint)> {
+        matches.into_iter().map(|(_, m)| m).collect()
+    }
 
     #[test]
     fn test_match_features() {
@@ -93,8 +190,13 @@ mod tests {
             // odd ball
             Descriptor(vec![0b01101000, 0b01000000, 0b00010000, 0b00000011]),
         ];
-        let matches =
-            super::match_features(&keypoints1, &descriptors1, &keypoints2, &descriptors2, 0);
+        let matches = without_distances(super::match_features(
+            &keypoints1,
+            &descriptors1,
+            &keypoints2,
+            &descriptors2,
+            0,
+        ));
         assert_eq!(matches.len(), 3);
         assert_eq!(matches[0].0, keypoints1[0]);
         assert_eq!(matches[0].1, keypoints2[0]);
@@ -102,8 +204,13 @@ mod tests {
         assert_eq!(matches[1].1, keypoints2[1]);
         assert_eq!(matches[2].0, keypoints1[2]);
         assert_eq!(matches[2].1, keypoints2[2]);
-        let matches =
-            super::match_features(&keypoints1, &descriptors1, &keypoints2, &descriptors2, 100);
+        let matches = without_distances(super::match_features(
+            &keypoints1,
+            &descriptors1,
+            &keypoints2,
+            &descriptors2,
+            100,
+        ));
         assert_eq!(matches.len(), 3);
         assert_eq!(matches[0].0, keypoints1[0]);
         assert_eq!(matches[0].1, keypoints2[0]);
@@ -113,6 +220,65 @@ mod tests {
         assert_eq!(matches[2].1, keypoints2[2]);
     }
 
+    #[test]
+    fn test_match_features_sorted_by_distance() {
+        let keypoint = |x: f32| KeyPoint {
+            x,
+            y: 0.0,
+            orientation: 0.0,
+        };
+        let keypoints1 = [keypoint(0.0), keypoint(1.0), keypoint(2.0)];
+        let descriptors1 = [
+            Descriptor(vec![0b00001111]),
+            Descriptor(vec![0b11110000]),
+            Descriptor(vec![0b10101010]),
+        ];
+        let keypoints2 = [keypoint(10.0), keypoint(11.0), keypoint(12.0)];
+        let descriptors2 = [
+            // 2 bits away from the first descriptor
+            Descriptor(vec![0b00111111]),
+            // identical to the second
+            Descriptor(vec![0b11110000]),
+            // 1 bit away from the third
+            Descriptor(vec![0b10101011]),
+        ];
+        let matches =
+            super::match_features(&keypoints1, &descriptors1, &keypoints2, &descriptors2, 2);
+        assert_eq!(
+            matches,
+            vec![
+                (0, (keypoints1[1], keypoints2[1])),
+                (1, (keypoints1[2], keypoints2[2])),
+                (2, (keypoints1[0], keypoints2[0])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_by_distance() {
+        let keypoint = |x: f32| KeyPoint {
+            x,
+            y: 0.0,
+            orientation: 0.0,
+        };
+        let scored = |distance: usize, x: f32| (distance, (keypoint(x), keypoint(x)));
+
+        let first = vec![scored(0, 0.0), scored(3, 1.0), scored(7, 2.0)];
+        let second = vec![scored(1, 10.0), scored(3, 11.0), scored(9, 12.0)];
+        let merged = super::merge_by_distance(first, second);
+        assert_eq!(
+            merged,
+            vec![
+                scored(0, 0.0),
+                scored(1, 10.0),
+                scored(3, 1.0),
+                scored(3, 11.0),
+                scored(7, 2.0),
+                scored(9, 12.0),
+            ]
+        );
+    }
+
     #[test]
     fn test_hamming_distance() {
         let bytes1 = [0b00000000, 0b00000000, 0b00000000, 0b00000000];
@@ -139,4 +305,79 @@ mod tests {
         let bytes2 = [0b00000000, 0b00000000, 0b00000000, 0b00000101];
         assert_eq!(super::hamming_distance(&bytes1, &bytes2), 2);
     }
+
+    #[test]
+    fn test_guided_match_features() {
+        let keypoint = |x: f32, y: f32| KeyPoint {
+            x,
+            y,
+            orientation: 0.0,
+        };
+        // pure horizontal motion, so every epipolar line is the row the keypoint is on
+        let fundamental = Matrix3::new(0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 1.0, 0.0);
+
+        let keypoints1 = [keypoint(10.0, 10.0), keypoint(20.0, 30.0)];
+        let descriptors1 = [
+            Descriptor(vec![0b00000000, 0b00000000]),
+            Descriptor(vec![0b11111111, 0b11111111]),
+        ];
+        let keypoints2 = [
+            // identical descriptor, but far away from the epipolar line
+            keypoint(15.0, 50.0),
+            // a few bits different, but on the epipolar line
+            keypoint(40.0, 11.0),
+            keypoint(25.0, 30.0),
+        ];
+        let descriptors2 = [
+            Descriptor(vec![0b00000000, 0b00000000]),
+            Descriptor(vec![0b00000111, 0b00000000]),
+            Descriptor(vec![0b11111111, 0b11111111]),
+        ];
+
+        let matches = without_distances(super::guided_match_features(
+            &keypoints1,
+            &descriptors1,
+            &keypoints2,
+            &descriptors2,
+            &fundamental,
+            2.0,
+            3,
+        ));
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0], (keypoints1[1], keypoints2[2]));
+        assert_eq!(matches[1], (keypoints1[0], keypoints2[1]));
+    }
+
+    #[test]
+    fn test_guided_match_features_one_to_one() {
+        let keypoint = |x: f32, y: f32| KeyPoint {
+            x,
+            y,
+            orientation: 0.0,
+        };
+        let fundamental = Matrix3::new(0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 1.0, 0.0);
+
+        // both keypoints in A are closest to the same keypoint in B, which goes to the closer one
+        let keypoints1 = [keypoint(10.0, 10.0), keypoint(20.0, 10.0)];
+        let descriptors1 = [
+            Descriptor(vec![0b00000001, 0b00000000]),
+            Descriptor(vec![0b00000000, 0b00000000]),
+        ];
+        let keypoints2 = [keypoint(30.0, 10.0), keypoint(40.0, 10.0)];
+        let descriptors2 = [
+            Descriptor(vec![0b00000000, 0b00000000]),
+            Descriptor(vec![0b11111111, 0b11111111]),
+        ];
+
+        let matches = without_distances(super::guided_match_features(
+            &keypoints1,
+            &descriptors1,
+            &keypoints2,
+            &descriptors2,
+            &fundamental,
+            2.0,
+            3,
+        ));
+        assert_eq!(matches, vec![(keypoints1[1], keypoints2[0])]);
+    }
 }
@@ -1,92 +1,852 @@
-use nalgebra::{DMatrix, Matrix3, Vector3, SVD};
+use nalgebra::{DMatrix, Matrix3, Vector3};
 
 use crate::common::*;
+use crate::five_point::five_point;
+use crate::polynomial::real_roots;
 use crate::rand::*;
+use crate::ransac::{self, Estimator, RansacOptions, RansacResult};
 
-fn keypoints_to_essential(keypoints: &[(KeyPoint, KeyPoint)]) -> Matrix3<f64> {
-    // Construct a matrix A from the keypoints
+// Which minimal solver RANSAC uses to generate essential matrix hypotheses.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum MinimalSolver {
+    // Needs only 5 matches per sample so far fewer iterations find an outlier free one, but
+    // yields up to 10 hypotheses per sample.
+    FivePoint,
+    // Needs 8 matches per sample and yields exactly one hypothesis.
+    EightPoint,
+}
+
+impl MinimalSolver {
+    fn sample_size(&self) -> usize {
+        match self {
+            MinimalSolver::FivePoint => 5,
+            MinimalSolver::EightPoint => 8,
+        }
+    }
+
+    fn solve(&self, sample: &[(KeyPoint, KeyPoint)]) -> Vec<Matrix3<f64>> {
+        match self {
+            MinimalSolver::FivePoint => five_point(sample),
+            MinimalSolver::EightPoint => vec![keypoints_to_essential(sample)],
+        }
+    }
+}
+
+// Pixel coordinates are in the hundreds while the constant column of the eight-point system is 1,
+// which makes the linear system badly conditioned. Hartley's fix is to move the centroid of the
+// points to the origin and scale them so their average distance from it is sqrt(2).
+pub fn hartley_normalization(points: &[(f64, f64)]) -> Matrix3<f64> {
+    let n = points.len() as f64;
+    let centroid_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let centroid_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+
+    let mean_distance = points
+        .iter()
+        .map(|p| ((p.0 - centroid_x).powi(2) + (p.1 - centroid_y).powi(2)).sqrt())
+        .sum::<f64>()
+        / n;
+    let scale = if mean_distance > 0.0 {
+        std::f64::consts::SQRT_2 / mean_distance
+    } else {
+        1.0
+    };
+
+    Matrix3::new(
+        scale,
+        0.0,
+        -scale * centroid_x,
+        0.0,
+        scale,
+        -scale * centroid_y,
+        0.0,
+        0.0,
+        1.0,
+    )
+}
+
+// The vector x minimizing |A·x| with |x| = 1, which is the right singular vector belonging to the
+// smallest singular value. With fewer rows than columns we pad A with zero rows so the SVD gives
+// us the full set of right singular vectors.
+pub fn null_vector(a: &DMatrix<f64>) -> Vec<f64> {
+    let a = if a.nrows() < a.ncols() {
+        a.clone().resize_vertically(a.ncols(), 0.0)
+    } else {
+        a.clone()
+    };
+    let svd = a.svd(false, true);
+    let v_t = svd.v_t.unwrap();
+    let smallest = svd.singular_values.imin();
+    v_t.row(smallest).iter().copied().collect()
+}
+
+// Builds the linear system A·m = 0 whose rows are p2ᵀ·M·p1 = 0 for each match (with M flattened
+// row by row into m), from Hartley normalized points. Returns the normalizing transforms too.
+fn normalized_epipolar_system(
+    keypoints: &[(KeyPoint, KeyPoint)],
+) -> (DMatrix<f64>, Matrix3<f64>, Matrix3<f64>) {
+    let points_1: Vec<(f64, f64)> = keypoints
+        .iter()
+        .map(|(p1, _)| (p1.x as f64, p1.y as f64))
+        .collect();
+    let points_2: Vec<(f64, f64)> = keypoints
+        .iter()
+        .map(|(_, p2)| (p2.x as f64, p2.y as f64))
+        .collect();
+    let t1 = hartley_normalization(&points_1);
+    let t2 = hartley_normalization(&points_2);
+
+    // Construct a matrix A from the normalized keypoints
     let mut a = DMatrix::<f64>::zeros(keypoints.len(), 9);
-    for (i, &(ref p1, ref p2)) in keypoints.iter().enumerate() {
-        a[(i, 0)] = p1.x as f64 * p2.x as f64;
-        a[(i, 1)] = p1.y as f64 * p2.x as f64;
-        a[(i, 2)] = p2.x as f64;
-        a[(i, 3)] = p1.x as f64 * p2.y as f64;
-        a[(i, 4)] = p1.y as f64 * p2.y as f64;
-        a[(i, 5)] = p2.y as f64;
-        a[(i, 6)] = p1.x as f64;
-        a[(i, 7)] = p1.y as f64;
+    for (i, (p1, p2)) in points_1.iter().zip(&points_2).enumerate() {
+        let p1 = t1 * Vector3::new(p1.0, p1.1, 1.0);
+        let p2 = t2 * Vector3::new(p2.0, p2.1, 1.0);
+        a[(i, 0)] = p1.x * p2.x;
+        a[(i, 1)] = p1.y * p2.x;
+        a[(i, 2)] = p2.x;
+        a[(i, 3)] = p1.x * p2.y;
+        a[(i, 4)] = p1.y * p2.y;
+        a[(i, 5)] = p2.y;
+        a[(i, 6)] = p1.x;
+        a[(i, 7)] = p1.y;
         a[(i, 8)] = 1.0;
     }
 
-    // Compute the singular value decomposition of A
-    let svd = a.svd(true, true);
-
-    // Extract the singular values and vectors
-    let v = svd.v_t.unwrap();
+    (a, t1, t2)
+}
 
-    // Extract the nullspace of A (i.e., the last column of V)
-    let e_vec = v.column(8);
+// Solves p2ᵀ·M·p1 = 0 for M in a least squares sense, with the points normalized first and the
+// result transformed back afterwards. No constraints are enforced on M.
+fn eight_point(keypoints: &[(KeyPoint, KeyPoint)]) -> Matrix3<f64> {
+    let (a, t1, t2) = normalized_epipolar_system(keypoints);
 
     // Reshape the nullspace vector into a 3x3 matrix
-    let essential_matrix = Matrix3::from_row_slice(&[
-        e_vec[0], e_vec[1], e_vec[2], e_vec[3], e_vec[4], e_vec[5], e_vec[6], e_vec[7], e_vec[8],
-    ]);
+    let m = Matrix3::from_row_slice(&null_vector(&a));
+
+    // Undo the normalization
+    t2.transpose() * m * t1
+}
+
+// A valid essential matrix has two equal singular values and a third of zero. Noise means the
+// linear solution never quite does, so we snap it to the closest matrix that does.
+fn enforce_essential_constraints(matrix: &Matrix3<f64>) -> Matrix3<f64> {
+    let svd = matrix.svd(true, true);
+    let u = svd.u.unwrap();
+    let v_t = svd.v_t.unwrap();
+
+    let mut singular_values = Vector3::new(1.0, 1.0, 1.0);
+    singular_values[svd.singular_values.imin()] = 0.0;
+
+    u * Matrix3::from_diagonal(&singular_values) * v_t
+}
+
+// The eight-point algorithm, expects keypoints in normalized image coordinates.
+fn keypoints_to_essential(keypoints: &[(KeyPoint, KeyPoint)]) -> Matrix3<f64> {
+    enforce_essential_constraints(&eight_point(keypoints))
+}
+
+// A fundamental matrix maps a point to its epipolar line, and all epipolar lines pass through the
+// epipole, so it must have rank 2. We zero the smallest singular value to get the closest such one.
+fn enforce_rank_2(matrix: &Matrix3<f64>) -> Matrix3<f64> {
+    let svd = matrix.svd(true, true);
+    let u = svd.u.unwrap();
+    let v_t = svd.v_t.unwrap();
+
+    let mut singular_values = svd.singular_values;
+    let smallest = singular_values.imin();
+    singular_values[smallest] = 0.0;
+
+    u * Matrix3::from_diagonal(&singular_values) * v_t
+}
+
+// The eight-point algorithm for a fundamental matrix, which works directly in pixel coordinates
+// and so doesn't need to know anything about the camera.
+pub fn keypoints_to_fundamental(keypoints: &[(KeyPoint, KeyPoint)]) -> Matrix3<f64> {
+    enforce_rank_2(&eight_point(keypoints))
+}
+
+// The seven-point algorithm finds the fundamental matrices consistent with just seven matches.
+// Seven equations leave a 2 dimensional space of solutions α·F1 + (1 - α)·F2, and requiring
+// det(F) = 0 gives a cubic in α with up to 3 real roots.
+fn seven_point(keypoints: &[(KeyPoint, KeyPoint)]) -> Vec<Matrix3<f64>> {
+    let (a, t1, t2) = normalized_epipolar_system(keypoints);
+
+    // padded with zero rows so the SVD gives us all 9 right singular vectors, the null space is
+    // the last two since singular values are sorted in descending order
+    let v_t = a.resize_vertically(9, 0.0).svd(false, true).v_t.unwrap();
+    let f1 = Matrix3::from_row_slice(v_t.row(7).transpose().as_slice());
+    let f2 = Matrix3::from_row_slice(v_t.row(8).transpose().as_slice());
+
+    // det(α·F1 + (1 - α)·F2) is a cubic c3·α³ + c2·α² + c1·α + c0, which we can recover from its
+    // value at four points
+    let det = |alpha: f64| (f1 * alpha + f2 * (1.0 - alpha)).determinant();
+    let (d0, d1, d_minus_1, d2) = (det(0.0), det(1.0), det(-1.0), det(2.0));
+    let c0 = d0;
+    let c2 = (d1 + d_minus_1) / 2.0 - c0;
+    let c3_plus_c1 = (d1 - d_minus_1) / 2.0;
+    let c3 = (d2 - 4.0 * c2 - c0 - 2.0 * c3_plus_c1) / 6.0;
+    let c1 = c3_plus_c1 - c3;
+
+    real_roots(&[c0, c1, c2, c3])
+        .into_iter()
+        .map(|alpha| {
+            let f = f1 * alpha + f2 * (1.0 - alpha);
+            // Undo the normalization
+            t2.transpose() * f * t1
+        })
+        .collect()
+}
+
+// Estimates the focal length of a camera from a fundamental matrix using Bougnoux's formula,
+// assuming both images come from the same camera with square pixels, no skew and the principal
+// point in the center of the image. This lets us bootstrap intrinsics for an unknown webcam.
+//
+// It fails (returns None) when the motion doesn't reveal the focal length, for example when the
+// optical axes of both views meet or the camera only rotates.
+pub fn focal_from_fundamental(
+    fundamental: &Matrix3<f64>,
+    width: usize,
+    height: usize,
+) -> Option<f64> {
+    // move the principal point to the origin
+    let to_pixels = Matrix3::new(
+        1.0,
+        0.0,
+        width as f64 / 2.0,
+        0.0,
+        1.0,
+        height as f64 / 2.0,
+        0.0,
+        0.0,
+        1.0,
+    );
+    let f = to_pixels.transpose() * fundamental * to_pixels;
+
+    // the epipole in image B is the left null vector of F
+    let svd = f.svd(true, false);
+    let u = svd.u.unwrap();
+    let e2: Vector3<f64> = u.column(svd.singular_values.imin()).into();
 
-    essential_matrix
+    let p = Vector3::new(0.0, 0.0, 1.0);
+    let i3 = Matrix3::from_diagonal(&Vector3::new(1.0, 1.0, 0.0));
+    let numerator = p.dot(&(e2.cross_matrix() * i3 * f * p)) * p.dot(&(f.transpose() * p));
+    let denominator = p.dot(&(e2.cross_matrix() * i3 * f * i3 * f.transpose() * p));
+
+    let focal_squared = -numerator / denominator;
+    if focal_squared.is_finite() && focal_squared > 0.0 {
+        Some(focal_squared.sqrt())
+    } else {
+        None
+    }
+}
+
+// How a match is measured against an essential or fundamental matrix M, for which every perfect
+// match satisfies p2ᵀ·M·p1 = 0. All three are distances in the units of the keypoints, so they can
+// be compared against the same inlier threshold.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum EpipolarResidual {
+    // A first order approximation of the reprojection error, cheap and nearly as accurate.
+    Sampson,
+    // The distance of each keypoint from the epipolar line of the other, summed.
+    Symmetric,
+    // How far the keypoints must move to become a perfect match, which is the reprojection error
+    // of the best triangulation of the match.
+    Reprojection,
+}
+
+impl EpipolarResidual {
+    pub fn residual(&self, matrix: &Matrix3<f64>, p1: &KeyPoint, p2: &KeyPoint) -> f64 {
+        match self {
+            EpipolarResidual::Sampson => sampson_error(matrix, p1, p2),
+            EpipolarResidual::Symmetric => symmetric_epipolar_error(matrix, p1, p2),
+            EpipolarResidual::Reprojection => {
+                let (c1, c2) = optimal_correction(matrix, p1, p2);
+                let dx1 = p1.x as f64 - c1.0;
+                let dy1 = p1.y as f64 - c1.1;
+                let dx2 = p2.x as f64 - c2.0;
+                let dy2 = p2.y as f64 - c2.1;
+                (dx1 * dx1 + dy1 * dy1 + dx2 * dx2 + dy2 * dy2).sqrt()
+            }
+        }
+    }
+}
+
+fn homogeneous(p: &KeyPoint) -> Vector3<f64> {
+    Vector3::new(p.x as f64, p.y as f64, 1.0)
 }
 
-fn choose_multiple_keypoints(
-    key_points: &Vec<(KeyPoint, KeyPoint)>,
-    num_keypoints: usize,
-    random: &mut Rand,
+// The epipolar line of p1 in the second image is M·p1, and that of p2 in the first image is Mᵀ·p2.
+fn symmetric_epipolar_error(matrix: &Matrix3<f64>, p1: &KeyPoint, p2: &KeyPoint) -> f64 {
+    let x1 = homogeneous(p1);
+    let x2 = homogeneous(p2);
+    let line2 = matrix * x1;
+    let line1 = matrix.transpose() * x2;
+    let algebraic = x2.dot(&line2).abs();
+
+    algebraic / (line1.x * line1.x + line1.y * line1.y).sqrt()
+        + algebraic / (line2.x * line2.x + line2.y * line2.y).sqrt()
+}
+
+// The algebraic error p2ᵀ·M·p1 divided by its gradient with respect to the four keypoint
+// coordinates, which is the distance to the nearest perfect match if the constraint were linear.
+// The sign tells which side of the epipolar lines the match is on, which a least squares
+// refinement needs to differentiate it. At the epipoles the gradient vanishes and it is 0.
+pub fn sampson_residual(matrix: &Matrix3<f64>, p1: &KeyPoint, p2: &KeyPoint) -> f64 {
+    let x1 = homogeneous(p1);
+    let x2 = homogeneous(p2);
+    let line2 = matrix * x1;
+    let line1 = matrix.transpose() * x2;
+    let gradient_squared =
+        line1.x * line1.x + line1.y * line1.y + line2.x * line2.x + line2.y * line2.y;
+    if gradient_squared == 0.0 {
+        return 0.0;
+    }
+
+    x2.dot(&line2) / gradient_squared.sqrt()
+}
+
+fn sampson_error(matrix: &Matrix3<f64>, p1: &KeyPoint, p2: &KeyPoint) -> f64 {
+    sampson_residual(matrix, p1, p2).abs()
+}
+
+// The closest pair of points to a match that satisfies p2ᵀ·M·p1 = 0 exactly. The constraint is
+// linearized around the current estimate and the smallest correction satisfying it is taken,
+// which is the Sampson correction on the first step and converges to the optimal one in a few
+// more (Kanatani's iterative optimal correction).
+pub fn optimal_correction(
+    matrix: &Matrix3<f64>,
+    p1: &KeyPoint,
+    p2: &KeyPoint,
+) -> ((f64, f64), (f64, f64)) {
+    let original = [p1.x as f64, p1.y as f64, p2.x as f64, p2.y as f64];
+    let mut correction = [0.0; 4];
+
+    for _ in 0..10 {
+        let x1 = Vector3::new(
+            original[0] - correction[0],
+            original[1] - correction[1],
+            1.0,
+        );
+        let x2 = Vector3::new(
+            original[2] - correction[2],
+            original[3] - correction[3],
+            1.0,
+        );
+        let line2 = matrix * x1;
+        let line1 = matrix.transpose() * x2;
+        let gradient = [line1.x, line1.y, line2.x, line2.y];
+        let gradient_squared: f64 = gradient.iter().map(|g| g * g).sum();
+        if gradient_squared == 0.0 {
+            break;
+        }
+
+        // the constraint at the original match, linearized around the current estimate
+        let error = x2.dot(&line2) + (0..4).map(|i| gradient[i] * correction[i]).sum::<f64>();
+        let step = error / gradient_squared;
+        let next = gradient.map(|g| g * step);
+
+        let change: f64 = (0..4).map(|i| (next[i] - correction[i]).powi(2)).sum();
+        correction = next;
+        if change < 1e-24 {
+            break;
+        }
+    }
+
+    (
+        (original[0] - correction[0], original[1] - correction[1]),
+        (original[2] - correction[2], original[3] - correction[3]),
+    )
+}
+
+// How well an essential matrix explains the matches, scored the way ORB-SLAM does so it can be
+// compared against `homography::score_homography`. Each match contributes more the closer it is to
+// its epipolar lines, measured in units of the expected noise `sigma`, and nothing in a direction
+// where it is an outlier.
+pub fn score_essential(
+    essential: &Matrix3<f64>,
+    key_points: &[(KeyPoint, KeyPoint)],
+    sigma: f64,
+) -> f64 {
+    // chi-squared thresholds at 95% for one degree of freedom, scored against two
+    let threshold = 3.841;
+    let threshold_score = 5.991;
+    let inv_sigma_squared = 1.0 / (sigma * sigma);
+
+    let squared_distance_to_line = |line: Vector3<f64>, point: &Vector3<f64>| {
+        line.dot(point).powi(2) / (line[0].powi(2) + line[1].powi(2))
+    };
+
+    key_points
+        .iter()
+        .map(|(p1, p2)| {
+            let p1 = Vector3::new(p1.x as f64, p1.y as f64, 1.0);
+            let p2 = Vector3::new(p2.x as f64, p2.y as f64, 1.0);
+
+            [
+                squared_distance_to_line(essential * p1, &p2) * inv_sigma_squared,
+                squared_distance_to_line(essential.transpose() * p2, &p1) * inv_sigma_squared,
+            ]
+            .iter()
+            .filter(|&&chi_squared| chi_squared <= threshold)
+            .map(|chi_squared| threshold_score - chi_squared)
+            .sum::<f64>()
+        })
+        .sum()
+}
+
+// The matches at the given indices.
+pub fn sample_matches(
+    matches: &[(KeyPoint, KeyPoint)],
+    indices: &[usize],
 ) -> Vec<(KeyPoint, KeyPoint)> {
-    key_points.choose_multiple(random, num_keypoints)
+    indices.iter().map(|&i| matches[i]).collect()
+}
+
+struct EssentialEstimator<'a> {
+    matches: &'a [(KeyPoint, KeyPoint)],
+    solver: MinimalSolver,
+    residual: EpipolarResidual,
+}
+
+impl<'a> Estimator for EssentialEstimator<'a> {
+    type Model = Matrix3<f64>;
+
+    fn sample_size(&self) -> usize {
+        self.solver.sample_size()
+    }
+
+    fn num_data(&self) -> usize {
+        self.matches.len()
+    }
+
+    fn estimate(&self, sample: &[usize]) -> Vec<Matrix3<f64>> {
+        self.solver.solve(&sample_matches(self.matches, sample))
+    }
+
+    fn residual(&self, essential: &Matrix3<f64>, index: usize) -> f64 {
+        let (p1, p2) = &self.matches[index];
+        self.residual.residual(essential, p1, p2)
+    }
+
+    fn refine(&self, inliers: &[usize]) -> Option<Matrix3<f64>> {
+        if inliers.len() < 8 {
+            return None;
+        }
+        Some(keypoints_to_essential(&sample_matches(
+            self.matches,
+            inliers,
+        )))
+    }
+}
+
+struct FundamentalEstimator<'a> {
+    matches: &'a [(KeyPoint, KeyPoint)],
+    residual: EpipolarResidual,
+}
+
+impl<'a> Estimator for FundamentalEstimator<'a> {
+    type Model = Matrix3<f64>;
+
+    fn sample_size(&self) -> usize {
+        7
+    }
+
+    fn num_data(&self) -> usize {
+        self.matches.len()
+    }
+
+    fn estimate(&self, sample: &[usize]) -> Vec<Matrix3<f64>> {
+        seven_point(&sample_matches(self.matches, sample))
+    }
+
+    fn residual(&self, fundamental: &Matrix3<f64>, index: usize) -> f64 {
+        let (p1, p2) = &self.matches[index];
+        self.residual.residual(fundamental, p1, p2)
+    }
+
+    fn refine(&self, inliers: &[usize]) -> Option<Matrix3<f64>> {
+        if inliers.len() < 8 {
+            return None;
+        }
+        Some(keypoints_to_fundamental(&sample_matches(
+            self.matches,
+            inliers,
+        )))
+    }
 }
 
 pub fn estimate_essential_ransac(
-    key_points: &Vec<(KeyPoint, KeyPoint)>,
-    num_iterations: usize,
-    inlier_threshold: f64,
+    key_points: &[(KeyPoint, KeyPoint)],
+    options: &RansacOptions,
+    solver: MinimalSolver,
+    residual: EpipolarResidual,
+    rnd: &mut Rand,
+) -> Option<RansacResult<Matrix3<f64>>> {
+    let estimator = EssentialEstimator {
+        matches: key_points,
+        solver,
+        residual,
+    };
+    if key_points.len() < estimator.sample_size() {
+        return None;
+    }
+    ransac::estimate(&estimator, options, rnd)
+}
+
+// Like `estimate_essential_ransac`, but for when we don't know the camera intrinsics. Works on
+// pixel coordinates, with hypotheses from the seven-point algorithm that local optimization refits
+// with the eight-point algorithm.
+pub fn estimate_fundamental_ransac(
+    key_points: &[(KeyPoint, KeyPoint)],
+    options: &RansacOptions,
+    residual: EpipolarResidual,
     rnd: &mut Rand,
-) -> Option<Matrix3<f64>> {
+) -> Option<RansacResult<Matrix3<f64>>> {
     if key_points.len() < 8 {
         return None;
     }
-    let mut best_essential_matrix = None;
-    let mut best_num_inliers = 0;
 
-    for _ in 0..num_iterations {
-        // Choose a random subset of keypoints
-        let subset = choose_multiple_keypoints(key_points, 9, rnd);
+    let estimator = FundamentalEstimator {
+        matches: key_points,
+        residual,
+    };
+    ransac::estimate(&estimator, options, rnd)
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::*;
+
+    #[test]
+    fn test_hartley_normalization() {
+        let points = [
+            (100.0, 200.0),
+            (300.0, 200.0),
+            (200.0, 100.0),
+            (200.0, 300.0),
+        ];
+        let t = hartley_normalization(&points);
+
+        let normalized: Vec<Vector3<f64>> = points
+            .iter()
+            .map(|p| t * Vector3::new(p.0, p.1, 1.0))
+            .collect();
+        let centroid = normalized.iter().sum::<Vector3<f64>>() / 4.0;
+        assert!(centroid.x.abs() < 1e-12);
+        assert!(centroid.y.abs() < 1e-12);
+
+        let mean_distance = normalized
+            .iter()
+            .map(|p| (p.x * p.x + p.y * p.y).sqrt())
+            .sum::<f64>()
+            / 4.0;
+        assert!((mean_distance - std::f64::consts::SQRT_2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_keypoints_to_essential() {
+        let mut rng = Rand::new_with_seed(1234);
+        let points = random_points(&mut rng, 8);
+        let rotation = small_rotation();
+        let translation = Vector3::new(1.0, 0.2, 0.1);
+        let matches = two_view_matches(&points, &rotation, &translation);
 
-        // Compute the essential matrix using the 8-point algorithm
-        let essential_matrix = keypoints_to_essential(&subset);
+        // exactly eight perfect matches should recover the ground truth
+        let essential = keypoints_to_essential(&matches);
+        assert_matrices_equal_up_to_scale(
+            &essential,
+            &essential_from_pose(&rotation, &translation),
+            1e-4,
+        );
 
-        // Compute the number of inliers that are consistent with the essential matrix
-        let mut num_inliers = 0;
-        for &(ref p1, ref p2) in key_points.iter() {
-            // Compute the epipolar lines corresponding to each keypoint
-            let l1 = essential_matrix * Vector3::new(p2.x as f64, p2.y as f64, 1.0);
-            let l2 = essential_matrix.transpose() * Vector3::new(p1.x as f64, p1.y as f64, 1.0);
+        // and it should be a valid essential matrix
+        let singular_values = essential.singular_values();
+        assert!((singular_values[0] - singular_values[1]).abs() < 1e-9);
+        assert!(singular_values[2].abs() < 1e-9);
+        assert!(essential.determinant().abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_keypoints_to_essential_many_points() {
+        let mut rng = Rand::new_with_seed(42);
+        let points = random_points(&mut rng, 100);
+        let rotation = small_rotation();
+        let translation = Vector3::new(-0.3, 0.5, 1.0);
+        let matches = two_view_matches(&points, &rotation, &translation);
+
+        let essential = keypoints_to_essential(&matches);
+        assert_matrices_equal_up_to_scale(
+            &essential,
+            &essential_from_pose(&rotation, &translation),
+            1e-4,
+        );
+        for (p1, p2) in matches.iter() {
+            let p1 = Vector3::new(p1.x as f64, p1.y as f64, 1.0);
+            let p2 = Vector3::new(p2.x as f64, p2.y as f64, 1.0);
+            assert!(p2.dot(&(essential * p1)).abs() < 1e-5);
+        }
+    }
 
-            // Compute the reprojection error for each key
-            let error1 = (l1.dot(&Vector3::new(p1.x as f64, p1.y as f64, 1.0))).abs()
-                / (l1[0].powi(2) + l1[1].powi(2)).sqrt();
-            let error2 = (l2.dot(&Vector3::new(p2.x as f64, p2.y as f64, 1.0))).abs()
-                / (l2[0].powi(2) + l2[1].powi(2)).sqrt();
+    #[test]
+    fn test_eight_point_in_pixels() {
+        // the same scene seen through a camera with pixel coordinates, where normalization
+        // matters most
+        let mut rng = Rand::new_with_seed(7);
+        let points = random_points(&mut rng, 50);
+        let rotation = small_rotation();
+        let translation = Vector3::new(1.0, 0.0, 0.2);
+        let intrinsics = CameraIntrinsics::new(500.0, 500.0, 320.0, 240.0, 0.0).unwrap();
+        let matches = pixel_matches(
+            &two_view_matches(&points, &rotation, &translation),
+            &intrinsics,
+        );
 
-            // If the sum of the errors is below the inlier threshold, count this as an inlier
-            if error1 + error2 < inlier_threshold {
-                num_inliers += 1;
+        let fundamental = eight_point(&matches);
+        let expected = intrinsics
+            .essential_to_fundamental(&essential_from_pose(&rotation, &translation))
+            .unwrap();
+        assert_matrices_equal_up_to_scale(&fundamental, &expected, 1e-3);
+    }
+
+    fn pixel_matches(
+        matches: &[(KeyPoint, KeyPoint)],
+        intrinsics: &CameraIntrinsics,
+    ) -> Vec<(KeyPoint, KeyPoint)> {
+        let k = intrinsics.matrix();
+        let to_pixels = |p: &KeyPoint| {
+            let p = k * Vector3::new(p.x as f64, p.y as f64, 1.0);
+            KeyPoint {
+                x: p.x as f32,
+                y: p.y as f32,
+                orientation: 0.0,
+            }
+        };
+        matches
+            .iter()
+            .map(|(p1, p2)| (to_pixels(p1), to_pixels(p2)))
+            .collect()
+    }
+
+    #[test]
+    fn test_keypoints_to_fundamental() {
+        let mut rng = Rand::new_with_seed(3);
+        let points = random_points(&mut rng, 30);
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.6, 0.3, 0.2);
+        let intrinsics = CameraIntrinsics::new(600.0, 550.0, 330.0, 250.0, 0.0).unwrap();
+        let matches = pixel_matches(
+            &two_view_matches(&points, &rotation, &translation),
+            &intrinsics,
+        );
+
+        let fundamental = keypoints_to_fundamental(&matches);
+        let expected = intrinsics
+            .essential_to_fundamental(&essential_from_pose(&rotation, &translation))
+            .unwrap();
+        assert_matrices_equal_up_to_scale(&fundamental, &expected, 1e-3);
+        assert_eq!(fundamental.rank(1e-9 * fundamental.norm()), 2);
+    }
+
+    #[test]
+    fn test_seven_point() {
+        let mut rng = Rand::new_with_seed(8);
+        for _ in 0..10 {
+            let points = random_points(&mut rng, 7);
+            let rotation = small_rotation();
+            let translation = Vector3::new(
+                rng.gen_range(-1.0..=1.0) as f64,
+                rng.gen_range(-1.0..=1.0) as f64,
+                rng.gen_range(-1.0..=1.0) as f64,
+            );
+            let intrinsics = CameraIntrinsics::new(500.0, 500.0, 320.0, 240.0, 0.0).unwrap();
+            let matches = pixel_matches(
+                &two_view_matches(&points, &rotation, &translation),
+                &intrinsics,
+            );
+
+            let solutions = seven_point(&matches);
+            assert!(!solutions.is_empty() && solutions.len() <= 3);
+
+            let expected = intrinsics
+                .essential_to_fundamental(&essential_from_pose(&rotation, &translation))
+                .unwrap();
+            let expected = expected / expected.norm();
+            assert!(solutions.iter().any(|f| {
+                let f = f / f.norm();
+                (f - expected).norm().min((f + expected).norm()) < 1e-3
+            }));
+
+            for f in solutions.iter() {
+                assert!(f.determinant().abs() < 1e-9 * f.norm().powi(3));
+            }
+        }
+    }
+
+    #[test]
+    fn test_focal_from_fundamental() {
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.7, 0.4, 0.3);
+        let intrinsics = CameraIntrinsics::new(520.0, 520.0, 320.0, 240.0, 0.0).unwrap();
+        let fundamental = intrinsics
+            .essential_to_fundamental(&essential_from_pose(&rotation, &translation))
+            .unwrap();
+
+        let focal = focal_from_fundamental(&fundamental, 640, 480).unwrap();
+        assert!((focal - 520.0).abs() < 1e-6);
+
+        // the scale of F doesn't matter
+        let focal = focal_from_fundamental(&(fundamental * -3.0), 640, 480).unwrap();
+        assert!((focal - 520.0).abs() < 1e-6);
+    }
+
+    const RESIDUALS: [EpipolarResidual; 3] = [
+        EpipolarResidual::Sampson,
+        EpipolarResidual::Symmetric,
+        EpipolarResidual::Reprojection,
+    ];
+
+    #[test]
+    fn test_epipolar_residuals() {
+        let mut rng = Rand::new_with_seed(9);
+        let points = random_points(&mut rng, 50);
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.5, -0.2, 0.1);
+        let matches = two_view_matches(&points, &rotation, &translation);
+        let essential = essential_from_pose(&rotation, &translation);
+
+        // perfect matches have no error however it is measured
+        for (p1, p2) in matches.iter() {
+            for residual in RESIDUALS.iter() {
+                assert!(residual.residual(&essential, p1, p2) < 1e-6);
             }
         }
 
-        // If this solution has more inliers than any previous solution, update the best estimate
-        if num_inliers > best_num_inliers {
-            best_essential_matrix = Some(essential_matrix);
-            best_num_inliers = num_inliers;
+        // the same in pixels with the fundamental matrix
+        let intrinsics = CameraIntrinsics::new(500.0, 500.0, 320.0, 240.0, 0.0).unwrap();
+        let fundamental = intrinsics.essential_to_fundamental(&essential).unwrap();
+        for (p1, p2) in pixel_matches(&matches, &intrinsics).iter() {
+            for residual in RESIDUALS.iter() {
+                assert!(residual.residual(&fundamental, p1, p2) < 1e-3);
+            }
+        }
+
+        // move the second keypoint off its epipolar line
+        let (p1, p2) = matches[0];
+        let line = essential * homogeneous(&p1);
+        let offset = 0.01;
+        let moved = KeyPoint {
+            x: p2.x + (offset * line.x / line.xy().norm()) as f32,
+            y: p2.y + (offset * line.y / line.xy().norm()) as f32,
+            orientation: 0.0,
+        };
+
+        // its distance from the epipolar line is exactly the offset, and the first keypoint is
+        // some distance from the epipolar line of the moved one as well
+        let symmetric = EpipolarResidual::Symmetric.residual(&essential, &p1, &moved);
+        assert!(symmetric > offset * 0.999);
+
+        // the original match is one way of correcting it, so the best correction moves the
+        // keypoints less than that, and Sampson's approximation is close to the best
+        let reprojection = EpipolarResidual::Reprojection.residual(&essential, &p1, &moved);
+        let sampson = EpipolarResidual::Sampson.residual(&essential, &p1, &moved);
+        assert!(reprojection > 0.0 && reprojection <= offset * 1.001);
+        assert!((sampson - reprojection).abs() < 0.01 * reprojection);
+
+        // and the corrected keypoints really are a perfect match
+        let (c1, c2) = optimal_correction(&essential, &p1, &moved);
+        let x1 = Vector3::new(c1.0, c1.1, 1.0);
+        let x2 = Vector3::new(c2.0, c2.1, 1.0);
+        assert!(x2.dot(&(essential * x1)).abs() < 1e-12);
+    }
+
+    // Replaces every fifth match with a random one.
+    fn add_outliers(rng: &mut Rand, matches: &mut [(KeyPoint, KeyPoint)], scale: f32) {
+        for i in (0..matches.len()).step_by(5) {
+            matches[i].1.x = rng.gen_range(-scale..=scale);
+            matches[i].1.y = rng.gen_range(-scale..=scale);
+        }
+    }
+
+    #[test]
+    fn test_estimate_essential_ransac() {
+        let mut rng = Rand::new_with_seed(10);
+        let points = random_points(&mut rng, 100);
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.5, -0.2, 0.1);
+        let mut matches = two_view_matches(&points, &rotation, &translation);
+        add_outliers(&mut rng, &mut matches, 0.5);
+        let expected = essential_from_pose(&rotation, &translation);
+
+        for solver in [MinimalSolver::FivePoint, MinimalSolver::EightPoint] {
+            for residual in RESIDUALS {
+                // stop as soon as we are sure enough to have drawn an outlier free sample
+                let options = RansacOptions {
+                    confidence: 0.999,
+                    min_iterations: 1,
+                    ..RansacOptions::new(1e-4, 200)
+                };
+                let result =
+                    estimate_essential_ransac(&matches, &options, solver, residual, &mut rng)
+                        .unwrap();
+                assert_matrices_equal_up_to_scale(&result.model, &expected, 1e-3);
+
+                // every inlier is found, and an outlier only by a lucky coincidence
+                for (i, &inlier) in result.inliers.iter().enumerate() {
+                    if i % 5 != 0 {
+                        assert!(inlier, "{:?} {:?} missed match {}", solver, residual, i);
+                    }
+                }
+                assert!(result.num_inliers >= 80 && result.num_inliers <= 82);
+            }
         }
+
+        // six matches are enough for the five-point solver but not for the eight-point one
+        let good = [&matches[1..5], &matches[6..8]].concat();
+        let options = RansacOptions::new(1e-4, 100);
+        let (five, eight) = (MinimalSolver::FivePoint, MinimalSolver::EightPoint);
+        let residual = EpipolarResidual::Sampson;
+        let result = estimate_essential_ransac(&good, &options, five, residual, &mut rng).unwrap();
+        assert_eq!(result.num_inliers, 6);
+        assert!(estimate_essential_ransac(&good, &options, eight, residual, &mut rng).is_none());
+        assert!(
+            estimate_essential_ransac(&good[..4], &options, five, residual, &mut rng).is_none()
+        );
     }
 
-    best_essential_matrix
+    #[test]
+    fn test_estimate_fundamental_ransac() {
+        let mut rng = Rand::new_with_seed(11);
+        let points = random_points(&mut rng, 100);
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.5, -0.2, 0.1);
+        let intrinsics = CameraIntrinsics::new(500.0, 500.0, 320.0, 240.0, 0.0).unwrap();
+        let mut matches = pixel_matches(
+            &two_view_matches(&points, &rotation, &translation),
+            &intrinsics,
+        );
+        add_outliers(&mut rng, &mut matches, 600.0);
+        let expected = intrinsics
+            .essential_to_fundamental(&essential_from_pose(&rotation, &translation))
+            .unwrap();
+
+        for residual in RESIDUALS {
+            let options = RansacOptions {
+                confidence: 0.999,
+                min_iterations: 1,
+                ..RansacOptions::new(0.1, 300)
+            };
+            let result =
+                estimate_fundamental_ransac(&matches, &options, residual, &mut rng).unwrap();
+            assert_matrices_equal_up_to_scale(&result.model, &expected, 1e-3);
+            for (i, &inlier) in result.inliers.iter().enumerate() {
+                if i % 5 != 0 {
+                    assert!(inlier, "{:?} missed match {}", residual, i);
+                }
+            }
+        }
+    }
 }
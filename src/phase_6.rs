@@ -1,20 +1,157 @@
 use nalgebra::{Matrix3, Vector3};
 
-pub fn decompose_essential_matrix(essential: Matrix3<f64>) -> (Matrix3<f64>, Vector3<f64>) {
-    // Compute the singular value decomposition of the essential matrix
+use crate::common::*;
+use crate::triangulation;
+
+// A relative pose together with how well the matches support it.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct PoseHypothesis {
+    pub rotation: Matrix3<f64>,
+    pub translation: Vector3<f64>,
+    // how many matches triangulate to a point in front of both cameras
+    pub num_good_points: usize,
+    // the median angle in degrees between the two rays that see each of those points, small
+    // angles mean the depths (and so the pose) are poorly constrained
+    pub parallax: f64,
+}
+
+// An essential matrix E = U·diag(1, 1, 0)·Vᵀ can come from four poses: the rotation is U·W·Vᵀ or
+// U·Wᵀ·Vᵀ and the translation is either sign of the last column of U. Only one of them puts the
+// scene in front of both cameras.
+pub fn essential_pose_candidates(essential: &Matrix3<f64>) -> [Pose; 4] {
     let svd = essential.svd(true, true);
+    let mut u = svd.u.unwrap();
+    let mut v_t = svd.v_t.unwrap();
 
-    // Extract the singular values and vectors
-    let u = svd.u.unwrap();
-    let v = svd.v_t.unwrap();
-    let s = svd.singular_values;
+    // E only fixes U and V up to sign, flip them so the rotations are proper (det = +1) and not
+    // reflections
+    if u.determinant() < 0.0 {
+        u = -u;
+    }
+    if v_t.determinant() < 0.0 {
+        v_t = -v_t;
+    }
 
-    // Compute the rotation matrix
     let w = Matrix3::new(0.0, -1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0);
-    let rotation = u * w * v.transpose();
-
-    // Compute the translation vector
+    let rotation_1 = u * w * v_t;
+    let rotation_2 = u * w.transpose() * v_t;
     let translation: Vector3<f64> = u.column(2).into();
 
-    (rotation, translation)
+    [
+        (rotation_1, translation),
+        (rotation_1, -translation),
+        (rotation_2, translation),
+        (rotation_2, -translation),
+    ]
+}
+
+// Triangulates every match with the pose, counting the points that end up in front of both
+// cameras and measuring the parallax they are seen with.
+pub fn evaluate_pose(
+    rotation: &Matrix3<f64>,
+    translation: &Vector3<f64>,
+    key_points: &[(KeyPoint, KeyPoint)],
+) -> PoseHypothesis {
+    let mut parallaxes: Vec<f64> = key_points
+        .iter()
+        .filter_map(|(p1, p2)| {
+            let point = triangulation::triangulate_linear(rotation, translation, p1, p2)?;
+            let depth_1 = point.z;
+            let depth_2 = (rotation * point + translation).z;
+            if depth_1 <= 0.0 || depth_2 <= 0.0 {
+                return None;
+            }
+            Some(triangulation::parallax(rotation, translation, &point))
+        })
+        .collect();
+
+    parallaxes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let parallax = if parallaxes.is_empty() {
+        0.0
+    } else {
+        parallaxes[parallaxes.len() / 2]
+    };
+
+    PoseHypothesis {
+        rotation: *rotation,
+        translation: *translation,
+        num_good_points: parallaxes.len(),
+        parallax,
+    }
+}
+
+// Picks the pose an essential matrix came from by checking which of its four candidates puts the
+// most matches in front of both cameras (the cheirality check). The matches should be inliers in
+// normalized image coordinates.
+pub fn decompose_essential_matrix(
+    essential: &Matrix3<f64>,
+    key_points: &[(KeyPoint, KeyPoint)],
+) -> Option<PoseHypothesis> {
+    essential_pose_candidates(essential)
+        .iter()
+        .map(|(rotation, translation)| evaluate_pose(rotation, translation, key_points))
+        .max_by_key(|hypothesis| hypothesis.num_good_points)
+        .filter(|hypothesis| hypothesis.num_good_points > 0)
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand::Rand;
+    use crate::synthetic::*;
+
+    #[test]
+    fn test_essential_pose_candidates() {
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.6, -0.3, 0.2).normalize();
+        let essential = essential_from_pose(&rotation, &translation);
+
+        let candidates = essential_pose_candidates(&essential);
+        for (candidate_rotation, candidate_translation) in candidates.iter() {
+            assert!((candidate_rotation.determinant() - 1.0).abs() < 1e-9);
+            assert!((candidate_translation.norm() - 1.0).abs() < 1e-9);
+            // every candidate explains the essential matrix up to scale
+            assert_matrices_equal_up_to_scale(
+                &essential_from_pose(candidate_rotation, candidate_translation),
+                &essential,
+                1e-9,
+            );
+        }
+
+        // the negated essential matrix is the same one
+        let negated = essential_pose_candidates(&-essential);
+        assert!(negated
+            .iter()
+            .all(|(r, _)| (r.determinant() - 1.0).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_decompose_essential_matrix() {
+        let mut rng = Rand::new_with_seed(12);
+        let points = random_points(&mut rng, 50);
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.6, -0.3, 0.2);
+        let matches = two_view_matches(&points, &rotation, &translation);
+        let essential = essential_from_pose(&rotation, &translation);
+
+        // the scale and sign of E don't change the answer
+        for scale in [1.0, -2.5] {
+            let pose = decompose_essential_matrix(&(essential * scale), &matches).unwrap();
+            assert!((pose.rotation - rotation).norm() < 1e-9);
+            assert!((pose.translation - translation.normalize()).norm() < 1e-9);
+            assert_eq!(pose.num_good_points, 50);
+            assert!(pose.parallax > 1.0 && pose.parallax < 20.0);
+        }
+
+        // the other three candidates put points behind a camera
+        for (r, t) in essential_pose_candidates(&essential).iter() {
+            let hypothesis = evaluate_pose(r, t, &matches);
+            let is_true_pose = (r - rotation).norm() < 1e-9 && t.dot(&translation) > 0.0;
+            assert_eq!(is_true_pose, hypothesis.num_good_points == 50);
+        }
+    }
 }
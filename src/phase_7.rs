@@ -0,0 +1,265 @@
+// The pose we get from decomposing the essential matrix is only as good as the minimal sample
+// (and the linear refit) it came from. Here it is polished by minimizing the Sampson error of all
+// the inliers with Levenberg–Marquardt.
+//
+// A pose from two views has 5 degrees of freedom: 3 for the rotation and 2 for the direction of
+// the translation, since its length can't be known. The rotation is updated by a small rotation
+// exp(ω)·R and the translation by moving it along the unit sphere, so the pose never stops being
+// a valid one.
+
+use nalgebra::{SMatrix, SVector, Vector3};
+
+use crate::common::*;
+use crate::geometry::SO3;
+use crate::phase_5;
+
+type Vector5 = SVector<f64, 5>;
+type Matrix5 = SMatrix<f64, 5, 5>;
+
+// How residuals are turned into a cost. The robust losses grow slower than the square for large
+// residuals, so a few bad matches can't drag the pose towards them.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Loss {
+    Squared,
+    // quadratic up to the given residual and linear after it
+    Huber(f64),
+    // grows only logarithmically past the given residual
+    Cauchy(f64),
+}
+
+impl Loss {
+    // The same loss with its scale multiplied, e.g. to convert it from pixels to normalized image
+    // coordinates.
+    pub fn scaled(&self, factor: f64) -> Loss {
+        match *self {
+            Loss::Squared => Loss::Squared,
+            Loss::Huber(delta) => Loss::Huber(delta * factor),
+            Loss::Cauchy(c) => Loss::Cauchy(c * factor),
+        }
+    }
+
+    pub fn cost(&self, residual: f64) -> f64 {
+        let squared = residual * residual;
+        match *self {
+            Loss::Squared => squared,
+            Loss::Huber(delta) => {
+                if residual.abs() <= delta {
+                    squared
+                } else {
+                    2.0 * delta * residual.abs() - delta * delta
+                }
+            }
+            Loss::Cauchy(c) => c * c * (1.0 + squared / (c * c)).ln(),
+        }
+    }
+
+    // The weight of a residual in the reweighted least squares problem, so each step minimizes
+    // the robust cost as if it were a weighted squared cost.
+    pub fn weight(&self, residual: f64) -> f64 {
+        match *self {
+            Loss::Squared => 1.0,
+            Loss::Huber(delta) => {
+                if residual.abs() <= delta {
+                    1.0
+                } else {
+                    delta / residual.abs()
+                }
+            }
+            Loss::Cauchy(c) => 1.0 / (1.0 + residual * residual / (c * c)),
+        }
+    }
+}
+
+fn residuals(pose: &Pose, key_points: &[(KeyPoint, KeyPoint)]) -> Vec<f64> {
+    let essential = pose.1.cross_matrix() * pose.0;
+    key_points
+        .iter()
+        .map(|(p1, p2)| phase_5::sampson_residual(&essential, p1, p2))
+        .collect()
+}
+
+fn total_cost(pose: &Pose, key_points: &[(KeyPoint, KeyPoint)], loss: Loss) -> f64 {
+    residuals(pose, key_points)
+        .iter()
+        .map(|&residual| loss.cost(residual))
+        .sum()
+}
+
+// Two directions perpendicular to t, which span the plane the translation can move in without
+// changing its length.
+fn tangent_basis(translation: &Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    // any axis not too close to t will do to start the cross products
+    let axis = if translation.x.abs() < 0.9 {
+        Vector3::x()
+    } else {
+        Vector3::y()
+    };
+    let b1 = translation.cross(&axis).normalize();
+    let b2 = translation.cross(&b1).normalize();
+    (b1, b2)
+}
+
+// Moves the pose by a step in its 5 degrees of freedom.
+fn apply_step(pose: &Pose, step: &Vector5) -> Pose {
+    let rotation = SO3::exp(&Vector3::new(step[0], step[1], step[2])).matrix() * pose.0;
+    let (b1, b2) = tangent_basis(&pose.1);
+    let translation = (pose.1 + b1 * step[3] + b2 * step[4]).normalize();
+    (rotation, translation)
+}
+
+// Levenberg–Marquardt on the Sampson errors of the matches, which should be inliers in
+// normalized image coordinates. The translation of the returned pose has unit length.
+pub fn refine_pose(
+    pose: &Pose,
+    key_points: &[(KeyPoint, KeyPoint)],
+    loss: Loss,
+    max_iterations: usize,
+) -> Pose {
+    let mut pose = (pose.0, pose.1.normalize());
+    // with fewer matches than degrees of freedom the pose isn't constrained
+    if key_points.len() < 5 {
+        return pose;
+    }
+
+    let mut cost = total_cost(&pose, key_points, loss);
+    let mut lambda = 1e-3;
+
+    for _ in 0..max_iterations {
+        let current = residuals(&pose, key_points);
+
+        // the Jacobian by central differences, one column per degree of freedom
+        let epsilon = 1e-7;
+        let mut jacobian = vec![Vector5::zeros(); key_points.len()];
+        for parameter in 0..5 {
+            let mut step = Vector5::zeros();
+            step[parameter] = epsilon;
+            let forward = residuals(&apply_step(&pose, &step), key_points);
+            let backward = residuals(&apply_step(&pose, &-step), key_points);
+            for (row, (f, b)) in jacobian.iter_mut().zip(forward.iter().zip(&backward)) {
+                row[parameter] = (f - b) / (2.0 * epsilon);
+            }
+        }
+
+        // the normal equations of the reweighted problem
+        let mut jtj = Matrix5::zeros();
+        let mut jtr = Vector5::zeros();
+        for (row, &residual) in jacobian.iter().zip(&current) {
+            let weight = loss.weight(residual);
+            jtj += row * row.transpose() * weight;
+            jtr += row * residual * weight;
+        }
+
+        // try steps until one lowers the cost, leaning more towards gradient descent each time
+        let mut improved = false;
+        while lambda < 1e10 {
+            let mut damped = jtj;
+            for i in 0..5 {
+                damped[(i, i)] += lambda * jtj[(i, i)].max(1e-12);
+            }
+            let step = match damped.lu().solve(&-jtr) {
+                Some(step) => step,
+                None => break,
+            };
+            let candidate = apply_step(&pose, &step);
+            let candidate_cost = total_cost(&candidate, key_points, loss);
+            if candidate_cost < cost {
+                let converged = cost - candidate_cost < 1e-12 * cost;
+                pose = candidate;
+                cost = candidate_cost;
+                lambda = (lambda / 10.0).max(1e-12);
+                improved = !converged;
+                break;
+            }
+            lambda *= 10.0;
+        }
+
+        if !improved {
+            break;
+        }
+    }
+
+    pose
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand::Rand;
+    use crate::synthetic::*;
+    use nalgebra::{Matrix3, Rotation3};
+
+    fn rotation_error(a: &Matrix3<f64>, b: &Matrix3<f64>) -> f64 {
+        Rotation3::from_matrix(&(a * b.transpose())).angle()
+    }
+
+    fn noisy_matches(rng: &mut Rand, pose: &Pose, noise: f32) -> Vec<(KeyPoint, KeyPoint)> {
+        let points = random_points(rng, 100);
+        let mut matches = two_view_matches(&points, &pose.0, &pose.1);
+        for (p1, p2) in matches.iter_mut() {
+            p1.x += rng.gen_range(-noise..=noise);
+            p1.y += rng.gen_range(-noise..=noise);
+            p2.x += rng.gen_range(-noise..=noise);
+            p2.y += rng.gen_range(-noise..=noise);
+        }
+        matches
+    }
+
+    #[test]
+    fn test_refine_pose() {
+        let mut rng = Rand::new_with_seed(14);
+        let truth = (small_rotation(), Vector3::new(0.6, -0.3, 0.2).normalize());
+        let matches = noisy_matches(&mut rng, &truth, 0.001);
+
+        // start a little off
+        let start = apply_step(&truth, &Vector5::new(0.02, -0.01, 0.015, 0.05, -0.03));
+        let refined = refine_pose(&start, &matches, Loss::Squared, 20);
+
+        assert!(rotation_error(&refined.0, &truth.0) < 0.1 * rotation_error(&start.0, &truth.0));
+        assert!((refined.1 - truth.1).norm() < 0.1 * (start.1 - truth.1).norm());
+        assert!((refined.1.norm() - 1.0).abs() < 1e-12);
+        assert!((refined.0.determinant() - 1.0).abs() < 1e-9);
+        assert!(
+            total_cost(&refined, &matches, Loss::Squared)
+                < total_cost(&start, &matches, Loss::Squared)
+        );
+    }
+
+    #[test]
+    fn test_refine_pose_with_outliers() {
+        let mut rng = Rand::new_with_seed(15);
+        let truth = (small_rotation(), Vector3::new(0.6, -0.3, 0.2).normalize());
+        let mut matches = noisy_matches(&mut rng, &truth, 0.0005);
+        for i in (0..matches.len()).step_by(10) {
+            matches[i].1.x += 0.05;
+        }
+
+        let start = apply_step(&truth, &Vector5::new(0.01, 0.01, -0.01, 0.03, 0.03));
+        let squared = refine_pose(&start, &matches, Loss::Squared, 20);
+        let huber = refine_pose(&start, &matches, Loss::Huber(0.002), 20);
+        let cauchy = refine_pose(&start, &matches, Loss::Cauchy(0.002), 20);
+
+        // the robust losses ignore the outliers the squared loss gets pulled around by
+        let squared_error = rotation_error(&squared.0, &truth.0);
+        assert!(rotation_error(&huber.0, &truth.0) < squared_error);
+        assert!(rotation_error(&cauchy.0, &truth.0) < squared_error);
+        assert!((cauchy.1 - truth.1).norm() < 0.01);
+    }
+
+    #[test]
+    fn test_losses() {
+        let huber = Loss::Huber(1.0);
+        assert_eq!(huber.cost(0.5), 0.25);
+        assert_eq!(huber.cost(3.0), 5.0);
+        assert_eq!(huber.weight(4.0), 0.25);
+
+        assert_eq!(Loss::Huber(2.0).scaled(0.5), huber);
+
+        let cauchy = Loss::Cauchy(1.0);
+        assert!((cauchy.cost(1.0) - 2.0_f64.ln()).abs() < 1e-12);
+        assert_eq!(cauchy.weight(1.0), 0.5);
+    }
+}
@@ -0,0 +1,507 @@
+// Perspective-n-Point: given 3D points we already know (say from triangulating an earlier pair
+// of frames) and where they show up in a new image, find the pose of the camera that took it.
+// Unlike the two-view problem the scale is fixed by the points, so the full translation comes out.
+//
+// Poses here map points from the world (the frame the 3D points are in) into the camera,
+// X_camera = R·X_world + t.
+
+use nalgebra::{Matrix3, Matrix6, SMatrix, SVector, Vector3, Vector6};
+
+use crate::common::*;
+use crate::geometry::SE3;
+use crate::polynomial::real_roots;
+use crate::rand::Rand;
+use crate::ransac::{self, Estimator, RansacOptions, RansacResult};
+
+// Which minimal solver RANSAC uses to generate pose hypotheses.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum PnpSolver {
+    // Needs only 3 points per sample, yielding up to 4 poses.
+    P3P,
+    // Needs 6 points per sample and yields one pose.
+    EPnP,
+}
+
+// The pose of the camera relative to three world points, from the directions it sees them in.
+// This is Grunert's method: the distances s1, s2 and s3 along the three rays must reproduce the
+// distances between the points, which by the law of cosines gives three equations that reduce to
+// a quartic in v = s3 / s1.
+pub fn p3p(points: &[Vector3<f64>; 3], bearings: &[Vector3<f64>; 3]) -> Vec<Pose> {
+    let j = bearings.map(|bearing| bearing.normalize());
+
+    // the sides of the triangle opposite each point, and the angles between the rays
+    let a = (points[1] - points[2]).norm();
+    let b = (points[0] - points[2]).norm();
+    let c = (points[0] - points[1]).norm();
+    let cos_alpha = j[1].dot(&j[2]);
+    let cos_beta = j[0].dot(&j[2]);
+    let cos_gamma = j[0].dot(&j[1]);
+    if b < 1e-12 {
+        return vec![];
+    }
+
+    let (a2, b2, c2) = (a * a, b * b, c * c);
+    let a_c = (a2 - c2) / b2;
+    let a_plus_c = (a2 + c2) / b2;
+
+    // Haralick et al.'s coefficients for Grunert's quartic, A0 up to A4
+    let coefficients = [
+        (1.0 + a_c).powi(2) - 4.0 * a2 / b2 * cos_gamma * cos_gamma,
+        4.0 * (-a_c * (1.0 + a_c) * cos_beta + 2.0 * a2 / b2 * cos_gamma * cos_gamma * cos_beta
+            - (1.0 - a_plus_c) * cos_alpha * cos_gamma),
+        2.0 * (a_c * a_c - 1.0
+            + 2.0 * a_c * a_c * cos_beta * cos_beta
+            + 2.0 * (b2 - c2) / b2 * cos_alpha * cos_alpha
+            - 4.0 * a_plus_c * cos_alpha * cos_beta * cos_gamma
+            + 2.0 * (b2 - a2) / b2 * cos_gamma * cos_gamma),
+        4.0 * (a_c * (1.0 - a_c) * cos_beta - (1.0 - a_plus_c) * cos_alpha * cos_gamma
+            + 2.0 * c2 / b2 * cos_alpha * cos_alpha * cos_beta),
+        (a_c - 1.0).powi(2) - 4.0 * c2 / b2 * cos_alpha * cos_alpha,
+    ];
+
+    real_roots(&coefficients)
+        .into_iter()
+        .filter_map(|v| {
+            let denominator = 2.0 * (cos_gamma - v * cos_alpha);
+            if denominator.abs() < 1e-12 {
+                return None;
+            }
+            let u = ((a_c - 1.0) * v * v - 2.0 * a_c * cos_beta * v + 1.0 + a_c) / denominator;
+            // the points must be in front of the camera
+            if u <= 0.0 || v <= 0.0 {
+                return None;
+            }
+            let s1_squared = c2 / (1.0 + u * u - 2.0 * u * cos_gamma);
+            if s1_squared <= 0.0 {
+                return None;
+            }
+            let s1 = s1_squared.sqrt();
+            let in_camera = [j[0] * s1, j[1] * (u * s1), j[2] * (v * s1)];
+            Some(align(points, &in_camera))
+        })
+        .collect()
+}
+
+// The rigid motion that best moves the points `from` onto `to` (Kabsch's algorithm): after
+// centering both sets, the rotation comes from the SVD of their cross-covariance.
+pub fn align(from: &[Vector3<f64>], to: &[Vector3<f64>]) -> Pose {
+    let n = from.len() as f64;
+    let from_centroid = from.iter().sum::<Vector3<f64>>() / n;
+    let to_centroid = to.iter().sum::<Vector3<f64>>() / n;
+
+    let mut covariance = Matrix3::zeros();
+    for (f, t) in from.iter().zip(to) {
+        covariance += (f - from_centroid) * (t - to_centroid).transpose();
+    }
+
+    let svd = covariance.svd(true, true);
+    let u = svd.u.unwrap();
+    let v = svd.v_t.unwrap().transpose();
+    // don't let the rotation become a reflection
+    let d = (v * u.transpose()).determinant().signum();
+    let rotation = v * Matrix3::from_diagonal(&Vector3::new(1.0, 1.0, d)) * u.transpose();
+
+    (rotation, to_centroid - rotation * from_centroid)
+}
+
+// EPnP of Lepetit, Moreno-Noguer and Fua. Every world point is written as a weighted sum of four
+// control points (its barycentric coordinates), and those weights are the same in the camera's
+// frame. Each observation gives two linear equations in the twelve camera coordinates of the
+// control points, whose solution is the null vector of the system up to scale. The scale is the
+// one that preserves the distances between the control points.
+//
+// With six or more points in general position the null space is a single vector, which is the
+// case handled here, and `refine` cleans up what noise leaves behind.
+pub fn epnp(points: &[Vector3<f64>], observations: &[(f64, f64)]) -> Option<Pose> {
+    let n = points.len();
+    if n < 6 {
+        return None;
+    }
+
+    // control points at the centroid and along the principal directions of the points
+    let centroid = points.iter().sum::<Vector3<f64>>() / n as f64;
+    let mut covariance = Matrix3::zeros();
+    for point in points {
+        covariance += (point - centroid) * (point - centroid).transpose();
+    }
+    let eigen = (covariance / n as f64).symmetric_eigen();
+    let mut controls = [centroid; 4];
+    for axis in 0..3 {
+        let spread = eigen.eigenvalues[axis].max(0.0).sqrt();
+        controls[axis + 1] = centroid + eigen.eigenvectors.column(axis) * spread.max(1e-9);
+    }
+
+    // barycentric coordinates: X = Σ αⱼ·Cⱼ with Σ αⱼ = 1
+    let basis = Matrix3::from_columns(&[
+        controls[1] - controls[0],
+        controls[2] - controls[0],
+        controls[3] - controls[0],
+    ]);
+    let basis_inverse = basis.try_inverse()?;
+    let alphas: Vec<[f64; 4]> = points
+        .iter()
+        .map(|point| {
+            let b = basis_inverse * (point - controls[0]);
+            [1.0 - b.x - b.y - b.z, b.x, b.y, b.z]
+        })
+        .collect();
+
+    // M·x = 0 with x the control points in camera coordinates, solved through MᵀM
+    let mut mtm = SMatrix::<f64, 12, 12>::zeros();
+    for (alpha, &(u, v)) in alphas.iter().zip(observations) {
+        let mut row_u = SVector::<f64, 12>::zeros();
+        let mut row_v = SVector::<f64, 12>::zeros();
+        for j in 0..4 {
+            row_u[3 * j] = alpha[j];
+            row_u[3 * j + 2] = -alpha[j] * u;
+            row_v[3 * j + 1] = alpha[j];
+            row_v[3 * j + 2] = -alpha[j] * v;
+        }
+        mtm += row_u * row_u.transpose() + row_v * row_v.transpose();
+    }
+    let eigen = mtm.symmetric_eigen();
+    let smallest = eigen.eigenvalues.imin();
+    let null_vector = eigen.eigenvectors.column(smallest);
+    let camera_controls: Vec<Vector3<f64>> = (0..4)
+        .map(|j| {
+            Vector3::new(
+                null_vector[3 * j],
+                null_vector[3 * j + 1],
+                null_vector[3 * j + 2],
+            )
+        })
+        .collect();
+
+    // pick the scale that best preserves the distances between the control points
+    let mut numerator = 0.0;
+    let mut denominator = 0.0;
+    for i in 0..4 {
+        for k in i + 1..4 {
+            let world = (controls[i] - controls[k]).norm();
+            let camera = (camera_controls[i] - camera_controls[k]).norm();
+            numerator += world * camera;
+            denominator += camera * camera;
+        }
+    }
+    if denominator == 0.0 {
+        return None;
+    }
+    let mut scale = numerator / denominator;
+
+    let in_camera = |scale: f64| -> Vec<Vector3<f64>> {
+        alphas
+            .iter()
+            .map(|alpha| (0..4).map(|j| camera_controls[j] * alpha[j] * scale).sum())
+            .collect()
+    };
+    // the null vector's sign is arbitrary, the points have to end up in front of the camera
+    if in_camera(scale).iter().filter(|p| p.z < 0.0).count() * 2 > n {
+        scale = -scale;
+    }
+
+    Some(align(points, &in_camera(scale)))
+}
+
+// How far a world point projects from where it was observed, in normalized image coordinates.
+fn reprojection_error(pose: &Pose, point: &Vector3<f64>, observation: &(f64, f64)) -> f64 {
+    let p = pose.0 * point + pose.1;
+    if p.z <= 0.0 {
+        return f64::INFINITY;
+    }
+    ((p.x / p.z - observation.0).powi(2) + (p.y / p.z - observation.1).powi(2)).sqrt()
+}
+
+// Gauss–Newton on the reprojection errors. Each step ξ = (ρ, ω) is applied on the left of the
+// pose, T ← exp(ξ)·T.
+pub fn refine(
+    pose: &Pose,
+    points: &[Vector3<f64>],
+    observations: &[(f64, f64)],
+    max_iterations: usize,
+) -> Pose {
+    let mut pose = SE3::from_pose(pose);
+    let cost = |pose: &SE3| -> f64 {
+        let pose = pose.to_pose();
+        points
+            .iter()
+            .zip(observations)
+            .map(|(point, observation)| reprojection_error(&pose, point, observation).powi(2))
+            .sum()
+    };
+    let mut current_cost = cost(&pose);
+
+    for _ in 0..max_iterations {
+        let mut jtj = Matrix6::zeros();
+        let mut jtr = Vector6::zeros();
+        for (point, &(u, v)) in points.iter().zip(observations) {
+            let p = pose.act(point);
+            if p.z <= 0.0 {
+                continue;
+            }
+            let residual = [p.x / p.z - u, p.y / p.z - v];
+
+            // how the projection moves with the camera point, and the camera point with the pose
+            let z_inverse = 1.0 / p.z;
+            let projection = SMatrix::<f64, 2, 3>::new(
+                z_inverse,
+                0.0,
+                -p.x * z_inverse * z_inverse,
+                0.0,
+                z_inverse,
+                -p.y * z_inverse * z_inverse,
+            );
+            let jacobian = projection * pose.act_jacobian(point);
+
+            for (row, r) in residual.iter().enumerate() {
+                let j_row = jacobian.row(row).transpose();
+                jtj += j_row * j_row.transpose();
+                jtr += j_row * *r;
+            }
+        }
+
+        let step = match jtj.cholesky() {
+            Some(cholesky) => cholesky.solve(&-jtr),
+            None => break,
+        };
+        let candidate = SE3::exp(&step) * pose;
+        let candidate_cost = cost(&candidate);
+        if candidate_cost >= current_cost {
+            break;
+        }
+        let converged = current_cost - candidate_cost < 1e-12 * current_cost;
+        pose = candidate;
+        current_cost = candidate_cost;
+        if converged {
+            break;
+        }
+    }
+
+    pose.to_pose()
+}
+
+struct PnpEstimator<'a> {
+    points: &'a [Vector3<f64>],
+    observations: &'a [(f64, f64)],
+    solver: PnpSolver,
+}
+
+impl<'a> PnpEstimator<'a> {
+    fn sample(&self, indices: &[usize]) -> (Vec<Vector3<f64>>, Vec<(f64, f64)>) {
+        indices
+            .iter()
+            .map(|&i| (self.points[i], self.observations[i]))
+            .unzip()
+    }
+}
+
+impl<'a> Estimator for PnpEstimator<'a> {
+    type Model = Pose;
+
+    fn sample_size(&self) -> usize {
+        match self.solver {
+            PnpSolver::P3P => 3,
+            PnpSolver::EPnP => 6,
+        }
+    }
+
+    fn num_data(&self) -> usize {
+        self.points.len()
+    }
+
+    fn estimate(&self, sample: &[usize]) -> Vec<Pose> {
+        let (points, observations) = self.sample(sample);
+        match self.solver {
+            PnpSolver::P3P => {
+                let bearings = [0, 1, 2].map(|i| {
+                    let (u, v) = observations[i];
+                    Vector3::new(u, v, 1.0)
+                });
+                p3p(&[points[0], points[1], points[2]], &bearings)
+            }
+            PnpSolver::EPnP => epnp(&points, &observations).into_iter().collect(),
+        }
+    }
+
+    fn residual(&self, pose: &Pose, index: usize) -> f64 {
+        reprojection_error(pose, &self.points[index], &self.observations[index])
+    }
+
+    fn refine(&self, inliers: &[usize]) -> Option<Pose> {
+        let (points, observations) = self.sample(inliers);
+        epnp(&points, &observations).map(|pose| refine(&pose, &points, &observations, 10))
+    }
+}
+
+// Finds the camera pose from 3D world points and the pixels they were seen at. The inlier
+// threshold of `options` is in pixels. The best RANSAC pose is refined with Gauss–Newton over its
+// inliers, and the result's residuals are reprojection errors in pixels.
+pub fn estimate_pose_pnp(
+    correspondences: &[(Vector3<f64>, KeyPoint)],
+    intrinsics: &CameraIntrinsics,
+    options: &RansacOptions,
+    solver: PnpSolver,
+    rnd: &mut Rand,
+) -> Option<RansacResult<Pose>> {
+    let points: Vec<Vector3<f64>> = correspondences.iter().map(|(point, _)| *point).collect();
+    let observations: Vec<(f64, f64)> = correspondences
+        .iter()
+        .map(|(_, key_point)| {
+            let normalized = intrinsics.normalize(key_point);
+            (normalized.x as f64, normalized.y as f64)
+        })
+        .collect();
+
+    let estimator = PnpEstimator {
+        points: &points,
+        observations: &observations,
+        solver,
+    };
+    let pixels_per_unit = 1.0 / intrinsics.pixels_to_normalized(1.0);
+    let normalized_options = RansacOptions {
+        inlier_threshold: options.inlier_threshold / pixels_per_unit,
+        ..*options
+    };
+    let result = ransac::estimate(&estimator, &normalized_options, rnd)?;
+
+    let (inlier_points, inlier_observations): (Vec<_>, Vec<_>) = points
+        .iter()
+        .zip(&observations)
+        .zip(&result.inliers)
+        .filter(|(_, &inlier)| inlier)
+        .map(|((point, observation), _)| (*point, *observation))
+        .unzip();
+    let pose = refine(&result.model, &inlier_points, &inlier_observations, 10);
+
+    let residuals: Vec<f64> = (0..points.len())
+        .map(|i| estimator.residual(&pose, i) * pixels_per_unit)
+        .collect();
+    let inliers: Vec<bool> = residuals
+        .iter()
+        .map(|&residual| residual < options.inlier_threshold)
+        .collect();
+    Some(RansacResult {
+        model: pose,
+        num_inliers: inliers.iter().filter(|&&inlier| inlier).count(),
+        inliers,
+        residuals,
+        iterations: result.iterations,
+    })
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::*;
+
+    fn camera_pose() -> Pose {
+        (small_rotation(), Vector3::new(0.3, -0.2, 0.5))
+    }
+
+    fn observe(pose: &Pose, point: &Vector3<f64>) -> (f64, f64) {
+        let p = pose.0 * point + pose.1;
+        (p.x / p.z, p.y / p.z)
+    }
+
+    fn assert_poses_equal(a: &Pose, b: &Pose, tolerance: f64) {
+        assert!((a.0 - b.0).norm() < tolerance, "{} {}", a.0, b.0);
+        assert!((a.1 - b.1).norm() < tolerance, "{} {}", a.1, b.1);
+    }
+
+    #[test]
+    fn test_p3p() {
+        let mut rng = Rand::new_with_seed(16);
+        let pose = camera_pose();
+        for _ in 0..10 {
+            let points = random_points(&mut rng, 3);
+            let bearings = [0, 1, 2].map(|i| {
+                let (u, v) = observe(&pose, &points[i]);
+                Vector3::new(u, v, 1.0)
+            });
+            let solutions = p3p(&[points[0], points[1], points[2]], &bearings);
+            assert!(!solutions.is_empty() && solutions.len() <= 4);
+            assert!(solutions
+                .iter()
+                .any(|solution| (solution.0 - pose.0).norm() < 1e-6
+                    && (solution.1 - pose.1).norm() < 1e-6));
+        }
+    }
+
+    #[test]
+    fn test_epnp() {
+        let mut rng = Rand::new_with_seed(17);
+        let pose = camera_pose();
+        let points = random_points(&mut rng, 20);
+        let observations: Vec<_> = points.iter().map(|p| observe(&pose, p)).collect();
+
+        let estimated = epnp(&points, &observations).unwrap();
+        assert_poses_equal(&estimated, &pose, 1e-6);
+    }
+
+    #[test]
+    fn test_refine() {
+        let mut rng = Rand::new_with_seed(18);
+        let pose = camera_pose();
+        let points = random_points(&mut rng, 20);
+        let observations: Vec<_> = points.iter().map(|p| observe(&pose, p)).collect();
+
+        let start = (
+            nalgebra::Rotation3::new(Vector3::new(0.02, -0.01, 0.03)).matrix() * pose.0,
+            pose.1 + Vector3::new(0.05, 0.02, -0.04),
+        );
+        let refined = refine(&start, &points, &observations, 20);
+        assert_poses_equal(&refined, &pose, 1e-8);
+    }
+
+    #[test]
+    fn test_align() {
+        let mut rng = Rand::new_with_seed(19);
+        let pose = camera_pose();
+        let points = random_points(&mut rng, 5);
+        let moved: Vec<_> = points.iter().map(|p| pose.0 * p + pose.1).collect();
+        assert_poses_equal(&align(&points, &moved), &pose, 1e-9);
+    }
+
+    #[test]
+    fn test_estimate_pose_pnp() {
+        let mut rng = Rand::new_with_seed(20);
+        let pose = camera_pose();
+        let intrinsics = CameraIntrinsics::new(500.0, 500.0, 320.0, 240.0, 0.0).unwrap();
+        let points = random_points(&mut rng, 60);
+        let mut correspondences: Vec<(Vector3<f64>, KeyPoint)> = points
+            .iter()
+            .map(|point| {
+                let (u, v) = observe(&pose, point);
+                let key_point = KeyPoint {
+                    x: (u * 500.0 + 320.0) as f32 + rng.gen_range(-0.5..=0.5),
+                    y: (v * 500.0 + 240.0) as f32 + rng.gen_range(-0.5..=0.5),
+                    orientation: 0.0,
+                };
+                (*point, key_point)
+            })
+            .collect();
+        // every fourth observation is nowhere near where it should be
+        for i in (0..correspondences.len()).step_by(4) {
+            correspondences[i].1.x += 40.0;
+        }
+
+        for solver in [PnpSolver::P3P, PnpSolver::EPnP] {
+            let options = RansacOptions {
+                confidence: 0.999,
+                min_iterations: 1,
+                ..RansacOptions::new(3.0, 500)
+            };
+            let result =
+                estimate_pose_pnp(&correspondences, &intrinsics, &options, solver, &mut rng)
+                    .unwrap();
+            assert_poses_equal(&result.model, &pose, 0.01);
+            for (i, &inlier) in result.inliers.iter().enumerate() {
+                assert_eq!(inlier, i % 4 != 0, "{:?} match {}", solver, i);
+            }
+            assert!(result.residuals[1] < 3.0);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// Polynomials are written c[0] + c[1]·x + ... + c[n]·xⁿ, from the lowest degree up, so that a
+// coefficient's index is its degree.
+
+use nalgebra::DMatrix;
+
+// The real roots of the polynomial, found as the eigenvalues of its companion matrix. Leading
+// coefficients that are (nearly) zero are dropped, so a "cubic" whose cubic term vanishes is
+// solved as the quadratic it really is.
+pub fn real_roots(coefficients: &[f64]) -> Vec<f64> {
+    let largest = coefficients.iter().fold(0.0_f64, |a, c| a.max(c.abs()));
+    if largest == 0.0 {
+        return vec![];
+    }
+    let degree = match coefficients.iter().rposition(|c| c.abs() > 1e-12 * largest) {
+        Some(degree) => degree,
+        None => return vec![],
+    };
+    if degree == 0 {
+        return vec![];
+    }
+
+    // the companion matrix has the polynomial as its characteristic polynomial
+    let mut companion = DMatrix::<f64>::zeros(degree, degree);
+    for i in 0..degree {
+        companion[(0, i)] = -coefficients[degree - 1 - i] / coefficients[degree];
+    }
+    for i in 1..degree {
+        companion[(i, i - 1)] = 1.0;
+    }
+
+    companion
+        .complex_eigenvalues()
+        .iter()
+        .filter(|root| root.im.abs() <= 1e-8 * root.re.abs().max(1.0))
+        .map(|root| root.re)
+        .collect()
+}
+
+pub fn multiply(a: &[f64], b: &[f64]) -> Vec<f64> {
+    let mut product = vec![0.0; a.len() + b.len() - 1];
+    for (i, x) in a.iter().enumerate() {
+        for (j, y) in b.iter().enumerate() {
+            product[i + j] += x * y;
+        }
+    }
+    product
+}
+
+pub fn add(a: &[f64], b: &[f64]) -> Vec<f64> {
+    (0..a.len().max(b.len()))
+        .map(|i| a.get(i).unwrap_or(&0.0) + b.get(i).unwrap_or(&0.0))
+        .collect()
+}
+
+pub fn scale(a: &[f64], factor: f64) -> Vec<f64> {
+    a.iter().map(|x| x * factor).collect()
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut roots: Vec<f64>) -> Vec<f64> {
+        roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        roots
+    }
+
+    #[test]
+    fn test_real_roots() {
+        // (x - 1)(x - 2)(x + 3) = 6 - 7x + x³
+        let roots = sorted(real_roots(&[6.0, -7.0, 0.0, 1.0]));
+        assert_eq!(roots.len(), 3);
+        assert!((roots[0] + 3.0).abs() < 1e-9);
+        assert!((roots[1] - 1.0).abs() < 1e-9);
+        assert!((roots[2] - 2.0).abs() < 1e-9);
+
+        // x² + 1 has no real roots
+        assert!(real_roots(&[1.0, 0.0, 1.0]).is_empty());
+
+        // a cubic with no cubic term is just a quadratic: -8 + 2x² = 0
+        let roots = sorted(real_roots(&[-8.0, 0.0, 2.0, 0.0]));
+        assert_eq!(roots.len(), 2);
+        assert!((roots[0] + 2.0).abs() < 1e-9);
+        assert!((roots[1] - 2.0).abs() < 1e-9);
+
+        // (x² - 1)(x² - 4) = 4 - 5x² + x⁴
+        let roots = sorted(real_roots(&[4.0, 0.0, -5.0, 0.0, 1.0]));
+        assert_eq!(roots.len(), 4);
+        assert!((roots[0] + 2.0).abs() < 1e-9);
+        assert!((roots[3] - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        // (1 + x)(1 - x) = 1 - x²
+        assert_eq!(multiply(&[1.0, 1.0], &[1.0, -1.0]), vec![1.0, 0.0, -1.0]);
+        assert_eq!(add(&[1.0], &[0.0, 2.0]), vec![1.0, 2.0]);
+        assert_eq!(scale(&[1.0, -2.0], 3.0), vec![3.0, -6.0]);
+    }
+}
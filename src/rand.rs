@@ -7,6 +7,7 @@ impl Rand {
         Self(seed)
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> u64 {
         self.0 = self.0.wrapping_mul(0x6C078965).wrapping_add(1);
         self.0
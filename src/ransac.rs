@@ -0,0 +1,491 @@
+// RANSAC (RANdom SAmple Consensus) fits a model to data that contains outliers. It repeatedly
+// fits the model to a small random sample, just big enough to determine it, and keeps whichever
+// model the rest of the data agrees with best. If one sample happens to contain only inliers, the
+// model it produces will be supported by all the other inliers.
+//
+// Everything that gets estimated robustly in this crate (essential and fundamental matrices,
+// homographies, camera poses) implements `Estimator` and goes through `estimate` here, which
+// supports a few well known improvements over the original algorithm:
+//
+// - MSAC scoring: instead of counting inliers, inliers are scored by how well they fit, so among
+//   models with the same number of inliers the more accurate one wins.
+// - PROSAC sampling: when the data is sorted from most to least promising (e.g. matches by
+//   descriptor distance), samples are drawn from the best data first and widen over time.
+// - LO-RANSAC: whenever a new best model is found it is refit to all its inliers, which usually
+//   gives a better model than any minimal sample can.
+// - Adaptive termination: once we have seen a model with inlier ratio w, we only need enough
+//   iterations to have drawn an all inlier sample with the desired confidence.
+
+use crate::rand::*;
+
+pub trait Estimator {
+    type Model: Clone;
+
+    // How many data points a minimal sample needs.
+    fn sample_size(&self) -> usize;
+
+    // How many data points there are to sample from.
+    fn num_data(&self) -> usize;
+
+    // Fits the models consistent with a minimal sample (some solvers have several solutions).
+    fn estimate(&self, sample: &[usize]) -> Vec<Self::Model>;
+
+    // How badly a data point fits a model, in the same units as the inlier threshold.
+    fn residual(&self, model: &Self::Model, index: usize) -> f64;
+
+    // Fits a model to more than a minimal sample, used by local optimization. Estimators without a
+    // non-minimal solver can leave this out.
+    fn refine(&self, _inliers: &[usize]) -> Option<Self::Model> {
+        None
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Scoring {
+    // Count the inliers.
+    Ransac,
+    // Sum the squared residuals of inliers, with outliers costing the squared threshold.
+    Msac,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Sampling {
+    // Every data point is equally likely to be sampled.
+    Uniform,
+    // The data is sorted best first, and samples start from the best and widen over time.
+    Prosac,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct RansacOptions {
+    pub inlier_threshold: f64,
+    pub scoring: Scoring,
+    pub sampling: Sampling,
+    pub local_optimization: bool,
+    // Stop once we are this sure an all inlier sample has been drawn.
+    pub confidence: f64,
+    pub min_iterations: usize,
+    pub max_iterations: usize,
+}
+
+impl RansacOptions {
+    // Plain RANSAC with a fixed number of iterations.
+    pub fn new(inlier_threshold: f64, num_iterations: usize) -> Self {
+        RansacOptions {
+            inlier_threshold,
+            scoring: Scoring::Ransac,
+            sampling: Sampling::Uniform,
+            local_optimization: false,
+            confidence: 1.0,
+            min_iterations: num_iterations,
+            max_iterations: num_iterations,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct RansacResult<M> {
+    pub model: M,
+    // which data points agree with the model, and how well every data point fits it
+    pub inliers: Vec<bool>,
+    pub residuals: Vec<f64>,
+    pub num_inliers: usize,
+    pub iterations: usize,
+}
+
+// The cost of a model over all the data, lower is better.
+fn cost<E: Estimator>(estimator: &E, model: &E::Model, options: &RansacOptions) -> (f64, usize) {
+    let threshold_squared = options.inlier_threshold * options.inlier_threshold;
+    let mut cost = 0.0;
+    let mut num_inliers = 0;
+    for index in 0..estimator.num_data() {
+        let residual = estimator.residual(model, index);
+        let residual_squared = residual * residual;
+        // NaN residuals count as outliers
+        if residual_squared < threshold_squared {
+            num_inliers += 1;
+            if options.scoring == Scoring::Msac {
+                cost += residual_squared;
+            }
+        } else {
+            cost += threshold_squared;
+        }
+    }
+    (cost, num_inliers)
+}
+
+fn inlier_indices<E: Estimator>(
+    estimator: &E,
+    model: &E::Model,
+    options: &RansacOptions,
+) -> Vec<usize> {
+    (0..estimator.num_data())
+        .filter(|&index| estimator.residual(model, index) < options.inlier_threshold)
+        .collect()
+}
+
+// How many iterations are needed to draw at least one all inlier sample with the given confidence
+// when a fraction `inlier_ratio` of the data are inliers.
+fn required_iterations(inlier_ratio: f64, sample_size: usize, confidence: f64) -> usize {
+    if confidence >= 1.0 {
+        return usize::MAX;
+    }
+    let all_inlier_probability = inlier_ratio.powi(sample_size as i32);
+    if all_inlier_probability <= 0.0 {
+        return usize::MAX;
+    }
+    if all_inlier_probability >= 1.0 {
+        return 0;
+    }
+    let iterations = (1.0 - confidence).ln() / (1.0 - all_inlier_probability).ln();
+    if iterations.is_finite() {
+        iterations.ceil() as usize
+    } else {
+        usize::MAX
+    }
+}
+
+// The PROSAC schedule of Chum and Matas. Over time the pool samples are drawn from grows from the
+// `sample_size` best data points to all of them, at the rate uniform sampling would have drawn
+// samples from each pool.
+struct ProsacSampler {
+    sample_size: usize,
+    num_data: usize,
+    pool_size: usize,
+    iteration: usize,
+    // the expected number of samples drawn only from the current pool, and the iteration at
+    // which the pool grows
+    samples_from_pool: f64,
+    grow_at: usize,
+}
+
+impl ProsacSampler {
+    fn new(sample_size: usize, num_data: usize, max_iterations: usize) -> Self {
+        let mut samples_from_pool = max_iterations as f64;
+        for i in 0..sample_size {
+            samples_from_pool *= (sample_size - i) as f64 / (num_data - i) as f64;
+        }
+        ProsacSampler {
+            sample_size,
+            num_data,
+            pool_size: sample_size,
+            iteration: 0,
+            samples_from_pool,
+            grow_at: 1,
+        }
+    }
+
+    fn sample(&mut self, rng: &mut Rand) -> Vec<usize> {
+        self.iteration += 1;
+        if self.iteration == self.grow_at && self.pool_size < self.num_data {
+            self.pool_size += 1;
+            let next = self.samples_from_pool * self.pool_size as f64
+                / (self.pool_size - self.sample_size) as f64;
+            self.grow_at += (next - self.samples_from_pool).ceil().max(1.0) as usize;
+            self.samples_from_pool = next;
+        }
+
+        if self.grow_at < self.iteration || self.pool_size == self.sample_size {
+            // the schedule has run ahead of us, sample the pool uniformly
+            (0..self.pool_size)
+                .collect::<Vec<_>>()
+                .choose_multiple(rng, self.sample_size)
+        } else {
+            // always include the newest point in the pool with the rest from the better ones
+            let mut sample = (0..self.pool_size - 1)
+                .collect::<Vec<_>>()
+                .choose_multiple(rng, self.sample_size - 1);
+            sample.push(self.pool_size - 1);
+            sample
+        }
+    }
+}
+
+// Refits the model to its inliers until the cost stops improving.
+fn local_optimization<E: Estimator>(
+    estimator: &E,
+    model: &E::Model,
+    model_cost: f64,
+    options: &RansacOptions,
+) -> Option<(E::Model, f64, usize)> {
+    let mut best: Option<(E::Model, f64, usize)> = None;
+    let mut current_model = model.clone();
+    let mut current_cost = model_cost;
+
+    for _ in 0..4 {
+        let inliers = inlier_indices(estimator, &current_model, options);
+        if inliers.len() <= estimator.sample_size() {
+            break;
+        }
+        let refined = match estimator.refine(&inliers) {
+            Some(refined) => refined,
+            None => break,
+        };
+        let (refined_cost, refined_inliers) = cost(estimator, &refined, options);
+        if refined_cost >= current_cost {
+            break;
+        }
+        current_model = refined.clone();
+        current_cost = refined_cost;
+        best = Some((refined, refined_cost, refined_inliers));
+    }
+
+    best
+}
+
+pub fn estimate<E: Estimator>(
+    estimator: &E,
+    options: &RansacOptions,
+    rng: &mut Rand,
+) -> Option<RansacResult<E::Model>> {
+    let sample_size = estimator.sample_size();
+    let num_data = estimator.num_data();
+    if num_data < sample_size {
+        return None;
+    }
+
+    let mut prosac = ProsacSampler::new(sample_size, num_data, options.max_iterations);
+    let all_indices: Vec<usize> = (0..num_data).collect();
+
+    let mut best: Option<(E::Model, f64, usize)> = None;
+    let mut required = options.max_iterations;
+    let mut iterations = 0;
+
+    while iterations
+        < options
+            .max_iterations
+            .min(required.max(options.min_iterations))
+    {
+        iterations += 1;
+
+        let sample = match options.sampling {
+            Sampling::Uniform => all_indices.choose_multiple(rng, sample_size),
+            Sampling::Prosac => prosac.sample(rng),
+        };
+
+        for model in estimator.estimate(&sample) {
+            let (model_cost, num_inliers) = cost(estimator, &model, options);
+            let is_better = match &best {
+                Some((_, best_cost, _)) => model_cost < *best_cost,
+                None => num_inliers > 0,
+            };
+            if !is_better {
+                continue;
+            }
+
+            best = Some((model.clone(), model_cost, num_inliers));
+            if options.local_optimization {
+                if let Some(optimized) = local_optimization(estimator, &model, model_cost, options)
+                {
+                    best = Some(optimized);
+                }
+            }
+
+            let best_inliers = best.as_ref().map(|b| b.2).unwrap_or(0);
+            required = required_iterations(
+                best_inliers as f64 / num_data as f64,
+                sample_size,
+                options.confidence,
+            );
+        }
+    }
+
+    best.map(|(model, _, _)| {
+        let residuals: Vec<f64> = (0..num_data)
+            .map(|index| estimator.residual(&model, index))
+            .collect();
+        let inliers: Vec<bool> = residuals
+            .iter()
+            .map(|&residual| residual < options.inlier_threshold)
+            .collect();
+        let num_inliers = inliers.iter().filter(|&&inlier| inlier).count();
+        RansacResult {
+            model,
+            inliers,
+            residuals,
+            num_inliers,
+            iterations,
+        }
+    })
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fits a line y = a·x + b to points, the classic RANSAC example.
+    struct LineEstimator {
+        points: Vec<(f64, f64)>,
+    }
+
+    impl Estimator for LineEstimator {
+        type Model = (f64, f64);
+
+        fn sample_size(&self) -> usize {
+            2
+        }
+
+        fn num_data(&self) -> usize {
+            self.points.len()
+        }
+
+        fn estimate(&self, sample: &[usize]) -> Vec<(f64, f64)> {
+            let (x1, y1) = self.points[sample[0]];
+            let (x2, y2) = self.points[sample[1]];
+            if x1 == x2 {
+                return vec![];
+            }
+            let a = (y2 - y1) / (x2 - x1);
+            vec![(a, y1 - a * x1)]
+        }
+
+        fn residual(&self, model: &(f64, f64), index: usize) -> f64 {
+            let (x, y) = self.points[index];
+            (model.0 * x + model.1 - y).abs()
+        }
+
+        // least squares fit
+        fn refine(&self, inliers: &[usize]) -> Option<(f64, f64)> {
+            let n = inliers.len() as f64;
+            let mean_x = inliers.iter().map(|&i| self.points[i].0).sum::<f64>() / n;
+            let mean_y = inliers.iter().map(|&i| self.points[i].1).sum::<f64>() / n;
+            let covariance: f64 = inliers
+                .iter()
+                .map(|&i| (self.points[i].0 - mean_x) * (self.points[i].1 - mean_y))
+                .sum();
+            let variance: f64 = inliers
+                .iter()
+                .map(|&i| (self.points[i].0 - mean_x).powi(2))
+                .sum();
+            if variance == 0.0 {
+                return None;
+            }
+            let a = covariance / variance;
+            Some((a, mean_y - a * mean_x))
+        }
+    }
+
+    // y = 2x + 1 with a little noise, and every third point an outlier
+    fn noisy_line(rng: &mut Rand) -> LineEstimator {
+        let points = (0..90)
+            .map(|i| {
+                let x = i as f64 / 10.0;
+                if i % 3 == 0 {
+                    (x, rng.gen_range(-20.0..=20.0) as f64)
+                } else {
+                    (x, 2.0 * x + 1.0 + rng.gen_range(-0.05..=0.05) as f64)
+                }
+            })
+            .collect();
+        LineEstimator { points }
+    }
+
+    fn check_line(result: &RansacResult<(f64, f64)>) {
+        assert!((result.model.0 - 2.0).abs() < 0.05, "{:?}", result.model);
+        assert!((result.model.1 - 1.0).abs() < 0.1, "{:?}", result.model);
+        assert!(result.num_inliers >= 60);
+        for (i, &inlier) in result.inliers.iter().enumerate() {
+            if i % 3 != 0 {
+                assert!(inlier);
+            }
+        }
+    }
+
+    #[test]
+    fn test_required_iterations() {
+        // the classic table: 50% inliers and samples of 2 need 17 iterations for 99% confidence
+        assert_eq!(required_iterations(0.5, 2, 0.99), 17);
+        assert_eq!(required_iterations(1.0, 8, 0.99), 0);
+        assert_eq!(required_iterations(0.0, 8, 0.99), usize::MAX);
+        assert_eq!(required_iterations(0.5, 8, 1.0), usize::MAX);
+    }
+
+    #[test]
+    fn test_ransac() {
+        let mut rng = Rand::new_with_seed(1);
+        let estimator = noisy_line(&mut rng);
+        let result = estimate(&estimator, &RansacOptions::new(0.1, 100), &mut rng).unwrap();
+        check_line(&result);
+        assert_eq!(result.iterations, 100);
+    }
+
+    #[test]
+    fn test_msac_with_local_optimization() {
+        let mut rng = Rand::new_with_seed(2);
+        let estimator = noisy_line(&mut rng);
+        let options = RansacOptions {
+            scoring: Scoring::Msac,
+            local_optimization: true,
+            ..RansacOptions::new(0.1, 100)
+        };
+        let result = estimate(&estimator, &options, &mut rng).unwrap();
+        check_line(&result);
+
+        // the least squares refit gets closer to the true line than any pair of points would
+        assert!((result.model.0 - 2.0).abs() < 0.01, "{:?}", result.model);
+    }
+
+    #[test]
+    fn test_adaptive_termination() {
+        let mut rng = Rand::new_with_seed(3);
+        let estimator = noisy_line(&mut rng);
+        let options = RansacOptions {
+            confidence: 0.99,
+            min_iterations: 1,
+            ..RansacOptions::new(0.1, 10000)
+        };
+        let result = estimate(&estimator, &options, &mut rng).unwrap();
+        check_line(&result);
+
+        // with two thirds inliers we only need about 9 iterations
+        assert!(result.iterations < 100, "{}", result.iterations);
+    }
+
+    #[test]
+    fn test_prosac() {
+        let mut rng = Rand::new_with_seed(4);
+        let mut estimator = noisy_line(&mut rng);
+        // sort so the inliers come first, as if sorted by how good the matches are
+        let (inliers, outliers): (Vec<_>, Vec<_>) = estimator
+            .points
+            .iter()
+            .enumerate()
+            .partition(|(i, _)| i % 3 != 0);
+        let points = inliers
+            .into_iter()
+            .chain(outliers)
+            .map(|(_, &point)| point)
+            .collect();
+        estimator.points = points;
+
+        let options = RansacOptions {
+            sampling: Sampling::Prosac,
+            local_optimization: true,
+            confidence: 0.99,
+            min_iterations: 1,
+            ..RansacOptions::new(0.1, 1000)
+        };
+        let result = estimate(&estimator, &options, &mut rng).unwrap();
+        assert!((result.model.0 - 2.0).abs() < 0.05);
+        assert!(result.num_inliers >= 60);
+
+        // the first samples come from the very best data
+        let mut sampler = ProsacSampler::new(2, 90, 1000);
+        for _ in 0..10 {
+            assert!(sampler.sample(&mut rng).iter().all(|&i| i < 10));
+        }
+    }
+
+    #[test]
+    fn test_not_enough_data() {
+        let mut rng = Rand::new_with_seed(5);
+        let estimator = LineEstimator {
+            points: vec![(1.0, 1.0)],
+        };
+        assert!(estimate(&estimator, &RansacOptions::new(0.1, 100), &mut rng).is_none());
+    }
+}
@@ -1,142 +1,447 @@
 use nalgebra::Matrix3;
-use nalgebra::Vector3;
 
 use crate::common::*;
-use crate::phase_1;
-use crate::phase_2;
-use crate::phase_3;
+use crate::frame::Frame;
+use crate::homography;
 use crate::phase_4;
 use crate::phase_5;
 use crate::phase_6;
+use crate::phase_7;
 use crate::rand::*;
+use crate::ransac::{RansacOptions, RansacResult, Sampling, Scoring};
+use crate::triangulation::{self, TriangulatedPoint, TriangulationMethod};
+
+// Everything `Slam::calculate_pose` found out about the two images.
+pub struct SlamResult {
+    pub pose: Option<Pose>,
+    pub matches: Vec<(KeyPoint, KeyPoint)>,
+    // for each match, whether it agrees with the model the pose came from and how far off it is
+    // in pixels
+    pub inliers: Vec<bool>,
+    pub residuals: Vec<f64>,
+    pub num_inliers: usize,
+    // how many inliers triangulate in front of both cameras, and the median angle in degrees
+    // they are seen with from the two cameras
+    pub num_good_points: usize,
+    pub parallax: f64,
+    // the inliers triangulated with the pose, in the first camera's coordinates and in units of
+    // the (unknown) distance between the cameras
+    pub points: Vec<TriangulatedPoint>,
+    // the match each point was triangulated from, in pixels
+    pub point_matches: Vec<(KeyPoint, KeyPoint)>,
+    pub keypoints_a: Vec<KeyPoint>,
+    pub keypoints_b: Vec<KeyPoint>,
+    pub blurred_image_a: Vec<u8>,
+    // the intrinsics the pixels were converted with, which self-calibration may have changed
+    pub intrinsics: CameraIntrinsics,
+}
 
 pub struct Slam<'a> {
-    image_a: Image<'a>,
-    image_b: Image<'a>,
+    frame_a: &'a Frame,
+    frame_b: &'a Frame,
+    intrinsics: CameraIntrinsics,
     random: Rand,
-    patch_size: usize,
-    num_pairs: usize,
     max_hamming_distance: usize,
-    blur_radius: f32,
-    essential_num_iterations: usize,
-    essential_threshold: f32,
+    // the inlier threshold is in pixels
+    ransac_options: RansacOptions,
+    essential_solver: phase_5::MinimalSolver,
+    epipolar_residual: phase_5::EpipolarResidual,
+    triangulation_method: TriangulationMethod,
+    pose_refinement: bool,
+    // the scale of the robust loss is in pixels
+    pose_refinement_loss: phase_7::Loss,
+    pose_refinement_iterations: usize,
+    guided_matching: bool,
+    guided_band_width: f32,
+    guided_max_hamming_distance: usize,
+    model_selection: bool,
+    self_calibration: bool,
+    estimated_intrinsics: Option<CameraIntrinsics>,
 }
 
 impl<'a> Slam<'a> {
-    pub fn new(image_a: Image<'a>, image_b: Image<'a>) -> Slam<'a> {
+    // The frames already hold their keypoints and descriptors, so a frame can be compared with
+    // the one before it and the one after it without extracting them twice.
+    pub fn new(frame_a: &'a Frame, frame_b: &'a Frame) -> Slam<'a> {
         let seed = 2523523;
         let random = Rand::new_with_seed(seed);
+        let intrinsics = CameraIntrinsics::typical_webcam(frame_a.width, frame_a.height);
         Slam {
-            image_a,
-            image_b,
+            frame_a,
+            frame_b,
+            intrinsics,
             random,
-            patch_size: 100,
-            num_pairs: 500,
             max_hamming_distance: 300,
-            blur_radius: 3.0,
-            essential_num_iterations: 1000,
-            essential_threshold: 10.0,
+            ransac_options: RansacOptions {
+                inlier_threshold: 10.0,
+                scoring: Scoring::Msac,
+                // matches come sorted from best to worst
+                sampling: Sampling::Prosac,
+                local_optimization: true,
+                confidence: 0.999,
+                min_iterations: 20,
+                max_iterations: 1000,
+            },
+            essential_solver: phase_5::MinimalSolver::FivePoint,
+            epipolar_residual: phase_5::EpipolarResidual::Sampson,
+            triangulation_method: TriangulationMethod::Optimal,
+            pose_refinement: true,
+            // residuals past a pixel or so are down-weighted in case an outlier slipped through
+            pose_refinement_loss: phase_7::Loss::Huber(1.0),
+            pose_refinement_iterations: 10,
+            guided_matching: false,
+            guided_band_width: 3.0,
+            guided_max_hamming_distance: 400,
+            model_selection: true,
+            self_calibration: false,
+            estimated_intrinsics: None,
         }
     }
 
-    pub fn calculate_pose(
-        &mut self,
-    ) -> (
-        Option<(Matrix3<f64>, Vector3<f64>)>,
-        Vec<(KeyPoint, KeyPoint)>,
-        (Vec<KeyPoint>, Vec<Descriptor>, Vec<u8>),
-        (Vec<KeyPoint>, Vec<Descriptor>),
-    ) {
-        let (key_points_with_orientation_a, blurred_image_a) = {
-            let width = self.image_a.width;
-            let height = self.image_a.height;
-
-            // PHASE 1  -  Convert RGB image to greyscale and blur it with a Gaussian filter
-            let greyscale = phase_1::rgb_to_grayscale(self.image_a.data, width, height);
-            let blurred_img =
-                phase_1::greyscale_gaussian_blur(&greyscale, width, height, self.blur_radius);
-            let threshold: u8 = 30;
-
-            // PHASE 2  -  Detect FAST keypoints and compute their orientations
-            let keypoints = phase_2::fast_keypoints(&blurred_img, width, height, threshold);
-            let key_points_with_orientation =
-                phase_2::compute_orientations(&blurred_img, width, &keypoints);
-
-            (key_points_with_orientation, blurred_img)
-        };
+    // When enabled, the first essential matrix estimate is used to search for more matches along
+    // epipolar lines, and the essential matrix is estimated again with the larger set of matches.
+    pub fn set_guided_matching(&mut self, enabled: bool) {
+        self.guided_matching = enabled;
+    }
 
-        let (key_points_with_orientation_b, blurred_image_b) = {
-            let width = self.image_b.width;
-            let height = self.image_b.height;
+    pub fn set_essential_solver(&mut self, solver: phase_5::MinimalSolver) {
+        self.essential_solver = solver;
+    }
 
-            // PHASE 1  -  Convert RGB image to greyscale and blur it with a Gaussian filter
-            let greyscale = phase_1::rgb_to_grayscale(self.image_b.data, width, height);
-            let blurred_img =
-                phase_1::greyscale_gaussian_blur(&greyscale, width, height, self.blur_radius);
-            let threshold: u8 = 30;
+    // How matches are measured against essential and fundamental matrices to decide if they are
+    // inliers.
+    pub fn set_epipolar_residual(&mut self, residual: phase_5::EpipolarResidual) {
+        self.epipolar_residual = residual;
+    }
 
-            // PHASE 2  -  Detect FAST keypoints and compute their orientations
-            let keypoints = phase_2::fast_keypoints(&blurred_img, width, height, threshold);
-            let key_points_with_orientation =
-                phase_2::compute_orientations(&blurred_img, width, &keypoints);
+    // When enabled, the pose is refined with Levenberg–Marquardt over the inliers before the
+    // points are triangulated with it.
+    pub fn set_pose_refinement(&mut self, enabled: bool) {
+        self.pose_refinement = enabled;
+    }
 
-            (key_points_with_orientation, blurred_img)
-        };
+    pub fn set_pose_refinement_loss(&mut self, loss: phase_7::Loss) {
+        self.pose_refinement_loss = loss;
+    }
 
-        // PHASE 3  -  Compute BRIEF descriptors for each keypoint so we can visually match them
-        let sampling_pattern =
-            phase_3::generate_sampling_pattern(&mut self.random, self.patch_size, self.num_pairs);
+    pub fn set_triangulation_method(&mut self, method: TriangulationMethod) {
+        self.triangulation_method = method;
+    }
 
-        let descriptors_a = phase_3::compute_brief_descriptors(
-            &blurred_image_a,
-            self.image_a.width as u32,
-            self.image_a.height as u32,
-            &key_points_with_orientation_b,
-            &sampling_pattern,
-        );
+    pub fn set_intrinsics(&mut self, intrinsics: CameraIntrinsics) {
+        self.intrinsics = intrinsics;
+    }
 
-        let descriptors_b = phase_3::compute_brief_descriptors(
-            &blurred_image_b,
-            self.image_b.width as u32,
-            self.image_b.height as u32,
-            &key_points_with_orientation_b,
-            &sampling_pattern,
-        );
+    // When enabled, a homography is estimated alongside the essential matrix and used for the pose
+    // instead whenever it explains the matches better, which is the case for planar scenes.
+    pub fn set_model_selection(&mut self, enabled: bool) {
+        self.model_selection = enabled;
+    }
+
+    // When enabled, the focal length is estimated from the fundamental matrix between the two
+    // images instead of trusting the intrinsics we were given.
+    pub fn set_self_calibration(&mut self, enabled: bool) {
+        self.self_calibration = enabled;
+    }
+
+    // The intrinsics found by self-calibration, if it was enabled and the motion allowed it.
+    pub fn estimated_intrinsics(&self) -> Option<CameraIntrinsics> {
+        self.estimated_intrinsics
+    }
+
+    fn self_calibrate(&mut self, matches: &[(KeyPoint, KeyPoint)]) {
+        let fundamental = phase_5::estimate_fundamental_ransac(
+            matches,
+            &self.ransac_options,
+            self.epipolar_residual,
+            &mut self.random,
+        )
+        .map(|result| result.model);
+
+        let width = self.frame_a.width;
+        let height = self.frame_a.height;
+        let intrinsics = fundamental
+            .and_then(|fundamental| phase_5::focal_from_fundamental(&fundamental, width, height))
+            .and_then(|focal| {
+                CameraIntrinsics::new(focal, focal, width as f64 / 2.0, height as f64 / 2.0, 0.0)
+            });
+
+        if let Some(intrinsics) = intrinsics {
+            self.intrinsics = intrinsics;
+            self.estimated_intrinsics = Some(intrinsics);
+        }
+    }
+
+    pub fn set_ransac_options(&mut self, options: RansacOptions) {
+        self.ransac_options = options;
+    }
+
+    // The RANSAC options with the inlier threshold converted from pixels to normalized image
+    // coordinates.
+    fn normalized_ransac_options(&self) -> RansacOptions {
+        RansacOptions {
+            inlier_threshold: self
+                .intrinsics
+                .pixels_to_normalized(self.ransac_options.inlier_threshold),
+            ..self.ransac_options
+        }
+    }
+
+    fn normalize_matches(&self, matches: &[(KeyPoint, KeyPoint)]) -> Vec<(KeyPoint, KeyPoint)> {
+        matches
+            .iter()
+            .map(|(a, b)| (self.intrinsics.normalize(a), self.intrinsics.normalize(b)))
+            .collect()
+    }
+
+    // The essential matrix relates normalized image coordinates, so the matches are converted out
+    // of pixels with the camera intrinsics before estimating it.
+    fn estimate_essential(
+        &mut self,
+        matches: &[(KeyPoint, KeyPoint)],
+    ) -> Option<RansacResult<Matrix3<f64>>> {
+        let normalized_matches = self.normalize_matches(matches);
+
+        phase_5::estimate_essential_ransac(
+            &normalized_matches,
+            &self.normalized_ransac_options(),
+            self.essential_solver,
+            self.epipolar_residual,
+            &mut self.random,
+        )
+    }
+
+    // Estimates a homography for the matches and checks whether it explains them better than the
+    // essential matrix does. If it does, the pose is taken from the homography instead, along with
+    // the RANSAC result so we know which matches agree with it.
+    fn pose_from_homography(
+        &mut self,
+        matches: &[(KeyPoint, KeyPoint)],
+        essential: Option<Matrix3<f64>>,
+    ) -> Option<(phase_6::PoseHypothesis, RansacResult<Matrix3<f64>>)> {
+        let normalized_matches = self.normalize_matches(matches);
+        let result = homography::estimate_homography_ransac(
+            &normalized_matches,
+            &self.normalized_ransac_options(),
+            &mut self.random,
+        )?;
+        let homography = result.model;
+
+        // we expect about a pixel of noise on each keypoint
+        let sigma = self.intrinsics.pixels_to_normalized(1.0);
+        let homography_score =
+            homography::score_homography(&homography, &normalized_matches, sigma);
+        let essential_score = essential
+            .map(|essential| phase_5::score_essential(&essential, &normalized_matches, sigma))
+            .unwrap_or(0.0);
+        if !homography::prefer_homography(homography_score, essential_score) {
+            return None;
+        }
+
+        let inlier_matches = inlier_matches(&normalized_matches, &result.inliers);
+        homography::select_decomposition(
+            &homography::decompose_homography(&homography),
+            &inlier_matches,
+        )
+        .map(|decomposition| {
+            let hypothesis = phase_6::evaluate_pose(
+                &decomposition.rotation,
+                &decomposition.translation,
+                &inlier_matches,
+            );
+            (hypothesis, result)
+        })
+    }
+
+    pub fn calculate_pose(&mut self) -> SlamResult {
+        // PHASES 1 to 3 happened when the frames were created
+        let key_points_with_orientation_a = &self.frame_a.keypoints;
+        let descriptors_a = &self.frame_a.descriptors;
+        let key_points_with_orientation_b = &self.frame_b.keypoints;
+        let descriptors_b = &self.frame_b.descriptors;
 
         // PHASE 4  -  Match features between the two images
 
-        let matched_keypoints = phase_4::match_features(
-            &key_points_with_orientation_a,
-            &descriptors_a,
-            &key_points_with_orientation_b,
-            &descriptors_b,
+        let scored_matches = phase_4::match_features(
+            key_points_with_orientation_a,
+            descriptors_a,
+            key_points_with_orientation_b,
+            descriptors_b,
             self.max_hamming_distance,
         );
+        let mut matched_keypoints = without_distances(&scored_matches);
 
-        // PHASE 5  -  RANSAC to find the best rotation and translation using 8 point algorithm
-        let essential_matrix = phase_5::estimate_essential_ransac(
-            &matched_keypoints,
-            *&self.essential_num_iterations,
-            *&self.essential_threshold as f64,
-            &mut self.random,
-        );
+        // PHASE 4c  -  Estimate the focal length from the fundamental matrix if we don't know it
+        if self.self_calibration {
+            self.self_calibrate(&matched_keypoints);
+        }
+
+        // PHASE 5  -  RANSAC to find the best rotation and translation using the 5 or 8 point algorithm
+        let mut essential_result = self.estimate_essential(&matched_keypoints);
+
+        // PHASE 4b  -  Search along epipolar lines for matches the first pass missed, then estimate again
+        let fundamental = essential_result
+            .as_ref()
+            .and_then(|result| self.intrinsics.essential_to_fundamental(&result.model));
+        if let (true, Some(fundamental)) = (self.guided_matching, fundamental) {
+            let (matched_a, matched_b): (Vec<KeyPoint>, Vec<KeyPoint>) =
+                matched_keypoints.iter().copied().unzip();
+            let (unmatched_keypoints_a, unmatched_descriptors_a) =
+                unmatched_keypoints(key_points_with_orientation_a, descriptors_a, &matched_a);
+            let (unmatched_keypoints_b, unmatched_descriptors_b) =
+                unmatched_keypoints(key_points_with_orientation_b, descriptors_b, &matched_b);
+
+            let guided_matches = phase_4::guided_match_features(
+                &unmatched_keypoints_a,
+                &unmatched_descriptors_a,
+                &unmatched_keypoints_b,
+                &unmatched_descriptors_b,
+                &fundamental,
+                self.guided_band_width as f64,
+                self.guided_max_hamming_distance,
+            );
+
+            if !guided_matches.is_empty() {
+                // PROSAC samples the best matches first, so the guided ones have to be slotted in
+                // by their distances rather than appended
+                matched_keypoints =
+                    without_distances(&phase_4::merge_by_distance(scored_matches, guided_matches));
+                essential_result = self.estimate_essential(&matched_keypoints);
+            }
+        }
 
-        // PHASE 6  -  Decompose the essential matrix to find the rotation and translation
-        let decomposed_essential = if let Some(essential) = essential_matrix {
-            Some(phase_6::decompose_essential_matrix(essential))
+        // PHASE 5b  -  Planar scenes are better explained by a homography, use it if it scores better
+        let essential_matrix = essential_result.as_ref().map(|result| result.model);
+        let homography_pose = if self.model_selection {
+            self.pose_from_homography(&matched_keypoints, essential_matrix)
         } else {
             None
         };
 
-        (
-            decomposed_essential,
-            matched_keypoints,
-            (
-                key_points_with_orientation_a,
-                descriptors_a,
-                blurred_image_a,
+        // the inliers of whichever model the pose comes from, in normalized image coordinates
+        let model_inliers = |result: &RansacResult<Matrix3<f64>>| {
+            inlier_matches(&self.normalize_matches(&matched_keypoints), &result.inliers)
+        };
+
+        // PHASE 6  -  Decompose the essential matrix, keeping the pose that puts the inliers in
+        //             front of both cameras
+        let (mut hypothesis, model_result) = match homography_pose {
+            Some((hypothesis, homography_result)) => (Some(hypothesis), Some(homography_result)),
+            None => {
+                let hypothesis = essential_result.as_ref().and_then(|result| {
+                    phase_6::decompose_essential_matrix(&result.model, &model_inliers(result))
+                });
+                (hypothesis, essential_result)
+            }
+        };
+
+        // PHASE 7  -  Polish the pose by minimizing the Sampson error of the inliers
+        if let (true, Some(current), Some(result)) =
+            (self.pose_refinement, hypothesis, model_result.as_ref())
+        {
+            let inlier_matches = model_inliers(result);
+            let loss = self
+                .pose_refinement_loss
+                .scaled(self.intrinsics.pixels_to_normalized(1.0));
+            let (rotation, translation) = phase_7::refine_pose(
+                &(current.rotation, current.translation),
+                &inlier_matches,
+                loss,
+                self.pose_refinement_iterations,
+            );
+            hypothesis = Some(phase_6::evaluate_pose(
+                &rotation,
+                &translation,
+                &inlier_matches,
+            ));
+        }
+        let pose = hypothesis.map(|hypothesis| (hypothesis.rotation, hypothesis.translation));
+
+        // PHASE 8  -  Triangulate the inliers into 3D points, keeping the ones in front of both
+        //             cameras that reproject close to where they were seen
+        let (points, point_matches) = match (&pose, &model_result) {
+            (Some((rotation, translation)), Some(result)) => {
+                let max_error = self.normalized_ransac_options().inlier_threshold;
+                let triangulated = triangulation::triangulate_matches(
+                    self.triangulation_method,
+                    rotation,
+                    translation,
+                    &model_inliers(result),
+                );
+                triangulated
+                    .into_iter()
+                    .zip(inlier_matches(&matched_keypoints, &result.inliers))
+                    .filter_map(|(point, pixels)| {
+                        let point = point?;
+                        let good = point.is_in_front(rotation, translation)
+                            && point.reprojection_error < max_error;
+                        good.then_some((point, pixels))
+                    })
+                    .unzip()
+            }
+            _ => (vec![], vec![]),
+        };
+
+        // without a model no match can be called an inlier
+        let num_matches = matched_keypoints.len();
+        let (inliers, residuals, num_inliers) = match model_result {
+            Some(result) => {
+                let pixels_per_unit = 1.0 / self.intrinsics.pixels_to_normalized(1.0);
+                let residuals = result
+                    .residuals
+                    .iter()
+                    .map(|residual| residual * pixels_per_unit)
+                    .collect();
+                (result.inliers, residuals, result.num_inliers)
+            }
+            None => (
+                vec![false; num_matches],
+                vec![f64::INFINITY; num_matches],
+                0,
             ),
-            (key_points_with_orientation_b, descriptors_b),
-        )
+        };
+
+        SlamResult {
+            pose,
+            matches: matched_keypoints,
+            inliers,
+            residuals,
+            num_inliers,
+            num_good_points: hypothesis.map_or(0, |hypothesis| hypothesis.num_good_points),
+            parallax: hypothesis.map_or(0.0, |hypothesis| hypothesis.parallax),
+            points,
+            point_matches,
+            keypoints_a: key_points_with_orientation_a.clone(),
+            keypoints_b: key_points_with_orientation_b.clone(),
+            blurred_image_a: self.frame_a.blurred_image.clone(),
+            intrinsics: self.intrinsics,
+        }
     }
 }
+
+fn without_distances(matches: &[phase_4::ScoredMatch]) -> Vec<(KeyPoint, KeyPoint)> {
+    matches.iter().map(|&(_, m)| m).collect()
+}
+
+// The keypoints, with their descriptors, that aren't among the matched ones.
+fn unmatched_keypoints(
+    keypoints: &[KeyPoint],
+    descriptors: &[Descriptor],
+    matched: &[KeyPoint],
+) -> (Vec<KeyPoint>, Vec<Descriptor>) {
+    keypoints
+        .iter()
+        .zip(descriptors)
+        .filter(|(keypoint, _)| !matched.contains(keypoint))
+        .map(|(keypoint, descriptor)| (*keypoint, descriptor.clone()))
+        .unzip()
+}
+
+fn inlier_matches(matches: &[(KeyPoint, KeyPoint)], inliers: &[bool]) -> Vec<(KeyPoint, KeyPoint)> {
+    matches
+        .iter()
+        .zip(inliers)
+        .filter(|(_, &inlier)| inlier)
+        .map(|(m, _)| *m)
+        .collect()
+}
@@ -0,0 +1,107 @@
+// Synthetic scenes with a known ground truth, so the geometry code can be tested on
+// correspondences we know are perfect before trusting it with noisy ones from real images.
+
+use nalgebra::{Matrix3, Rotation3, Vector3};
+
+use crate::common::{CameraIntrinsics, Descriptor, KeyPoint, Pose};
+use crate::frame::Frame;
+use crate::rand::Rand;
+
+// The camera the synthetic frames are taken with, 640×480 pixels and about 65° across.
+pub fn intrinsics() -> CameraIntrinsics {
+    CameraIntrinsics::new(500.0, 500.0, 320.0, 240.0, 0.0).unwrap()
+}
+
+// A rotation of a few degrees around every axis, like a hand-held camera would make.
+pub fn small_rotation() -> Matrix3<f64> {
+    *Rotation3::from_euler_angles(0.05, -0.1, 0.08).matrix()
+}
+
+// A set of 3D points scattered in a box in front of the first camera.
+pub fn random_points(rng: &mut Rand, num_points: usize) -> Vec<Vector3<f64>> {
+    (0..num_points)
+        .map(|_| {
+            Vector3::new(
+                rng.gen_range(-2.0..=2.0) as f64,
+                rng.gen_range(-2.0..=2.0) as f64,
+                rng.gen_range(4.0..=8.0) as f64,
+            )
+        })
+        .collect()
+}
+
+// `num_descriptors` random descriptors, which are all far apart from each other so each one only
+// matches itself.
+pub fn random_descriptors(rng: &mut Rand, num_descriptors: usize) -> Vec<Descriptor> {
+    (0..num_descriptors)
+        .map(|_| Descriptor((0..63).map(|_| (rng.next() >> 32) as u8).collect()))
+        .collect()
+}
+
+// What a camera at the pose sees of the scene: a keypoint with the point's descriptor for every
+// point in front of it, in the order of the points. Points projecting outside the image are kept,
+// so keypoint i is point i whenever the whole scene is in front of the camera.
+pub fn frame_at(pose: &Pose, points: &[Vector3<f64>], descriptors: &[Descriptor]) -> Frame {
+    let (keypoints, descriptors) = points
+        .iter()
+        .zip(descriptors)
+        .filter_map(|(point, descriptor)| {
+            let (x, y) = intrinsics().project(&(pose.0 * point + pose.1))?;
+            let keypoint = KeyPoint {
+                x: x as f32,
+                y: y as f32,
+                orientation: 0.0,
+            };
+            Some((keypoint, descriptor.clone()))
+        })
+        .unzip();
+    Frame {
+        width: 640,
+        height: 480,
+        blurred_image: vec![],
+        keypoints,
+        descriptors,
+    }
+}
+
+// Projects a 3D point in camera coordinates onto the normalized image plane.
+pub fn project(point: &Vector3<f64>) -> KeyPoint {
+    KeyPoint {
+        x: (point.x / point.z) as f32,
+        y: (point.y / point.z) as f32,
+        orientation: 0.0,
+    }
+}
+
+// The first camera sits at the origin looking down +z, the second sees a point X at R·X + t.
+// Matches are returned in normalized image coordinates.
+pub fn two_view_matches(
+    points: &[Vector3<f64>],
+    rotation: &Matrix3<f64>,
+    translation: &Vector3<f64>,
+) -> Vec<(KeyPoint, KeyPoint)> {
+    points
+        .iter()
+        .map(|point| (project(point), project(&(rotation * point + translation))))
+        .collect()
+}
+
+// The essential matrix E = [t]ₓ·R, which satisfies p2ᵀ·E·p1 = 0 for every match.
+pub fn essential_from_pose(rotation: &Matrix3<f64>, translation: &Vector3<f64>) -> Matrix3<f64> {
+    translation.cross_matrix() * rotation
+}
+
+// Matrices from the eight-point family are only known up to scale (and sign), so compare their
+// directions rather than their entries.
+pub fn assert_matrices_equal_up_to_scale(a: &Matrix3<f64>, b: &Matrix3<f64>, tolerance: f64) {
+    let a = a / a.norm();
+    let b = b / b.norm();
+    let difference = (a - b).norm().min((a + b).norm());
+    assert!(
+        difference < tolerance,
+        "matrices differ by {} up to scale:\n{}\n{}",
+        difference,
+        a,
+        b
+    );
+}
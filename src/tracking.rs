@@ -0,0 +1,770 @@
+// Tracking locates every new frame against the map. Rather than comparing each keypoint with
+// every map point, it guesses where the camera is and projects the map points into the image with
+// that guess: a point's keypoint must be near where it lands, so only the keypoints in a small
+// window around it need to be compared.
+//
+// The guess comes from assuming the camera keeps moving the way it just did (a constant velocity
+// model). When that fails, say the camera jerked, the frame's keypoints are matched by their
+// descriptors with the points seen by the last keyframe instead, and the pose is found with PnP.
+// Either way the pose is then polished using every map point of the local map (the points the
+// reference keyframe and the keyframes sharing points with it see) that projects into the image.
+
+use std::collections::BTreeSet;
+
+use crate::common::*;
+use crate::frame::Frame;
+use crate::geometry::SE3;
+use crate::initializer::{Initialization, Initializer};
+use crate::keyframe_policy::{self, KeyFrameDecision, KeyFrameOptions};
+use crate::local_mapping::LocalMapping;
+use crate::map::{KeyFrameId, Map, MapPoint, MapPointId};
+use crate::phase_4::hamming_distance;
+use crate::pnp::{self, PnpSolver};
+use crate::rand::Rand;
+use crate::ransac::{RansacOptions, Scoring};
+use crate::slam::Slam;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TrackingState {
+    // there is no map yet, so frames go to the initializer
+    NotInitialized,
+    Ok,
+    // the last frame couldn't be located, so the next ones are matched with the last keyframe
+    // until one can be
+    Lost,
+}
+
+pub struct TrackingResult {
+    pub state: TrackingState,
+    // the camera's pose in the map, mapping world points into the camera
+    pub pose: Option<Pose>,
+    // the frame's keypoints (by index) matched with map points, all of them inliers of the pose
+    pub matches: Vec<(usize, MapPointId)>,
+    // whether the frame was added to the map as a keyframe, and why
+    pub keyframe: KeyFrameDecision,
+}
+
+pub struct Tracker {
+    state: TrackingState,
+    initializer: Initializer,
+    map: Map,
+    local_mapping: LocalMapping,
+    intrinsics: CameraIntrinsics,
+    random: Rand,
+    last_pose: Option<SE3>,
+    // the motion between the last two frames, T_last·T_before⁻¹
+    velocity: Option<SE3>,
+    reference_keyframe: Option<KeyFrameId>,
+    keyframe_options: KeyFrameOptions,
+    frames_since_keyframe: usize,
+    // in pixels
+    search_radius: f64,
+    max_hamming_distance: usize,
+    // in pixels
+    inlier_threshold: f64,
+    min_matches: usize,
+}
+
+impl Tracker {
+    pub fn new(intrinsics: CameraIntrinsics) -> Self {
+        // the first map is built with the same camera the frames are tracked with
+        let mut initializer = Initializer::new();
+        initializer.set_intrinsics(intrinsics);
+        Tracker {
+            state: TrackingState::NotInitialized,
+            initializer,
+            map: Map::new(),
+            local_mapping: LocalMapping::new(intrinsics),
+            intrinsics,
+            random: Rand::new_with_seed(2523523),
+            last_pose: None,
+            velocity: None,
+            reference_keyframe: None,
+            keyframe_options: KeyFrameOptions::new(),
+            frames_since_keyframe: 0,
+            search_radius: 15.0,
+            max_hamming_distance: 150,
+            inlier_threshold: 4.0,
+            min_matches: 30,
+        }
+    }
+
+    // Applied to the `Slam` the initializer builds the first map with.
+    pub fn set_configuration(&mut self, configure: fn(&mut Slam)) {
+        self.initializer.set_configuration(configure);
+    }
+
+    // How far in pixels from where a map point projects its keypoint is searched for.
+    pub fn set_search_radius(&mut self, search_radius: f64) {
+        self.search_radius = search_radius;
+    }
+
+    pub fn set_keyframe_options(&mut self, options: KeyFrameOptions) {
+        self.keyframe_options = options;
+    }
+
+    pub fn set_max_hamming_distance(&mut self, max_hamming_distance: usize) {
+        self.max_hamming_distance = max_hamming_distance;
+    }
+
+    // How many map points a frame must be matched with for its pose to be trusted.
+    pub fn set_min_matches(&mut self, min_matches: usize) {
+        self.min_matches = min_matches;
+    }
+
+    pub fn state(&self) -> TrackingState {
+        self.state
+    }
+
+    pub fn map(&self) -> &Map {
+        &self.map
+    }
+
+    pub fn local_mapping(&self) -> &LocalMapping {
+        &self.local_mapping
+    }
+
+    // The pose of the last frame that could be located.
+    pub fn pose(&self) -> Option<Pose> {
+        self.last_pose.map(|pose| pose.to_pose())
+    }
+
+    // Throws the map away and waits for a new one to be initialized.
+    pub fn reset(&mut self) {
+        self.state = TrackingState::NotInitialized;
+        self.initializer.reset();
+        self.map = Map::new();
+        self.local_mapping.reset();
+        self.last_pose = None;
+        self.velocity = None;
+        self.reference_keyframe = None;
+        self.frames_since_keyframe = 0;
+    }
+
+    pub fn process_frame(&mut self, frame: Frame) -> TrackingResult {
+        if self.state == TrackingState::NotInitialized {
+            return self.initialize(frame);
+        }
+
+        // a first guess of the pose, from the motion model while things are going well
+        let guess = match self.state {
+            TrackingState::Ok => self.track_with_motion_model(&frame),
+            _ => None,
+        };
+        let guess = match guess {
+            Some(pose) => Some(pose),
+            None => self.track_reference_keyframe(&frame),
+        };
+
+        match guess.and_then(|pose| self.track_local_map(&frame, &pose)) {
+            Some((pose, matches)) => {
+                let se3 = SE3::from_pose(&pose);
+                self.velocity = self.last_pose.map(|last| se3 * last.inverse());
+                self.last_pose = Some(se3);
+                self.state = TrackingState::Ok;
+                self.frames_since_keyframe += 1;
+                self.count_observations(&frame, &pose, &matches);
+
+                let keyframe = self.decide_keyframe(&pose, matches.len());
+                if keyframe.is_keyframe() {
+                    self.insert_keyframe(&frame, pose, &matches);
+                }
+                TrackingResult {
+                    state: self.state,
+                    pose: Some(pose),
+                    matches,
+                    keyframe,
+                }
+            }
+            None => {
+                self.velocity = None;
+                self.state = TrackingState::Lost;
+                TrackingResult {
+                    state: self.state,
+                    pose: None,
+                    matches: vec![],
+                    keyframe: KeyFrameDecision::NotTracked,
+                }
+            }
+        }
+    }
+
+    fn decide_keyframe(&self, pose: &Pose, num_tracked: usize) -> KeyFrameDecision {
+        let reference = match self.reference_keyframe.and_then(|id| self.map.keyframe(id)) {
+            Some(reference) => reference,
+            // nothing to compare with, so the frame is as new a view as they come
+            None => return KeyFrameDecision::FewTrackedPoints,
+        };
+        keyframe_policy::decide(
+            &self.keyframe_options,
+            self.frames_since_keyframe,
+            pose,
+            num_tracked,
+            &reference.pose,
+            reference.observed_map_points().count(),
+        )
+    }
+
+    // Adds the frame to the map, seeing the map points it was matched with, grows the map around it
+    // and makes it the reference keyframe.
+    fn insert_keyframe(&mut self, frame: &Frame, pose: Pose, matches: &[(usize, MapPointId)]) {
+        let keyframe = self.map.add_keyframe(pose, frame);
+        for &(index, point) in matches {
+            self.map.add_observation(point, keyframe, index);
+        }
+        self.map.attach_keyframe(keyframe);
+        self.local_mapping.process_keyframe(&mut self.map, keyframe);
+        self.reference_keyframe = Some(keyframe);
+        self.frames_since_keyframe = 0;
+    }
+
+    fn initialize(&mut self, frame: Frame) -> TrackingResult {
+        let initial = match self.initializer.process_frame(frame) {
+            Initialization::Initialized(initial) => initial,
+            _ => {
+                return TrackingResult {
+                    state: TrackingState::NotInitialized,
+                    pose: None,
+                    matches: vec![],
+                    keyframe: KeyFrameDecision::NotTracked,
+                }
+            }
+        };
+
+        let map = Map::from_initial_map(&initial);
+        // the second keyframe is the one the current frame became
+        let current = map.keyframes().last().unwrap();
+        let matches = current
+            .map_points
+            .iter()
+            .enumerate()
+            .filter_map(|(index, point)| point.map(|point| (index, point)))
+            .collect();
+        let current = current.id;
+        self.start(map, initial.pose, current);
+
+        TrackingResult {
+            state: self.state,
+            pose: Some(initial.pose),
+            matches,
+            keyframe: KeyFrameDecision::Initialization,
+        }
+    }
+
+    // Starts tracking in a map, from a camera at `pose` next to the keyframe.
+    fn start(&mut self, map: Map, pose: Pose, reference_keyframe: KeyFrameId) {
+        self.map = map;
+        self.local_mapping.reset();
+        self.last_pose = Some(SE3::from_pose(&pose));
+        self.velocity = None;
+        self.reference_keyframe = Some(reference_keyframe);
+        self.frames_since_keyframe = 0;
+        self.state = TrackingState::Ok;
+    }
+
+    fn track_with_motion_model(&self, frame: &Frame) -> Option<Pose> {
+        let predicted = (self.velocity? * self.last_pose?).to_pose();
+        let matches = self.search_by_projection(frame, &predicted, self.search_radius);
+        if matches.len() < self.min_matches {
+            return None;
+        }
+        let (pose, inliers) = self.optimize_pose(frame, &predicted, &matches);
+        (inliers.len() >= self.min_matches).then_some(pose)
+    }
+
+    // Matches the frame's descriptors with the map points the reference keyframe sees, with no
+    // idea where in the image they should be, and finds the pose from those with RANSAC.
+    fn track_reference_keyframe(&mut self, frame: &Frame) -> Option<Pose> {
+        let keyframe = self.map.keyframe(self.reference_keyframe?)?;
+
+        let candidates = keyframe
+            .map_points
+            .iter()
+            .zip(&keyframe.descriptors)
+            .filter_map(|(point, keyframe_descriptor)| {
+                let point = (*point)?;
+                let (distance, index) = frame
+                    .descriptors
+                    .iter()
+                    .enumerate()
+                    .map(|(index, descriptor)| {
+                        (
+                            hamming_distance(&descriptor.0, &keyframe_descriptor.0),
+                            index,
+                        )
+                    })
+                    .min()?;
+                Some((distance, index, point))
+            })
+            .collect();
+        let matches = self.unique_matches(candidates, frame.keypoints.len());
+        if matches.len() < self.min_matches {
+            return None;
+        }
+
+        let correspondences: Vec<_> = matches
+            .iter()
+            .map(|&(index, point)| {
+                (
+                    self.map.map_point(point).unwrap().position,
+                    frame.keypoints[index],
+                )
+            })
+            .collect();
+        let options = RansacOptions {
+            scoring: Scoring::Msac,
+            local_optimization: true,
+            confidence: 0.999,
+            min_iterations: 20,
+            ..RansacOptions::new(self.inlier_threshold, 500)
+        };
+        let result = pnp::estimate_pose_pnp(
+            &correspondences,
+            &self.intrinsics,
+            &options,
+            PnpSolver::P3P,
+            &mut self.random,
+        )?;
+        (result.num_inliers >= self.min_matches).then_some(result.model)
+    }
+
+    // With a pose to start from, looks for every point of the local map in the image, not just the
+    // ones a single keyframe sees, and polishes the pose with all of them.
+    fn track_local_map(
+        &self,
+        frame: &Frame,
+        pose: &Pose,
+    ) -> Option<(Pose, Vec<(usize, MapPointId)>)> {
+        // the pose is known better than the motion model's guess was, so a smaller window will do
+        let matches = self.search_by_projection(frame, pose, self.search_radius / 2.0);
+        if matches.len() < self.min_matches {
+            return None;
+        }
+        let (pose, inliers) = self.optimize_pose(frame, pose, &matches);
+        (inliers.len() >= self.min_matches).then_some((pose, inliers))
+    }
+
+    // The map points the reference keyframe and its covisible keyframes see. The rest of the map
+    // was seen from elsewhere and is unlikely to be in view, so it isn't searched for.
+    fn local_map_points(&self) -> BTreeSet<MapPointId> {
+        let reference = match self.reference_keyframe.and_then(|id| self.map.keyframe(id)) {
+            Some(reference) => reference,
+            None => return BTreeSet::new(),
+        };
+        std::iter::once(reference.id)
+            .chain(reference.covisible_keyframes(1))
+            .filter_map(|id| self.map.keyframe(id))
+            .flat_map(|keyframe| keyframe.observed_map_points())
+            .collect()
+    }
+
+    // Projects the local map's points into the frame with the pose and matches each with the
+    // keypoint within `radius` pixels with the closest descriptor.
+    fn search_by_projection(
+        &self,
+        frame: &Frame,
+        pose: &Pose,
+        radius: f64,
+    ) -> Vec<(usize, MapPointId)> {
+        let grid = KeyPointGrid::new(frame, radius);
+        let candidates = self
+            .local_map_points()
+            .into_iter()
+            .filter_map(|id| {
+                let point = self.map.map_point(id)?;
+                let (u, v) = self.expected_pixel(frame, pose, point)?;
+                let (distance, index) = grid
+                    .within(frame, u, v, radius)
+                    .map(|index| {
+                        (
+                            hamming_distance(&frame.descriptors[index].0, &point.descriptor.0),
+                            index,
+                        )
+                    })
+                    .min()?;
+                Some((distance, index, id))
+            })
+            .collect();
+        self.unique_matches(candidates, frame.keypoints.len())
+    }
+
+    // Where in the frame the map point should show up, or None if it isn't expected to be seen.
+    fn expected_pixel(&self, frame: &Frame, pose: &Pose, point: &MapPoint) -> Option<(f64, f64)> {
+        let (u, v) = self
+            .intrinsics
+            .project(&(pose.0 * point.position + pose.1))?;
+        if u < 0.0 || v < 0.0 || u >= frame.width as f64 || v >= frame.height as f64 {
+            return None;
+        }
+        // too close, too far or seen from too different an angle to look the same
+        let center = -pose.0.transpose() * pose.1;
+        let ray = (point.position - center).normalize();
+        if !point.is_in_range(&center) || ray.dot(&point.viewing_direction) < 0.5 {
+            return None;
+        }
+        Some((u, v))
+    }
+
+    // Counts the local map's points the located frame should have seen and the ones it was matched
+    // with, for local mapping to tell the points that are hard to find.
+    fn count_observations(&mut self, frame: &Frame, pose: &Pose, matches: &[(usize, MapPointId)]) {
+        let visible: Vec<MapPointId> = self
+            .local_map_points()
+            .into_iter()
+            .filter(|&id| {
+                self.map
+                    .map_point(id)
+                    .is_some_and(|point| self.expected_pixel(frame, pose, point).is_some())
+            })
+            .collect();
+        for point in visible {
+            self.map.increase_visible(point);
+        }
+        for &(_, point) in matches {
+            self.map.increase_found(point);
+        }
+    }
+
+    // Keeps the candidate matches (descriptor distance, keypoint index, map point) that are close
+    // enough, giving each keypoint to the point with the closest descriptor.
+    fn unique_matches(
+        &self,
+        mut candidates: Vec<(usize, usize, MapPointId)>,
+        num_keypoints: usize,
+    ) -> Vec<(usize, MapPointId)> {
+        candidates.sort();
+        let mut taken = vec![false; num_keypoints];
+        candidates
+            .into_iter()
+            .filter(|&(distance, index, _)| {
+                distance <= self.max_hamming_distance && !std::mem::replace(&mut taken[index], true)
+            })
+            .map(|(_, index, point)| (index, point))
+            .collect()
+    }
+
+    // Pose-only optimization: the map points stay put and only the camera moves to minimize their
+    // reprojection errors. Matches that end up too far from where they were seen are dropped and
+    // the pose is optimized again without them, a few times over.
+    fn optimize_pose(
+        &self,
+        frame: &Frame,
+        pose: &Pose,
+        matches: &[(usize, MapPointId)],
+    ) -> (Pose, Vec<(usize, MapPointId)>) {
+        let position = |point: MapPointId| self.map.map_point(point).unwrap().position;
+        let mut pose = *pose;
+        let mut inliers = matches.to_vec();
+
+        for _ in 0..4 {
+            let (points, observations): (Vec<_>, Vec<_>) = inliers
+                .iter()
+                .map(|&(index, point)| {
+                    let normalized = self.intrinsics.normalize(&frame.keypoints[index]);
+                    (position(point), (normalized.x as f64, normalized.y as f64))
+                })
+                .unzip();
+            if points.len() < 3 {
+                break;
+            }
+            pose = pnp::refine(&pose, &points, &observations, 10);
+
+            inliers = matches
+                .iter()
+                .filter(|&&(index, point)| {
+                    let keypoint = frame.keypoints[index];
+                    match self
+                        .intrinsics
+                        .project(&(pose.0 * position(point) + pose.1))
+                    {
+                        Some((u, v)) => {
+                            (u - keypoint.x as f64).hypot(v - keypoint.y as f64)
+                                < self.inlier_threshold
+                        }
+                        None => false,
+                    }
+                })
+                .copied()
+                .collect();
+        }
+
+        (pose, inliers)
+    }
+}
+
+// The frame's keypoints bucketed into square cells by where they are in the image, so the ones
+// near a pixel are found by looking in the few cells around it rather than at all of them.
+struct KeyPointGrid {
+    cell_size: f64,
+    columns: usize,
+    rows: usize,
+    // the indices of the keypoints in each cell, row by row
+    cells: Vec<Vec<usize>>,
+}
+
+impl KeyPointGrid {
+    fn new(frame: &Frame, cell_size: f64) -> Self {
+        let cell_size = cell_size.max(1.0);
+        let columns = (frame.width as f64 / cell_size).ceil().max(1.0) as usize;
+        let rows = (frame.height as f64 / cell_size).ceil().max(1.0) as usize;
+        let mut grid = KeyPointGrid {
+            cell_size,
+            columns,
+            rows,
+            cells: vec![vec![]; columns * rows],
+        };
+        for (index, keypoint) in frame.keypoints.iter().enumerate() {
+            let column = grid.cell(keypoint.x as f64, columns);
+            let row = grid.cell(keypoint.y as f64, rows);
+            grid.cells[row * columns + column].push(index);
+        }
+        grid
+    }
+
+    // The cell along one axis that a coordinate falls in, with the ones outside the image in the
+    // cells at its border.
+    fn cell(&self, coordinate: f64, num_cells: usize) -> usize {
+        ((coordinate / self.cell_size).floor().max(0.0) as usize).min(num_cells - 1)
+    }
+
+    // The indices of the keypoints within `radius` pixels of (u, v).
+    fn within<'a>(
+        &'a self,
+        frame: &'a Frame,
+        u: f64,
+        v: f64,
+        radius: f64,
+    ) -> impl Iterator<Item = usize> + 'a {
+        let columns = self.cell(u - radius, self.columns)..=self.cell(u + radius, self.columns);
+        let rows = self.cell(v - radius, self.rows)..=self.cell(v + radius, self.rows);
+        rows.flat_map(move |row| {
+            columns
+                .clone()
+                .flat_map(move |column| &self.cells[row * self.columns + column])
+        })
+        .copied()
+        .filter(move |&index| {
+            let keypoint = frame.keypoints[index];
+            (keypoint.x as f64 - u).powi(2) + (keypoint.y as f64 - v).powi(2) <= radius * radius
+        })
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::*;
+    use nalgebra::{Matrix3, Rotation3, Vector3};
+
+    // A scene of points each with a descriptor of its own.
+    fn scene() -> (Vec<Vector3<f64>>, Vec<Descriptor>) {
+        let mut rng = Rand::new_with_seed(17);
+        let points = random_points(&mut rng, 200);
+        let descriptors = random_descriptors(&mut rng, points.len());
+        (points, descriptors)
+    }
+
+    // A tracker in a map made of a single keyframe at the origin that sees the whole scene.
+    fn tracker_in_scene(points: &[Vector3<f64>], descriptors: &[Descriptor]) -> Tracker {
+        let origin = (Matrix3::identity(), Vector3::zeros());
+        let frame = frame_at(&origin, points, descriptors);
+        let mut map = Map::new();
+        let keyframe = map.add_keyframe(origin, &frame);
+        for (index, keypoint) in frame.keypoints.iter().enumerate() {
+            let position = points
+                .iter()
+                .find(|point| {
+                    let (x, y) = intrinsics().project(point).unwrap();
+                    x as f32 == keypoint.x && y as f32 == keypoint.y
+                })
+                .unwrap();
+            let point = map.add_map_point(*position);
+            map.add_observation(point, keyframe, index);
+        }
+
+        let mut tracker = Tracker::new(intrinsics());
+        tracker.start(map, origin, keyframe);
+        tracker
+    }
+
+    // The camera turning a little and moving sideways, once per frame.
+    fn step() -> SE3 {
+        let rotation = Rotation3::from_euler_angles(0.0, 0.02, 0.01).into_inner();
+        SE3::from_pose(&(rotation, Vector3::new(-0.1, 0.02, 0.0)))
+    }
+
+    fn assert_pose_close(a: &Pose, b: &Pose) {
+        assert!((a.0 - b.0).norm() < 1e-4, "{} {}", a.0, b.0);
+        assert!((a.1 - b.1).norm() < 1e-4, "{} {}", a.1, b.1);
+    }
+
+    #[test]
+    fn test_tracking() {
+        let (points, descriptors) = scene();
+        let mut tracker = tracker_in_scene(&points, &descriptors);
+
+        // the first frame has no velocity yet and is found from the keyframe, the following ones
+        // with the motion model
+        let mut truth = SE3::identity();
+        for _ in 0..3 {
+            truth = step() * truth;
+            let result = tracker.process_frame(frame_at(&truth.to_pose(), &points, &descriptors));
+            assert_eq!(result.state, TrackingState::Ok);
+            assert_pose_close(&result.pose.unwrap(), &truth.to_pose());
+            assert!(result.matches.len() > 100);
+
+            // every match is the right point
+            let frame = frame_at(&truth.to_pose(), &points, &descriptors);
+            for &(index, point) in &result.matches {
+                let position = tracker.map().map_point(point).unwrap().position;
+                let (x, y) = intrinsics()
+                    .project(&(truth.rotation.matrix() * position + truth.translation))
+                    .unwrap();
+                assert!((x as f32 - frame.keypoints[index].x).abs() < 1e-3);
+                assert!((y as f32 - frame.keypoints[index].y).abs() < 1e-3);
+            }
+        }
+        assert!(tracker.velocity.is_some());
+    }
+
+    #[test]
+    fn test_keypoint_grid() {
+        let (points, descriptors) = scene();
+        let frame = frame_at(&step().to_pose(), &points, &descriptors);
+        let grid = KeyPointGrid::new(&frame, 15.0);
+
+        // the same keypoints as looking at all of them, including around pixels outside the image
+        let mut rng = Rand::new_with_seed(19);
+        for _ in 0..100 {
+            let u = rng.gen_range(-20.0..=660.0) as f64;
+            let v = rng.gen_range(-20.0..=500.0) as f64;
+            let radius = rng.gen_range(1.0..=40.0) as f64;
+            let mut found: Vec<usize> = grid.within(&frame, u, v, radius).collect();
+            found.sort();
+            let expected: Vec<usize> = (0..frame.keypoints.len())
+                .filter(|&index| {
+                    let keypoint = frame.keypoints[index];
+                    (keypoint.x as f64 - u).powi(2) + (keypoint.y as f64 - v).powi(2)
+                        <= radius * radius
+                })
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn test_initialization() {
+        let (points, descriptors) = scene();
+        let mut tracker = Tracker::new(intrinsics());
+
+        let origin = (Matrix3::identity(), Vector3::zeros());
+        let result = tracker.process_frame(frame_at(&origin, &points, &descriptors));
+        assert_eq!(result.state, TrackingState::NotInitialized);
+
+        // the focal length isn't the one a 60° field of view would give, so the map only comes
+        // out right if the initializer uses the tracker's intrinsics
+        let truth = SE3::from_pose(&(
+            Rotation3::from_euler_angles(0.0, 0.02, 0.01).into_inner(),
+            Vector3::new(-0.5, 0.1, 0.0),
+        ));
+        let result = tracker.process_frame(frame_at(&truth.to_pose(), &points, &descriptors));
+        assert_eq!(result.state, TrackingState::Ok);
+        assert_eq!(result.keyframe, KeyFrameDecision::Initialization);
+
+        // the scale is the map's own, so only the direction of the translation can be compared
+        let (rotation, translation) = result.pose.unwrap();
+        assert!((rotation - truth.rotation.matrix()).norm() < 1e-3);
+        assert!((translation.normalize() - truth.translation.normalize()).norm() < 1e-3);
+    }
+
+    #[test]
+    fn test_motion_model_prediction() {
+        let (points, descriptors) = scene();
+        let mut tracker = tracker_in_scene(&points, &descriptors);
+        tracker.velocity = Some(step());
+
+        let truth = step().to_pose();
+        let frame = frame_at(&truth, &points, &descriptors);
+        let pose = tracker.track_with_motion_model(&frame).unwrap();
+        assert_pose_close(&pose, &truth);
+
+        // a sudden jerk puts the map points far from their keypoints, so the motion model finds
+        // nothing but the keyframe still does
+        let jerk = (
+            Rotation3::from_euler_angles(0.1, 0.0, 0.0).into_inner(),
+            Vector3::zeros(),
+        );
+        let frame = frame_at(&jerk, &points, &descriptors);
+        assert!(tracker.track_with_motion_model(&frame).is_none());
+        let result = tracker.process_frame(frame);
+        assert_eq!(result.state, TrackingState::Ok);
+        assert_pose_close(&result.pose.unwrap(), &jerk);
+    }
+
+    #[test]
+    fn test_lost_and_found() {
+        let (points, descriptors) = scene();
+        let mut tracker = tracker_in_scene(&points, &descriptors);
+
+        // a view of something else entirely
+        let other_descriptors = random_descriptors(&mut Rand::new_with_seed(18), points.len());
+        let truth = step().to_pose();
+        let result = tracker.process_frame(frame_at(&truth, &points, &other_descriptors));
+        assert_eq!(result.state, TrackingState::Lost);
+        assert!(result.pose.is_none());
+        assert_eq!(tracker.state(), TrackingState::Lost);
+
+        // back to the scene, found again from the keyframe
+        let result = tracker.process_frame(frame_at(&truth, &points, &descriptors));
+        assert_eq!(result.state, TrackingState::Ok);
+        assert_pose_close(&result.pose.unwrap(), &truth);
+    }
+
+    #[test]
+    fn test_keyframe_insertion() {
+        let (points, descriptors) = scene();
+        let mut tracker = tracker_in_scene(&points, &descriptors);
+        tracker.set_keyframe_options(KeyFrameOptions {
+            max_translation: 0.25,
+            ..KeyFrameOptions::new()
+        });
+
+        // each step moves the camera about 0.1, so every third frame is far enough from the last
+        // keyframe
+        let mut truth = SE3::identity();
+        let mut decisions = vec![];
+        for _ in 0..6 {
+            truth = step() * truth;
+            let result = tracker.process_frame(frame_at(&truth.to_pose(), &points, &descriptors));
+            decisions.push(result.keyframe);
+        }
+        use KeyFrameDecision::*;
+        assert_eq!(
+            decisions,
+            vec![
+                TooSoon,
+                NotNeeded,
+                Translation,
+                TooSoon,
+                NotNeeded,
+                Translation
+            ]
+        );
+        assert_eq!(tracker.map().num_keyframes(), 3);
+
+        // the new keyframes see the points they were matched with
+        let keyframe = tracker.map().keyframes().last().unwrap();
+        assert_pose_close(&keyframe.pose, &truth.to_pose());
+        assert!(keyframe.observed_map_points().count() > 100);
+        for point in keyframe.observed_map_points() {
+            assert_eq!(
+                tracker.map().map_point(point).unwrap().observations.len(),
+                3
+            );
+        }
+    }
+}
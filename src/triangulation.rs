@@ -0,0 +1,388 @@
+// Triangulation turns a match and the relative pose of the two cameras into the 3D point both
+// cameras are looking at. The first camera sits at the origin looking down +z, the second sees a
+// point X at R·X + t, and the keypoints are in normalized image coordinates.
+//
+// With perfect keypoints the two viewing rays meet exactly at the point. Real keypoints are a
+// little off so the rays miss each other, and the methods here differ in how they decide where
+// the point most likely is.
+
+use nalgebra::{Matrix3, Matrix4, Vector3};
+
+use crate::common::KeyPoint;
+use crate::polynomial::{add, multiply, real_roots, scale};
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TriangulationMethod {
+    // Least squares solution of the linear projection equations, fast but it minimizes an
+    // algebraic error with no geometric meaning.
+    Linear,
+    // The point halfway along the shortest segment between the two viewing rays.
+    Midpoint,
+    // Hartley and Sturm's method: move the keypoints as little as possible so the rays do meet,
+    // which is the point with the smallest reprojection error.
+    Optimal,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct TriangulatedPoint {
+    pub position: Vector3<f64>,
+    // the root mean square distance between the keypoints and the projections of the point
+    pub reprojection_error: f64,
+    // the angle in degrees between the two viewing rays, points seen with little parallax have
+    // poorly known depths
+    pub parallax: f64,
+}
+
+impl TriangulatedPoint {
+    pub fn is_in_front(&self, rotation: &Matrix3<f64>, translation: &Vector3<f64>) -> bool {
+        self.position.z > 0.0 && (rotation * self.position + translation).z > 0.0
+    }
+}
+
+pub fn triangulate(
+    method: TriangulationMethod,
+    rotation: &Matrix3<f64>,
+    translation: &Vector3<f64>,
+    p1: &KeyPoint,
+    p2: &KeyPoint,
+) -> Option<TriangulatedPoint> {
+    let position = match method {
+        TriangulationMethod::Linear => triangulate_linear(rotation, translation, p1, p2),
+        TriangulationMethod::Midpoint => triangulate_midpoint(rotation, translation, p1, p2),
+        TriangulationMethod::Optimal => triangulate_optimal(rotation, translation, p1, p2),
+    }?;
+
+    let error_1 = projection_error(&position, p1);
+    let error_2 = projection_error(&(rotation * position + translation), p2);
+
+    Some(TriangulatedPoint {
+        position,
+        reprojection_error: ((error_1 * error_1 + error_2 * error_2) / 2.0).sqrt(),
+        parallax: parallax(rotation, translation, &position),
+    })
+}
+
+// Triangulates every match, with None for the ones that can't be so the points still line up
+// with the matches they came from.
+pub fn triangulate_matches(
+    method: TriangulationMethod,
+    rotation: &Matrix3<f64>,
+    translation: &Vector3<f64>,
+    key_points: &[(KeyPoint, KeyPoint)],
+) -> Vec<Option<TriangulatedPoint>> {
+    key_points
+        .iter()
+        .map(|(p1, p2)| triangulate(method, rotation, translation, p1, p2))
+        .collect()
+}
+
+// The angle in degrees between the rays from the two camera centers to a point.
+pub fn parallax(rotation: &Matrix3<f64>, translation: &Vector3<f64>, point: &Vector3<f64>) -> f64 {
+    // the second camera sits at C = -Rᵀ·t in the first camera's coordinates
+    let center_2 = -rotation.transpose() * translation;
+    let ray_1 = point;
+    let ray_2 = point - center_2;
+    let cos_parallax = ray_1.dot(&ray_2) / (ray_1.norm() * ray_2.norm());
+    cos_parallax.clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+// How far a point in camera coordinates projects from where the keypoint was seen.
+fn projection_error(point: &Vector3<f64>, key_point: &KeyPoint) -> f64 {
+    let dx = point.x / point.z - key_point.x as f64;
+    let dy = point.y / point.z - key_point.y as f64;
+    (dx * dx + dy * dy).sqrt()
+}
+
+// Each view gives two linear equations in the homogeneous point (x·P₃ - P₁ and y·P₃ - P₂) for
+// the cameras P = [I | 0] and P = [R | t], and the point is their least squares solution.
+pub fn triangulate_linear(
+    rotation: &Matrix3<f64>,
+    translation: &Vector3<f64>,
+    p1: &KeyPoint,
+    p2: &KeyPoint,
+) -> Option<Vector3<f64>> {
+    linear(
+        (p1.x as f64, p1.y as f64),
+        (p2.x as f64, p2.y as f64),
+        rotation,
+        translation,
+    )
+}
+
+fn linear(
+    (x1, y1): (f64, f64),
+    (x2, y2): (f64, f64),
+    rotation: &Matrix3<f64>,
+    translation: &Vector3<f64>,
+) -> Option<Vector3<f64>> {
+    let mut a = Matrix4::zeros();
+
+    // first camera, P = [I | 0]
+    a[(0, 0)] = -1.0;
+    a[(0, 2)] = x1;
+    a[(1, 1)] = -1.0;
+    a[(1, 2)] = y1;
+
+    // second camera, P = [R | t]
+    for col in 0..3 {
+        a[(2, col)] = x2 * rotation[(2, col)] - rotation[(0, col)];
+        a[(3, col)] = y2 * rotation[(2, col)] - rotation[(1, col)];
+    }
+    a[(2, 3)] = x2 * translation.z - translation.x;
+    a[(3, 3)] = y2 * translation.z - translation.y;
+
+    let svd = a.svd(false, true);
+    let smallest = svd.singular_values.imin();
+    let homogeneous = svd.v_t?.row(smallest).transpose();
+
+    // a point at infinity has no position
+    if homogeneous[3].abs() < 1e-12 {
+        return None;
+    }
+    finite(Vector3::new(homogeneous[0], homogeneous[1], homogeneous[2]) / homogeneous[3])
+}
+
+// The ray from the first camera is s·d1 and from the second C + u·d2, both in the first camera's
+// coordinates. Setting the derivatives of |s·d1 - C - u·d2|² to zero gives a 2x2 linear system.
+pub fn triangulate_midpoint(
+    rotation: &Matrix3<f64>,
+    translation: &Vector3<f64>,
+    p1: &KeyPoint,
+    p2: &KeyPoint,
+) -> Option<Vector3<f64>> {
+    let center_2 = -rotation.transpose() * translation;
+    let d1 = Vector3::new(p1.x as f64, p1.y as f64, 1.0);
+    let d2 = rotation.transpose() * Vector3::new(p2.x as f64, p2.y as f64, 1.0);
+
+    let a = d1.dot(&d1);
+    let b = d1.dot(&d2);
+    let c = d2.dot(&d2);
+    let denominator = a * c - b * b;
+    // parallel rays never get closest at any one place
+    if denominator.abs() < 1e-12 * a * c {
+        return None;
+    }
+
+    let e = d1.dot(&center_2);
+    let f = d2.dot(&center_2);
+    let s = (c * e - b * f) / denominator;
+    let u = (b * e - a * f) / denominator;
+
+    finite((d1 * s + center_2 + d2 * u) / 2.0)
+}
+
+// Hartley and Sturm's optimal triangulation. The corrected keypoints lie on a pair of
+// corresponding epipolar lines, and all such pairs can be written with one parameter t. After
+// moving both keypoints to the origin and both epipoles onto the x axis, the squared distance of
+// the keypoints from the lines is
+//
+//     s(t) = t² / (1 + f1²·t²) + (c·t + d)² / ((a·t + b)² + f2²·(c·t + d)²)
+//
+// whose minimum is a root of a degree 6 polynomial. The corrected keypoints triangulate exactly,
+// so the linear method finishes the job.
+pub fn triangulate_optimal(
+    rotation: &Matrix3<f64>,
+    translation: &Vector3<f64>,
+    p1: &KeyPoint,
+    p2: &KeyPoint,
+) -> Option<Vector3<f64>> {
+    let essential = translation.cross_matrix() * rotation;
+    let (c1, c2) = hartley_sturm_correction(
+        &essential,
+        (p1.x as f64, p1.y as f64),
+        (p2.x as f64, p2.y as f64),
+    )
+    .unwrap_or(((p1.x as f64, p1.y as f64), (p2.x as f64, p2.y as f64)));
+    linear(c1, c2, rotation, translation)
+}
+
+fn hartley_sturm_correction(
+    fundamental: &Matrix3<f64>,
+    (x1, y1): (f64, f64),
+    (x2, y2): (f64, f64),
+) -> Option<((f64, f64), (f64, f64))> {
+    // move the keypoints to the origin
+    let t1 = Matrix3::new(1.0, 0.0, -x1, 0.0, 1.0, -y1, 0.0, 0.0, 1.0);
+    let t2 = Matrix3::new(1.0, 0.0, -x2, 0.0, 1.0, -y2, 0.0, 0.0, 1.0);
+    let f = t2.try_inverse()?.transpose() * fundamental * t1.try_inverse()?;
+
+    // the epipoles are the null vectors of F, scaled so their x and y have unit length
+    let svd = f.svd(true, true);
+    let smallest = svd.singular_values.imin();
+    let e1: Vector3<f64> = svd.v_t?.row(smallest).transpose();
+    let e2: Vector3<f64> = svd.u?.column(smallest).into();
+    let e1 = e1 / e1.xy().norm();
+    let e2 = e2 / e2.xy().norm();
+    if !e1.iter().chain(e2.iter()).all(|c| c.is_finite()) {
+        return None;
+    }
+
+    // rotate the epipoles onto the x axis
+    let r1 = Matrix3::new(e1.x, e1.y, 0.0, -e1.y, e1.x, 0.0, 0.0, 0.0, 1.0);
+    let r2 = Matrix3::new(e2.x, e2.y, 0.0, -e2.y, e2.x, 0.0, 0.0, 0.0, 1.0);
+    let f = r2 * f * r1.transpose();
+
+    let (f1, f2) = (e1.z, e2.z);
+    let (a, b, c, d) = (f[(1, 1)], f[(1, 2)], f[(2, 1)], f[(2, 2)]);
+
+    // g(t) = t·((a·t + b)² + f2²·(c·t + d)²)² - (a·d - b·c)·(1 + f1²·t²)²·(a·t + b)·(c·t + d)
+    let at_b = [b, a];
+    let ct_d = [d, c];
+    let denominator = add(
+        &multiply(&at_b, &at_b),
+        &scale(&multiply(&ct_d, &ct_d), f2 * f2),
+    );
+    let one_f1t2 = [1.0, 0.0, f1 * f1];
+    let g = add(
+        &multiply(&[0.0, 1.0], &multiply(&denominator, &denominator)),
+        &scale(
+            &multiply(&multiply(&one_f1t2, &one_f1t2), &multiply(&at_b, &ct_d)),
+            -(a * d - b * c),
+        ),
+    );
+
+    let cost = |t: f64| {
+        let line_2 = (c * t + d).powi(2);
+        t * t / (1.0 + f1 * f1 * t * t) + line_2 / ((a * t + b).powi(2) + f2 * f2 * line_2)
+    };
+
+    // as t goes to infinity, which has to be checked as well
+    let mut best_t = f64::INFINITY;
+    let mut best_cost = 1.0 / (f1 * f1) + c * c / (a * a + f2 * f2 * c * c);
+    for t in real_roots(&g) {
+        let t_cost = cost(t);
+        if t_cost < best_cost {
+            best_cost = t_cost;
+            best_t = t;
+        }
+    }
+
+    // the two epipolar lines for the best t, and the points on them closest to the origin
+    let (line_1, line_2) = if best_t.is_finite() {
+        (
+            Vector3::new(best_t * f1, 1.0, -best_t),
+            Vector3::new(-f2 * (c * best_t + d), a * best_t + b, c * best_t + d),
+        )
+    } else {
+        (Vector3::new(f1, 0.0, -1.0), Vector3::new(-f2 * c, a, c))
+    };
+    let closest = |line: Vector3<f64>| {
+        Vector3::new(
+            -line.x * line.z,
+            -line.y * line.z,
+            line.x * line.x + line.y * line.y,
+        )
+    };
+
+    // and back to where the keypoints were
+    let x1 = t1.try_inverse()? * r1.transpose() * closest(line_1);
+    let x2 = t2.try_inverse()? * r2.transpose() * closest(line_2);
+    if x1.z.abs() < 1e-12 || x2.z.abs() < 1e-12 {
+        return None;
+    }
+    Some(((x1.x / x1.z, x1.y / x1.z), (x2.x / x2.z, x2.y / x2.z)))
+}
+
+fn finite(point: Vector3<f64>) -> Option<Vector3<f64>> {
+    if point.iter().all(|c| c.is_finite()) {
+        Some(point)
+    } else {
+        None
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phase_5::EpipolarResidual;
+    use crate::synthetic::*;
+
+    const METHODS: [TriangulationMethod; 3] = [
+        TriangulationMethod::Linear,
+        TriangulationMethod::Midpoint,
+        TriangulationMethod::Optimal,
+    ];
+
+    #[test]
+    fn test_triangulate_perfect_matches() {
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.6, -0.3, 0.2);
+        let point = Vector3::new(0.4, -0.7, 5.0);
+        let p1 = project(&point);
+        let p2 = project(&(rotation * point + translation));
+
+        for method in METHODS {
+            let triangulated = triangulate(method, &rotation, &translation, &p1, &p2).unwrap();
+            assert!(
+                (triangulated.position - point).norm() < 1e-4,
+                "{:?}",
+                method
+            );
+            assert!(triangulated.reprojection_error < 1e-6);
+            assert!(triangulated.is_in_front(&rotation, &translation));
+        }
+    }
+
+    #[test]
+    fn test_triangulate_noisy_matches() {
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.6, -0.3, 0.2);
+        let essential = translation.cross_matrix() * rotation;
+        let point = Vector3::new(0.4, -0.7, 5.0);
+        let p1 = project(&point);
+        let mut p2 = project(&(rotation * point + translation));
+        p2.x += 0.003;
+        p2.y -= 0.002;
+
+        let errors: Vec<f64> = METHODS
+            .iter()
+            .map(|&method| {
+                let triangulated = triangulate(method, &rotation, &translation, &p1, &p2).unwrap();
+                assert!((triangulated.position - point).norm() < 0.2, "{:?}", method);
+                triangulated.reprojection_error
+            })
+            .collect();
+
+        // the optimal method has the smallest reprojection error of all
+        assert!(errors[2] <= errors[0] + 1e-12 && errors[2] <= errors[1] + 1e-12);
+
+        // and it is the same as the smallest correction that makes the match perfect, which is
+        // split over four coordinates where the reprojection error is the RMS of two distances
+        let correction = EpipolarResidual::Reprojection.residual(&essential, &p1, &p2);
+        assert!((errors[2] * 2.0_f64.sqrt() - correction).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_parallax() {
+        // cameras a unit apart, with the point 10 in front of the middle between them
+        let rotation = Matrix3::identity();
+        let translation = Vector3::new(-1.0, 0.0, 0.0);
+        let point = Vector3::new(0.5, 0.0, 10.0);
+        let expected = 2.0 * (0.5_f64 / 10.0).atan().to_degrees();
+        assert!((parallax(&rotation, &translation, &point) - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_triangulate_matches() {
+        let mut rng = crate::rand::Rand::new_with_seed(13);
+        let points = random_points(&mut rng, 30);
+        let rotation = small_rotation();
+        let translation = Vector3::new(0.6, -0.3, 0.2);
+        let matches = two_view_matches(&points, &rotation, &translation);
+
+        let triangulated = triangulate_matches(
+            TriangulationMethod::Optimal,
+            &rotation,
+            &translation,
+            &matches,
+        );
+        assert_eq!(triangulated.len(), 30);
+        for (point, expected) in triangulated.iter().zip(points.iter()) {
+            assert!((point.unwrap().position - expected).norm() < 1e-3);
+        }
+    }
+}
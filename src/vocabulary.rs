@@ -0,0 +1,582 @@
+// To notice that the camera is back somewhere it has been, every new keyframe would have to be
+// compared with every old one. Matching all their descriptors is far too slow for that, so images
+// are summarized the way search engines summarize documents: as a bag of words. Descriptors that
+// look alike are grouped into visual words, and an image becomes a short list of which words it
+// has and how many. Two images of the same place share many words, and finding the images that
+// share words with a new one is a lookup rather than a search.
+//
+// The words are learned offline from example images. All their descriptors are split into
+// `branching` clusters, each cluster again into `branching` clusters and so on `depth` times, and
+// the leaves of that tree are the words. Finding a descriptor's word is then `depth` small
+// comparisons down the tree rather than one with every word.
+//
+// BRIEF descriptors are strings of bits compared by Hamming distance, so the center of a cluster
+// can't be the mean of its descriptors. Instead each bit of the center is the majority vote of
+// that bit over the cluster (k-majority, the binary version of k-means).
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::common::*;
+use crate::phase_4::hamming_distance;
+use crate::rand::Rand;
+
+pub type WordId = usize;
+pub type ImageId = usize;
+
+// The weight of each word an image has. The weights add up to 1.
+pub type BowVector = BTreeMap<WordId, f64>;
+
+const MAX_CLUSTERING_ITERATIONS: usize = 10;
+// Training the same images twice gives the same vocabulary.
+const CLUSTERING_SEED: u64 = 7219;
+
+const FILE_MAGIC: &[u8; 4] = b"SBOW";
+const FILE_VERSION: u8 = 1;
+
+struct Node {
+    parent: usize,
+    // the center of the node's cluster, empty for the root
+    descriptor: Descriptor,
+    children: Vec<usize>,
+    // set for the leaves
+    word: Option<WordId>,
+}
+
+pub struct Vocabulary {
+    branching: usize,
+    depth: usize,
+    // the tree, with the root first and every node after its parent
+    nodes: Vec<Node>,
+    // how telling each word is, ln(N / Nᵢ) for a word found in Nᵢ of the N training images, so a
+    // word every image has tells nothing
+    weights: Vec<f64>,
+}
+
+impl Vocabulary {
+    // Learns a vocabulary of up to branching^depth words from the descriptors of a set of images.
+    pub fn train(images: &[Vec<Descriptor>], branching: usize, depth: usize) -> Vocabulary {
+        let mut vocabulary = Vocabulary::with_nodes(branching, depth, vec![]);
+        let descriptors: Vec<&Descriptor> = images.iter().flatten().collect();
+        let mut random = Rand::new_with_seed(CLUSTERING_SEED);
+        vocabulary.grow(0, &descriptors, 1, &mut random);
+        let num_words = vocabulary.number_words();
+
+        let mut num_images = vec![0; num_words];
+        for image in images {
+            let words: BTreeSet<WordId> = image
+                .iter()
+                .filter_map(|descriptor| vocabulary.word(descriptor))
+                .collect();
+            for word in words {
+                num_images[word] += 1;
+            }
+        }
+        vocabulary.weights = num_images
+            .iter()
+            .map(|&n| match n {
+                0 => 0.0,
+                n => (images.len() as f64 / n as f64).ln(),
+            })
+            .collect();
+        vocabulary
+    }
+
+    pub fn branching(&self) -> usize {
+        self.branching
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn num_words(&self) -> usize {
+        self.weights.len()
+    }
+
+    pub fn weight(&self, word: WordId) -> f64 {
+        self.weights[word]
+    }
+
+    // The word a descriptor belongs to, found by going down the tree towards the closest center.
+    // None if the vocabulary has no words.
+    pub fn word(&self, descriptor: &Descriptor) -> Option<WordId> {
+        let mut node = 0;
+        while let Some(&closest) = self.nodes[node]
+            .children
+            .iter()
+            .min_by_key(|&&child| hamming_distance(&descriptor.0, &self.nodes[child].descriptor.0))
+        {
+            node = closest;
+        }
+        self.nodes[node].word
+    }
+
+    // The image's bag of words: each word weighted by how often the image has it and how telling
+    // it is (tf-idf).
+    pub fn transform(&self, descriptors: &[Descriptor]) -> BowVector {
+        let mut vector = BowVector::new();
+        for word in descriptors.iter().filter_map(|d| self.word(d)) {
+            if self.weights[word] > 0.0 {
+                *vector.entry(word).or_insert(0.0) += self.weights[word];
+            }
+        }
+        let total: f64 = vector.values().sum();
+        for weight in vector.values_mut() {
+            *weight /= total;
+        }
+        vector
+    }
+
+    // The vocabulary in a compact binary form, all numbers little-endian:
+    //
+    //     "SBOW", version (u8), branching (u32), depth (u32), descriptor length in bytes (u32),
+    //     number of nodes after the root (u32)
+    //
+    // and then for each node after the root, in order: its parent (u32), its center and its word's
+    // weight (f32, 0 for nodes that aren't words).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let descriptor_length = self.nodes.get(1).map_or(0, |node| node.descriptor.0.len());
+        let mut bytes = FILE_MAGIC.to_vec();
+        bytes.push(FILE_VERSION);
+        for value in [
+            self.branching,
+            self.depth,
+            descriptor_length,
+            self.nodes.len() - 1,
+        ] {
+            bytes.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        for node in &self.nodes[1..] {
+            bytes.extend_from_slice(&(node.parent as u32).to_le_bytes());
+            bytes.extend_from_slice(&node.descriptor.0);
+            let weight = node.word.map_or(0.0, |word| self.weights[word]);
+            bytes.extend_from_slice(&(weight as f32).to_le_bytes());
+        }
+        bytes
+    }
+
+    // Reads a vocabulary written by `to_bytes`, or None if the bytes aren't one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Vocabulary> {
+        let mut position = 0;
+        let mut take = |length: usize| {
+            let taken = bytes.get(position..position + length)?;
+            position += length;
+            Some(taken)
+        };
+        if take(4)? != FILE_MAGIC || take(1)?[0] != FILE_VERSION {
+            return None;
+        }
+        let mut read_u32 = || Some(u32::from_le_bytes(take(4)?.try_into().ok()?) as usize);
+        let branching = read_u32()?;
+        let depth = read_u32()?;
+        let descriptor_length = read_u32()?;
+        let num_nodes = read_u32()?;
+
+        let mut nodes = vec![];
+        let mut node_weights = vec![];
+        for index in 1..=num_nodes {
+            let parent = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+            // parents come first, so this can't make a cycle
+            if parent >= index {
+                return None;
+            }
+            let descriptor = Descriptor(take(descriptor_length)?.to_vec());
+            let weight = f32::from_le_bytes(take(4)?.try_into().ok()?) as f64;
+            nodes.push((parent, descriptor));
+            node_weights.push(weight);
+        }
+        if position != bytes.len() {
+            return None;
+        }
+
+        let mut vocabulary = Vocabulary::with_nodes(branching, depth, nodes);
+        vocabulary.number_words();
+        vocabulary.weights = vocabulary.nodes[1..]
+            .iter()
+            .zip(&node_weights)
+            .filter(|(node, _)| node.word.is_some())
+            .map(|(_, &weight)| weight)
+            .collect();
+        Some(vocabulary)
+    }
+
+    // A vocabulary with a root and the given (parent, center) nodes after it, with no words yet.
+    fn with_nodes(branching: usize, depth: usize, nodes: Vec<(usize, Descriptor)>) -> Vocabulary {
+        let mut vocabulary = Vocabulary {
+            branching,
+            depth,
+            nodes: vec![Node {
+                parent: 0,
+                descriptor: Descriptor(vec![]),
+                children: vec![],
+                word: None,
+            }],
+            weights: vec![],
+        };
+        for (parent, descriptor) in nodes {
+            vocabulary.add_node(parent, descriptor);
+        }
+        vocabulary
+    }
+
+    fn add_node(&mut self, parent: usize, descriptor: Descriptor) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            parent,
+            descriptor,
+            children: vec![],
+            word: None,
+        });
+        self.nodes[parent].children.push(index);
+        index
+    }
+
+    // Splits the node's descriptors into clusters that become its children, and those again until
+    // the tree is `depth` deep.
+    fn grow(&mut self, node: usize, descriptors: &[&Descriptor], level: usize, random: &mut Rand) {
+        if level > self.depth || descriptors.is_empty() {
+            return;
+        }
+        let clusters = if descriptors.len() <= self.branching {
+            descriptors
+                .iter()
+                .map(|&descriptor| (descriptor.clone(), vec![descriptor]))
+                .collect()
+        } else {
+            k_majority(descriptors, self.branching, random)
+        };
+
+        // a cluster that couldn't be split would only be split into itself again
+        let split = clusters.len() > 1;
+        for (center, members) in clusters {
+            let child = self.add_node(node, center);
+            if split && members.len() > 1 {
+                self.grow(child, &members, level + 1, random);
+            }
+        }
+    }
+
+    // Numbers the leaves as words, in the order of the nodes, and returns how many there are.
+    fn number_words(&mut self) -> usize {
+        let mut next_word = 0;
+        for node in self.nodes.iter_mut().skip(1) {
+            if node.children.is_empty() {
+                node.word = Some(next_word);
+                next_word += 1;
+            }
+        }
+        next_word
+    }
+}
+
+// Clusters the descriptors into at most k clusters, returning each one's center and members.
+fn k_majority<'a>(
+    descriptors: &[&'a Descriptor],
+    k: usize,
+    random: &mut Rand,
+) -> Vec<(Descriptor, Vec<&'a Descriptor>)> {
+    // k-means++ seeding: each new center is a descriptor picked with a chance growing with the
+    // square of its distance to the centers so far, so the centers start out spread apart
+    let mut centers = vec![descriptors[random.next_max(descriptors.len())].clone()];
+    let mut distances: Vec<u64> = descriptors
+        .iter()
+        .map(|d| hamming_distance(&d.0, &centers[0].0) as u64)
+        .collect();
+    while centers.len() < k {
+        let total: u64 = distances.iter().map(|d| d * d).sum();
+        if total == 0 {
+            break;
+        }
+        let mut target = random.next() % total;
+        let index = distances
+            .iter()
+            .position(|&d| {
+                if target < d * d {
+                    return true;
+                }
+                target -= d * d;
+                false
+            })
+            .unwrap();
+        centers.push(descriptors[index].clone());
+        for (distance, descriptor) in distances.iter_mut().zip(descriptors) {
+            let to_new = hamming_distance(&descriptor.0, &descriptors[index].0) as u64;
+            *distance = (*distance).min(to_new);
+        }
+    }
+
+    let mut assignments = vec![usize::MAX; descriptors.len()];
+    for _ in 0..MAX_CLUSTERING_ITERATIONS {
+        let new_assignments: Vec<usize> = descriptors
+            .iter()
+            .map(|descriptor| {
+                (0..centers.len())
+                    .min_by_key(|&c| hamming_distance(&descriptor.0, &centers[c].0))
+                    .unwrap()
+            })
+            .collect();
+        if new_assignments == assignments {
+            break;
+        }
+        assignments = new_assignments;
+        centers = (0..centers.len())
+            .map(|c| {
+                let members: Vec<&Descriptor> = descriptors
+                    .iter()
+                    .zip(&assignments)
+                    .filter(|(_, &assignment)| assignment == c)
+                    .map(|(&descriptor, _)| descriptor)
+                    .collect();
+                majority(&members).unwrap_or_else(|| centers[c].clone())
+            })
+            .collect();
+    }
+
+    let mut clusters: Vec<(Descriptor, Vec<&Descriptor>)> =
+        centers.into_iter().map(|center| (center, vec![])).collect();
+    for (&descriptor, &assignment) in descriptors.iter().zip(&assignments) {
+        clusters[assignment].1.push(descriptor);
+    }
+    clusters.retain(|(_, members)| !members.is_empty());
+    clusters
+}
+
+// The descriptor whose every bit is set where more than half of the descriptors have it set.
+fn majority(descriptors: &[&Descriptor]) -> Option<Descriptor> {
+    let length = descriptors.first()?.0.len();
+    let mut counts = vec![0; length * 8];
+    for descriptor in descriptors {
+        for (bit, count) in counts.iter_mut().enumerate() {
+            if descriptor.0[bit / 8] & (1 << (bit % 8)) != 0 {
+                *count += 1;
+            }
+        }
+    }
+    let mut center = vec![0u8; length];
+    for (bit, &count) in counts.iter().enumerate() {
+        if 2 * count > descriptors.len() {
+            center[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+    Some(Descriptor(center))
+}
+
+// How alike two bags of words are, from 0 for no words in common to 1 for the same bag. This is
+// 1 - ½·|a - b|₁, which for weights adding up to 1 comes down to the sum of the smaller weight of
+// each word.
+pub fn score(a: &BowVector, b: &BowVector) -> f64 {
+    a.iter()
+        .filter_map(|(word, weight_a)| Some(weight_a.min(*b.get(word)?)))
+        .sum()
+}
+
+// The images seen so far, indexed by word so a query only looks at the images sharing a word
+// with it.
+#[derive(Default)]
+pub struct Database {
+    // for each word, the images that have it and its weight in them
+    inverted_index: BTreeMap<WordId, Vec<(ImageId, f64)>>,
+    images: BTreeMap<ImageId, BowVector>,
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Database::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    // Adds an image under the given id, e.g. its keyframe's, replacing any image with that id.
+    pub fn add(&mut self, id: ImageId, vector: BowVector) {
+        self.remove(id);
+        for (&word, &weight) in &vector {
+            self.inverted_index
+                .entry(word)
+                .or_default()
+                .push((id, weight));
+        }
+        self.images.insert(id, vector);
+    }
+
+    pub fn remove(&mut self, id: ImageId) {
+        if let Some(vector) = self.images.remove(&id) {
+            for word in vector.keys() {
+                let images = self.inverted_index.get_mut(word).unwrap();
+                images.retain(|&(image, _)| image != id);
+                if images.is_empty() {
+                    self.inverted_index.remove(word);
+                }
+            }
+        }
+    }
+
+    // The images most like the query, the best first, with their scores.
+    pub fn query(&self, vector: &BowVector, max_results: usize) -> Vec<(ImageId, f64)> {
+        let mut scores: BTreeMap<ImageId, f64> = BTreeMap::new();
+        for (word, &weight) in vector {
+            for &(image, image_weight) in self.inverted_index.get(word).into_iter().flatten() {
+                *scores.entry(image).or_insert(0.0) += weight.min(image_weight);
+            }
+        }
+        let mut results: Vec<(ImageId, f64)> = scores.into_iter().collect();
+        results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+        results.truncate(max_results);
+        results
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Descriptors in `num_clusters` groups, each `noise` bits away from its group's center.
+    fn clustered_descriptors(
+        num_clusters: usize,
+        per_cluster: usize,
+        noise: usize,
+    ) -> Vec<Vec<Descriptor>> {
+        let mut random = Rand::new_with_seed(23);
+        (0..num_clusters)
+            .map(|_| {
+                let center: Vec<u8> = (0..32).map(|_| (random.next() >> 32) as u8).collect();
+                (0..per_cluster)
+                    .map(|_| {
+                        let mut descriptor = center.clone();
+                        for _ in 0..noise {
+                            let bit = random.next_max(256);
+                            descriptor[bit / 8] ^= 1 << (bit % 8);
+                        }
+                        Descriptor(descriptor)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_majority() {
+        let descriptors = [
+            Descriptor(vec![0b1100, 0b01]),
+            Descriptor(vec![0b1010, 0b01]),
+            Descriptor(vec![0b1001, 0b10]),
+        ];
+        let references: Vec<&Descriptor> = descriptors.iter().collect();
+        assert_eq!(majority(&references), Some(Descriptor(vec![0b1000, 0b01])));
+    }
+
+    #[test]
+    fn test_train() {
+        let clusters = clustered_descriptors(6, 20, 3);
+        let vocabulary = Vocabulary::train(&clusters, 3, 3);
+        assert!(vocabulary.num_words() <= 27);
+
+        // a group may be split into a few words as the tree is deeper than it needs to be, but no
+        // two groups share one
+        let words: Vec<BTreeSet<WordId>> = clusters
+            .iter()
+            .map(|cluster| {
+                cluster
+                    .iter()
+                    .map(|d| vocabulary.word(d).unwrap())
+                    .collect()
+            })
+            .collect();
+        for (i, words_i) in words.iter().enumerate() {
+            for words_j in &words[i + 1..] {
+                assert!(words_i.is_disjoint(words_j));
+            }
+        }
+        // each training image is one group, so every word is in one image out of 6
+        for &word in words.iter().flatten() {
+            assert!((vocabulary.weight(word) - 6.0_f64.ln()).abs() < 1e-12);
+        }
+    }
+
+    // Images made of half of each of the given groups of identical descriptors.
+    fn image(
+        vocabulary: &Vocabulary,
+        clusters: &[Vec<Descriptor>],
+        groups: &[usize],
+        half: usize,
+    ) -> BowVector {
+        let descriptors: Vec<Descriptor> = groups
+            .iter()
+            .flat_map(|&g| clusters[g][half * 10..(half + 1) * 10].iter().cloned())
+            .collect();
+        vocabulary.transform(&descriptors)
+    }
+
+    #[test]
+    fn test_transform_and_score() {
+        // one word per group as its descriptors are all the same
+        let clusters = clustered_descriptors(6, 20, 0);
+        let vocabulary = Vocabulary::train(&clusters, 3, 3);
+        assert_eq!(vocabulary.num_words(), 6);
+
+        let a = image(&vocabulary, &clusters, &[0, 1, 2], 0);
+        let b = image(&vocabulary, &clusters, &[1, 2, 3], 0);
+        let c = image(&vocabulary, &clusters, &[4, 5], 0);
+        assert_eq!(a.len(), 3);
+        assert!((a.values().sum::<f64>() - 1.0).abs() < 1e-12);
+
+        assert!((score(&a, &a) - 1.0).abs() < 1e-12);
+        assert!((score(&a, &b) - 2.0 / 3.0).abs() < 1e-12);
+        assert_eq!(score(&a, &c), 0.0);
+    }
+
+    #[test]
+    fn test_database() {
+        let clusters = clustered_descriptors(6, 20, 0);
+        let vocabulary = Vocabulary::train(&clusters, 3, 3);
+        let image = |groups: &[usize], half| image(&vocabulary, &clusters, groups, half);
+
+        let mut database = Database::new();
+        database.add(10, image(&[0, 1, 2], 0));
+        database.add(11, image(&[2, 3], 0));
+        database.add(12, image(&[4, 5], 0));
+        assert_eq!(database.len(), 3);
+
+        // the other halves of the groups are the same words
+        let results = database.query(&image(&[0, 1, 2], 1), 5);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, 10);
+        assert!((results[0].1 - 1.0).abs() < 1e-12);
+        assert_eq!(results[1].0, 11);
+        assert!((results[1].1 - 1.0 / 3.0).abs() < 1e-12);
+
+        database.remove(10);
+        assert_eq!(database.query(&image(&[0, 1, 2], 1), 5).len(), 1);
+        assert!(database.query(&image(&[0], 1), 5).is_empty());
+    }
+
+    #[test]
+    fn test_file_format() {
+        let clusters = clustered_descriptors(6, 20, 3);
+        let vocabulary = Vocabulary::train(&clusters, 3, 3);
+        let bytes = vocabulary.to_bytes();
+
+        let loaded = Vocabulary::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.branching(), 3);
+        assert_eq!(loaded.depth(), 3);
+        assert_eq!(loaded.num_words(), vocabulary.num_words());
+        for descriptor in clusters.iter().flatten() {
+            let word = vocabulary.word(descriptor).unwrap();
+            assert_eq!(loaded.word(descriptor), Some(word));
+            // the weights are stored as f32
+            assert!((loaded.weight(word) - vocabulary.weight(word)).abs() < 1e-6);
+        }
+
+        assert!(Vocabulary::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        assert!(Vocabulary::from_bytes(b"not a vocabulary").is_none());
+    }
+}